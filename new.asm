@@ -1,17 +1,16 @@
-
 @code
 ._entry:
-  call .main
+  call  .main
   halt
 
 .main:
-  const 15                      ; How many times will we loop?
-  call .goingup
+  const 15                              ; How many times will we loop?
+  call  .goingup
   ret
 
 .goingup:
-  store 0                       ; Store this for later use
-  const 1                       ; We'll start with 1
+  store 0                               ; Store this for later use
+  const 1                               ; We'll start with 1
   'loop:
   print
   dup
@@ -19,14 +18,14 @@
   dup
   dup
   store 1
-  load 0
+  load  0
   const 1
   sub
   dup
   store 0
   jmpnz 'loop
-  load 1
-  call .backdown
+  load  1
+  call  .backdown
   ret
 
 .backdown:
@@ -0,0 +1,269 @@
+use assembler::expr::Expr;
+use assembler::lexer::LabelType;
+use image::Image;
+use linker::Linker;
+use linker::object::{ObjectFile, Symbol, Segment, Relocation};
+use opcode::{Opcode, Operand};
+
+
+/// A global memory operand: a `data`/`space` label or a fixed address.
+pub enum Target {
+    Label(String),
+    Address(u32),
+}
+
+impl<'a> From<&'a str> for Target {
+    fn from(label: &'a str) -> Self {
+        Target::Label(global(label))
+    }
+}
+
+impl From<u32> for Target {
+    fn from(address: u32) -> Self {
+        Target::Address(address)
+    }
+}
+
+/// A label operand still to be filled in, and how.
+enum Fixup {
+    Absolute(String),
+    /// A relative jump, with the code offset the jump is measured from.
+    Relative(String, usize),
+}
+
+/// Emits a program through typed methods instead of SlangASM text. Labels
+/// are global labels, with or without the leading `.`, and may be used
+/// before they are defined. `build` produces the same image `Assembler`
+/// would for the equivalent source.
+pub struct ProgramBuilder {
+    name: String,
+    code: Vec<u8>,
+    labels: Vec<(String, usize)>,
+    data: Vec<u32>,
+    data_labels: Vec<(String, usize)>,
+    space: usize,
+    space_labels: Vec<(String, usize)>,
+    fixups: Vec<(usize, Fixup)>,
+    /// The first operand that did not fit, reported by `object`.
+    error: Option<String>,
+}
+
+impl ProgramBuilder {
+    pub fn new(name: &str) -> Self {
+        ProgramBuilder {
+            name: name.to_owned(),
+            code: Vec::new(),
+            labels: Vec::new(),
+            data: Vec::new(),
+            data_labels: Vec::new(),
+            space: 0,
+            space_labels: Vec::new(),
+            fixups: Vec::new(),
+            error: None,
+        }
+    }
+    /// Mark the next instruction with a global label.
+    pub fn label(&mut self, name: &str) -> &mut Self {
+        self.labels.push((global(name), self.code.len()));
+        self
+    }
+    /// Reserve zeroed words of global memory under a label.
+    pub fn space(&mut self, name: &str, words: usize) -> &mut Self {
+        self.space_labels.push((global(name), self.space));
+        self.space += words;
+        self
+    }
+
+    pub fn const_(&mut self, value: i64) -> &mut Self { self.op_value(Opcode::Const, value) }
+    pub fn gload<T: Into<Target>>(&mut self, target: T) -> &mut Self { self.op_target(Opcode::GLoad, target.into()) }
+    pub fn gstore<T: Into<Target>>(&mut self, target: T) -> &mut Self { self.op_target(Opcode::GStore, target.into()) }
+    pub fn call(&mut self, label: &str) -> &mut Self { self.op_target(Opcode::Call, Target::from(label)) }
+    pub fn jmp(&mut self, label: &str) -> &mut Self { self.op_target(Opcode::Jmp, Target::from(label)) }
+    pub fn jmpnz(&mut self, label: &str) -> &mut Self { self.op_target(Opcode::JmpNZ, Target::from(label)) }
+
+    /// Emit an instruction that takes no operand.
+    pub fn op(&mut self, opcode: Opcode) -> &mut Self {
+        self.code.push(opcode as u8);
+        self
+    }
+    /// Emit an instruction with a literal operand. An operand that does not
+    /// fit is emitted as zeroes and reported by `object`.
+    pub fn op_value(&mut self, opcode: Opcode, value: i64) -> &mut Self {
+        let operand = opcode.info().operand;
+        self.code.push(opcode as u8);
+        match operand.encode(value) {
+            Some(mut bytes) => self.code.append(&mut bytes),
+            None => {
+                if self.error.is_none() {
+                    self.error = Some(format!("{} does not fit in the {} byte operand of {}.", value, operand.width(), opcode.info().mnemonic));
+                }
+                self.code.append(&mut vec![0; operand.width()]);
+            },
+        }
+        self
+    }
+    fn op_target(&mut self, opcode: Opcode, target: Target) -> &mut Self {
+        match target {
+            Target::Address(address) => self.op_value(opcode, address as i64),
+            Target::Label(label) => {
+                self.code.push(opcode as u8);
+                self.fixups.push((self.code.len(), Fixup::Absolute(label)));
+                self.code.append(&mut vec![0; opcode.info().operand.width()]);
+                self
+            },
+        }
+    }
+
+    /// Resolve what can be resolved within the program and produce a
+    /// relocatable object for the rest.
+    pub fn object(&self) -> Result<ObjectFile, String> {
+        if let Some(ref error) = self.error { return Err(error.clone()) }
+        let mut object = ObjectFile::new(&self.name);
+        object.code = self.code.clone();
+        object.data = self.data.clone();
+        object.space = self.space;
+        let segments = vec![(Segment::Code, &self.labels), (Segment::Data, &self.data_labels), (Segment::Space, &self.space_labels)];
+        for (segment, labels) in segments {
+            for &(ref name, offset) in labels {
+                if object.symbol(name).is_some() { return Err(format!("{} is defined more than once.", name)) }
+                object.symbols.push(Symbol { name: name.clone(), segment, offset });
+            }
+        }
+        for &(offset, ref fixup) in &self.fixups {
+            match *fixup {
+                Fixup::Absolute(ref label) => {
+                    if object.symbol(label).is_none() && !object.imports.contains(label) {
+                        object.imports.push(label.clone());
+                    }
+                    let expr = Expr::Reference(LabelType::Global, label.clone());
+                    object.relocations.push(Relocation { offset, expr });
+                },
+                Fixup::Relative(ref label, end) => {
+                    let target = match self.labels.iter().find(|&(name, _)| name == label) {
+                        Some(&(_, target)) => target,
+                        None => return Err(format!("{} is not a code label of {}; relative jumps must stay within it.", label, self.name)),
+                    };
+                    let operand = Operand::Offset(end - offset);
+                    let distance = target as i64 - end as i64;
+                    match operand.encode(distance) {
+                        Some(bytes) => object.code[offset..end].copy_from_slice(&bytes),
+                        None => return Err(format!("The jump to {} is too far for a {} byte offset.", label, operand.width())),
+                    }
+                },
+            }
+        }
+        Ok(object)
+    }
+    /// Link the program on its own, as `Assembler::assemble` does.
+    pub fn build(&self) -> Result<Image, String> {
+        let mut linker = Linker::new();
+        linker.add(self.object()?);
+        linker.link()
+    }
+}
+
+/// The rest of the instruction set. The compiler emits these through `op`,
+/// so for now only tests call them.
+#[allow(dead_code)]
+impl ProgramBuilder {
+    /// Add initialized words of global memory under a label.
+    pub fn data(&mut self, name: &str, values: &[u32]) -> &mut Self {
+        self.data_labels.push((global(name), self.data.len()));
+        self.data.extend_from_slice(values);
+        self
+    }
+
+    pub fn noop(&mut self) -> &mut Self { self.op(Opcode::Noop) }
+    pub fn load(&mut self, slot: u32) -> &mut Self { self.op_value(Opcode::Load, slot as i64) }
+    pub fn store(&mut self, slot: u32) -> &mut Self { self.op_value(Opcode::Store, slot as i64) }
+    pub fn dup(&mut self) -> &mut Self { self.op(Opcode::Dup) }
+    pub fn swap(&mut self) -> &mut Self { self.op(Opcode::Swap) }
+    pub fn drop(&mut self) -> &mut Self { self.op(Opcode::Drop) }
+    pub fn add(&mut self) -> &mut Self { self.op(Opcode::Add) }
+    pub fn sub(&mut self) -> &mut Self { self.op(Opcode::Sub) }
+    pub fn mul(&mut self) -> &mut Self { self.op(Opcode::Mul) }
+    pub fn div(&mut self) -> &mut Self { self.op(Opcode::Div) }
+    pub fn pow(&mut self) -> &mut Self { self.op(Opcode::Pow) }
+    pub fn modulo(&mut self) -> &mut Self { self.op(Opcode::Mod) }
+    pub fn shl(&mut self) -> &mut Self { self.op(Opcode::Shl) }
+    pub fn shr(&mut self) -> &mut Self { self.op(Opcode::Shr) }
+    pub fn and(&mut self) -> &mut Self { self.op(Opcode::And) }
+    pub fn or(&mut self) -> &mut Self { self.op(Opcode::Or) }
+    pub fn xor(&mut self) -> &mut Self { self.op(Opcode::Xor) }
+    pub fn not(&mut self) -> &mut Self { self.op(Opcode::Not) }
+    pub fn cmp_eq(&mut self) -> &mut Self { self.op(Opcode::CmpEq) }
+    pub fn cmp_ne(&mut self) -> &mut Self { self.op(Opcode::CmpNe) }
+    pub fn cmp_gt(&mut self) -> &mut Self { self.op(Opcode::CmpGt) }
+    pub fn cmp_lt(&mut self) -> &mut Self { self.op(Opcode::CmpLt) }
+    pub fn fadd(&mut self) -> &mut Self { self.op(Opcode::FAdd) }
+    pub fn fsub(&mut self) -> &mut Self { self.op(Opcode::FSub) }
+    pub fn fmul(&mut self) -> &mut Self { self.op(Opcode::FMul) }
+    pub fn fdiv(&mut self) -> &mut Self { self.op(Opcode::FDiv) }
+    pub fn fpow(&mut self) -> &mut Self { self.op(Opcode::FPow) }
+    pub fn fmod(&mut self) -> &mut Self { self.op(Opcode::FMod) }
+    pub fn fcmp_eq(&mut self) -> &mut Self { self.op(Opcode::FCmpEq) }
+    pub fn fcmp_ne(&mut self) -> &mut Self { self.op(Opcode::FCmpNe) }
+    pub fn fcmp_gt(&mut self) -> &mut Self { self.op(Opcode::FCmpGt) }
+    pub fn fcmp_lt(&mut self) -> &mut Self { self.op(Opcode::FCmpLt) }
+    pub fn itof(&mut self) -> &mut Self { self.op(Opcode::IToF) }
+    pub fn ftoi(&mut self) -> &mut Self { self.op(Opcode::FToI) }
+    pub fn jmp_rel(&mut self, label: &str) -> &mut Self { self.op_relative(Opcode::RelJmp, label) }
+    pub fn jmp_rel_eq(&mut self, label: &str) -> &mut Self { self.op_relative(Opcode::RelJmpEq, label) }
+    pub fn jmp_rel_ne(&mut self, label: &str) -> &mut Self { self.op_relative(Opcode::RelJmpNe, label) }
+    pub fn jmp_rel_gt(&mut self, label: &str) -> &mut Self { self.op_relative(Opcode::RelJmpGt, label) }
+    pub fn jmp_rel_lt(&mut self, label: &str) -> &mut Self { self.op_relative(Opcode::RelJmpLt, label) }
+    pub fn ret(&mut self) -> &mut Self { self.op(Opcode::Ret) }
+    pub fn print(&mut self) -> &mut Self { self.op(Opcode::Print) }
+    pub fn fprint(&mut self) -> &mut Self { self.op(Opcode::FPrint) }
+    pub fn halt(&mut self) -> &mut Self { self.op(Opcode::Halt) }
+
+    fn op_relative(&mut self, opcode: Opcode, label: &str) -> &mut Self {
+        let width = opcode.info().operand.width();
+        self.code.push(opcode as u8);
+        let end = self.code.len() + width;
+        self.fixups.push((self.code.len(), Fixup::Relative(global(label), end)));
+        self.code.append(&mut vec![0; width]);
+        self
+    }
+}
+
+fn global(name: &str) -> String {
+    if name.starts_with('.') { name.to_owned() } else { format!(".{}", name) }
+}
+
+
+#[test]
+fn test_builder_matches_assembler() {
+    use assembler::Assembler;
+    let source = "@data\n.seed: 3 4\n@space\n.total: 1\n@code\n._entry:\n  gload .seed\n  call .double\n  gstore .total\n  halt\n\
+                  .double:\n  dup\n  const 0\n  jmp_rel_eq .done\n  add\n.done:\n  ret\n";
+    let expected = Assembler::new(source.to_owned()).assemble();
+
+    let mut b = ProgramBuilder::new("test");
+    b.data("seed", &[3, 4]).space("total", 1);
+    b.label("_entry").gload("seed").call("double").gstore("total").halt();
+    b.label("double").dup().const_(0).jmp_rel_eq("done").add();
+    b.label("done").ret();
+    let image = b.build().unwrap();
+    assert!(image.code == expected.code);
+    assert!(image.data == expected.data);
+}
+
+#[test]
+fn test_builder_errors() {
+    let mut b = ProgramBuilder::new("test");
+    b.label("_entry").jmp_rel("elsewhere");
+    assert!(b.build().unwrap_err() == ".elsewhere is not a code label of test; relative jumps must stay within it.");
+
+    let mut b = ProgramBuilder::new("test");
+    b.label("_entry").halt().label("._entry").halt();
+    assert!(b.build().unwrap_err() == "._entry is defined more than once.");
+
+    let mut b = ProgramBuilder::new("test");
+    b.label("_entry").call("missing").gload(7u32);
+    assert!(b.build().unwrap_err() == ".missing is not a known label (imported by test).");
+
+    let mut b = ProgramBuilder::new("test");
+    b.label("_entry").const_(1 << 40).load(1).halt();
+    assert!(b.build().unwrap_err() == "1099511627776 does not fit in the 4 byte operand of const.");
+}
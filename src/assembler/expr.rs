@@ -0,0 +1,394 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use super::lexer::LabelType;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+}
+
+impl BinaryOp {
+    fn precedence(&self) -> u8 {
+        match *self {
+            BinaryOp::Or                                => 1,
+            BinaryOp::Xor                               => 2,
+            BinaryOp::And                               => 3,
+            BinaryOp::Shl | BinaryOp::Shr               => 4,
+            BinaryOp::Add | BinaryOp::Sub               => 5,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 6,
+        }
+    }
+    fn symbol(&self) -> &'static str {
+        match *self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+            BinaryOp::And => "&",
+            BinaryOp::Or  => "|",
+            BinaryOp::Xor => "^",
+        }
+    }
+}
+
+/// An operand expression, evaluated by the assembler once every label
+/// address is known.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Constant(i64),
+    Reference(LabelType, String),
+    Symbol(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, String> {
+        let mut parser = Parser::new(source);
+        let expr = parser.expression(0)?;
+        parser.skip_whitespace();
+        match parser.chars.peek() {
+            Some(&ch) => Err(format!("Unexpected '{}' in expression '{}'.", ch, source)),
+            None => Ok(expr),
+        }
+    }
+    /// Parse a whitespace or comma separated list of expressions. A `-` that
+    /// follows whitespace and sits against its operand starts a new, negative
+    /// value, so `1 -2` is two values where `1 - 2` and `1-2` are one.
+    pub fn parse_list(source: &str) -> Result<Vec<Expr>, String> {
+        let mut parser = Parser::new(source);
+        parser.list = true;
+        let mut exprs = Vec::new();
+        loop {
+            parser.skip_whitespace();
+            if parser.chars.peek().is_none() { return Ok(exprs); }
+            exprs.push(parser.expression(0)?);
+            parser.skip_whitespace();
+            if parser.chars.peek() == Some(&',') { parser.chars.next(); }
+        }
+    }
+    /// Replace every symbol that `replace` knows about with its expansion.
+    pub fn substitute<F>(&self, replace: &F) -> Expr
+        where F: Fn(&str) -> Option<Expr>
+    {
+        match *self {
+            Expr::Symbol(ref name) => match replace(name) {
+                Some(expr) => expr,
+                None => self.clone(),
+            },
+            Expr::Unary(op, ref operand) => Expr::Unary(op, Box::new(operand.substitute(replace))),
+            Expr::Binary(op, ref lhs, ref rhs) => {
+                Expr::Binary(op, Box::new(lhs.substitute(replace)), Box::new(rhs.substitute(replace)))
+            },
+            _ => self.clone(),
+        }
+    }
+    /// Replace the label references that `replace` returns an expression for.
+    pub fn replace_references<F>(&self, replace: &F) -> Expr
+        where F: Fn(&LabelType, &str) -> Option<Expr>
+    {
+        match *self {
+            Expr::Reference(ref label_type, ref label) => match replace(label_type, label) {
+                Some(expr) => expr,
+                None => self.clone(),
+            },
+            Expr::Unary(op, ref operand) => Expr::Unary(op, Box::new(operand.replace_references(replace))),
+            Expr::Binary(op, ref lhs, ref rhs) => {
+                Expr::Binary(op, Box::new(lhs.replace_references(replace)), Box::new(rhs.replace_references(replace)))
+            },
+            _ => self.clone(),
+        }
+    }
+    /// Every label referenced by the expression.
+    pub fn references(&self) -> Vec<(&LabelType, &str)> {
+        match *self {
+            Expr::Reference(ref label_type, ref label) => vec![(label_type, label)],
+            Expr::Unary(_, ref operand) => operand.references(),
+            Expr::Binary(_, ref lhs, ref rhs) => {
+                let mut labels = lhs.references();
+                labels.append(&mut rhs.references());
+                labels
+            },
+            _ => Vec::new(),
+        }
+    }
+    /// Every symbol name used in the expression.
+    pub fn symbols(&self) -> Vec<&str> {
+        match *self {
+            Expr::Symbol(ref name) => vec![name],
+            Expr::Unary(_, ref operand) => operand.symbols(),
+            Expr::Binary(_, ref lhs, ref rhs) => {
+                let mut names = lhs.symbols();
+                names.append(&mut rhs.symbols());
+                names
+            },
+            _ => Vec::new(),
+        }
+    }
+    /// Evaluate the expression, asking `lookup` for the value of every
+    /// reference and symbol leaf.
+    pub fn evaluate<F>(&self, lookup: &F) -> Result<i64, String>
+        where F: Fn(&Expr) -> Option<i64>
+    {
+        match *self {
+            Expr::Constant(value) => Ok(value),
+            Expr::Reference(_, ref label) => match lookup(self) {
+                Some(value) => Ok(value),
+                None => Err(format!("{} is not a known label.", label)),
+            },
+            Expr::Symbol(ref name) => match lookup(self) {
+                Some(value) => Ok(value),
+                None => Err(format!("{} is not a defined symbol.", name)),
+            },
+            Expr::Unary(op, ref operand) => {
+                let value = operand.evaluate(lookup)?;
+                Ok(match op {
+                    UnaryOp::Neg => value.wrapping_neg(),
+                    UnaryOp::Not => !value,
+                })
+            },
+            Expr::Binary(op, ref lhs, ref rhs) => {
+                let l = lhs.evaluate(lookup)?;
+                let r = rhs.evaluate(lookup)?;
+                match op {
+                    BinaryOp::Add => Ok(l.wrapping_add(r)),
+                    BinaryOp::Sub => Ok(l.wrapping_sub(r)),
+                    BinaryOp::Mul => Ok(l.wrapping_mul(r)),
+                    BinaryOp::Div | BinaryOp::Mod if r == 0 => {
+                        Err(format!("Division by zero in expression '{}'.", self))
+                    },
+                    BinaryOp::Div => Ok(l.wrapping_div(r)),
+                    BinaryOp::Mod => Ok(l.wrapping_rem(r)),
+                    BinaryOp::Shl => Ok(l.wrapping_shl(r as u32)),
+                    BinaryOp::Shr => Ok(l.wrapping_shr(r as u32)),
+                    BinaryOp::And => Ok(l & r),
+                    BinaryOp::Or  => Ok(l | r),
+                    BinaryOp::Xor => Ok(l ^ r),
+                }
+            },
+        }
+    }
+    /// Fold the expression down to a constant if it has no references or
+    /// symbols in it.
+    pub fn fold(&self) -> Option<i64> {
+        self.evaluate(&|_: &Expr| None).ok()
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::Constant(value) => write!(f, "{}", value),
+            Expr::Reference(_, ref label) | Expr::Symbol(ref label) => write!(f, "{}", label),
+            Expr::Unary(UnaryOp::Neg, ref operand) => write!(f, "-{}", operand),
+            Expr::Unary(UnaryOp::Not, ref operand) => write!(f, "~{}", operand),
+            Expr::Binary(op, ref lhs, ref rhs) => write!(f, "({} {} {})", lhs, op.symbol(), rhs),
+        }
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    chars: Peekable<Chars<'a>>,
+    list: bool,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Self {
+        Parser {
+            source,
+            chars: source.chars().peekable(),
+            list: false,
+            depth: 0,
+        }
+    }
+    fn skip_whitespace(&mut self) {
+        while let Some(&ch) = self.chars.peek() {
+            if !ch.is_whitespace() { break; }
+            self.chars.next();
+        }
+    }
+    fn expression(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = match self.peek_operator() {
+                Some(op) => op,
+                None => return Ok(lhs),
+            };
+            if op.precedence() <= min_precedence { return Ok(lhs); }
+            for _ in 0..op.symbol().len() { self.chars.next(); }
+            let rhs = self.expression(op.precedence())?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+    }
+    fn peek_operator(&mut self) -> Option<BinaryOp> {
+        let spaced = self.chars.peek().is_some_and(|ch| ch.is_whitespace());
+        self.skip_whitespace();
+        let mut ahead = self.chars.clone();
+        let op = match ahead.next() {
+            Some('+') => BinaryOp::Add,
+            // In a list, `1 -2` is two values rather than a subtraction.
+            Some('-') if self.list && self.depth == 0 && spaced
+                && ahead.peek().is_some_and(|ch| !ch.is_whitespace()) => return None,
+            Some('-') => BinaryOp::Sub,
+            Some('*') => BinaryOp::Mul,
+            Some('/') => BinaryOp::Div,
+            Some('%') => BinaryOp::Mod,
+            Some('&') => BinaryOp::And,
+            Some('|') => BinaryOp::Or,
+            Some('^') => BinaryOp::Xor,
+            Some('<') if ahead.next() == Some('<') => BinaryOp::Shl,
+            Some('>') if ahead.next() == Some('>') => BinaryOp::Shr,
+            _ => return None,
+        };
+        Some(op)
+    }
+    fn unary(&mut self) -> Result<Expr, String> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some(&'-') => {
+                self.chars.next();
+                Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.unary()?)))
+            },
+            Some(&'~') => {
+                self.chars.next();
+                Ok(Expr::Unary(UnaryOp::Not, Box::new(self.unary()?)))
+            },
+            _ => self.primary(),
+        }
+    }
+    fn primary(&mut self) -> Result<Expr, String> {
+        self.skip_whitespace();
+        match self.chars.peek().cloned() {
+            Some('(') => {
+                self.chars.next();
+                self.depth += 1;
+                let expr = self.expression(0)?;
+                self.depth -= 1;
+                self.skip_whitespace();
+                match self.chars.next() {
+                    Some(')') => Ok(expr),
+                    _ => Err(format!("Missing ')' in expression '{}'.", self.source)),
+                }
+            },
+            Some('.') => {
+                self.chars.next();
+                let name = self.take_word();
+                if name.is_empty() { return Err(format!("Missing label name in expression '{}'.", self.source)) }
+                Ok(Expr::Reference(LabelType::Global, format!(".{}", name)))
+            },
+            Some('\'') => {
+                self.chars.next();
+                let name = self.take_word();
+                if name.is_empty() { return Err(format!("Missing label name in expression '{}'.", self.source)) }
+                Ok(Expr::Reference(LabelType::Local, format!("'{}", name)))
+            },
+            Some(ch) if ch.is_ascii_digit() => {
+                let word = self.take_word();
+                let parsed = if word.starts_with("0x") || word.starts_with("0X") {
+                    i64::from_str_radix(&word[2..], 16)
+                } else {
+                    word.parse()
+                };
+                parsed.map(Expr::Constant)
+                      .map_err(|_| format!("{} is not a valid number.", word))
+            },
+            Some(ch) if ch.is_alphabetic() || ch == '_' => Ok(Expr::Symbol(self.take_word())),
+            Some(ch) => Err(format!("Unexpected '{}' in expression '{}'.", ch, self.source)),
+            None => Err(format!("Unexpected end of expression '{}'.", self.source)),
+        }
+    }
+    fn take_word(&mut self) -> String {
+        let mut word = String::new();
+        while let Some(&ch) = self.chars.peek() {
+            if !(ch.is_alphanumeric() || ch == '_') { break; }
+            word.push(ch);
+            self.chars.next();
+        }
+        word
+    }
+}
+
+
+#[test]
+fn test_parse_precedence() {
+    let expr = Expr::parse("1 + 4*2").unwrap();
+    assert!(expr.fold() == Some(9));
+    let expr = Expr::parse("(1 + 4) * 2 - -3").unwrap();
+    assert!(expr.fold() == Some(13));
+    let expr = Expr::parse("1 << 4 | 0x0F & ~0").unwrap();
+    assert!(expr.fold() == Some(31));
+}
+
+#[test]
+fn test_parse_leaves() {
+    let expr = Expr::parse(".table + BUF_BASE").unwrap();
+    let expected = Expr::Binary(
+        BinaryOp::Add,
+        Box::new(Expr::Reference(LabelType::Global, ".table".to_owned())),
+        Box::new(Expr::Symbol("BUF_BASE".to_owned())),
+    );
+    assert!(expr == expected);
+    assert!(Expr::parse("'loop").unwrap() == Expr::Reference(LabelType::Local, "'loop".to_owned()));
+}
+
+#[test]
+fn test_parse_errors() {
+    assert!(Expr::parse("1 +").is_err());
+    assert!(Expr::parse("(1 + 2").is_err());
+    assert!(Expr::parse("1 2").is_err());
+}
+
+#[test]
+fn test_parse_list() {
+    let exprs = Expr::parse_list("1 2, .x + 1  'y").unwrap();
+    assert!(exprs.len() == 4);
+    assert!(exprs[2] == Expr::parse(".x + 1").unwrap());
+    assert!(Expr::parse_list("").unwrap().is_empty());
+    assert!(Expr::parse_list("1 -2 3").unwrap() == vec![
+        Expr::Constant(1), Expr::Unary(UnaryOp::Neg, Box::new(Expr::Constant(2))), Expr::Constant(3),
+    ]);
+    assert!(Expr::parse_list("1 - 2, 1-2, (1 -2)").unwrap().len() == 3);
+}
+
+#[test]
+fn test_substitute() {
+    let expr = Expr::parse("SLOT * 2").unwrap();
+    let slot = Expr::parse("BASE + 1").unwrap();
+    let expanded = expr.substitute(&|name: &str| if name == "SLOT" { Some(slot.clone()) } else { None });
+    assert!(expanded.symbols() == vec!["BASE"]);
+}
+
+#[test]
+fn test_evaluate_lookup() {
+    let expr = Expr::parse(".table + 4*2").unwrap();
+    let value = expr.evaluate(&|leaf: &Expr| match *leaf {
+        Expr::Reference(_, ref label) if label == ".table" => Some(100),
+        _ => None,
+    });
+    assert!(value == Ok(108));
+    assert!(Expr::parse("UNDEFINED + 1").unwrap().fold().is_none());
+    assert!(Expr::parse("1 / 0").unwrap().evaluate(&|_: &Expr| None).is_err());
+}
@@ -0,0 +1,212 @@
+use std::collections::BTreeMap;
+
+use super::lexer::{Token, Spanned, Lexer, Directive, LabelType};
+
+
+/// Trailing comments start at this column unless some commented line is
+/// wider.
+const COMMENT_COLUMN: usize = 40;
+const INDENT: &str = "  ";
+
+/// One formatted line, before columns are aligned.
+enum Row {
+    Blank,
+    Comment(String),
+    Directive(String, String, Option<String>),
+    /// The directive and name, then the value.
+    Define(String, String, Option<String>),
+    Label(LabelType, String, Option<String>),
+    Data(String, String, Option<String>),
+    Instruction(String, String, Option<String>),
+}
+
+impl Row {
+    fn comment(&mut self) -> Option<&mut Option<String>> {
+        match *self {
+            Row::Blank | Row::Comment(_) => None,
+            Row::Directive(_, _, ref mut comment) | Row::Define(_, _, ref mut comment) | Row::Label(_, _, ref mut comment)
+            | Row::Data(_, _, ref mut comment) | Row::Instruction(_, _, ref mut comment) => Some(comment),
+        }
+    }
+    fn indented(&self) -> bool {
+        matches!(*self, Row::Instruction(_, _, _) | Row::Label(LabelType::Local, _, _))
+    }
+}
+
+/// The word starting at byte `start` of `line`, up to `end`.
+fn word(line: &str, start: usize, end: usize) -> &str {
+    line[start..end].split_whitespace().next().unwrap_or("")
+}
+
+/// The text after the word starting at byte `start` of `line`, up to `end`,
+/// with runs of whitespace collapsed.
+fn rest(line: &str, start: usize, end: usize) -> String {
+    let word = line[start..end].find(char::is_whitespace).map_or(end, |index| start + index);
+    line[word..end].split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Rewrite SlangASM source in the canonical layout: directives and global
+/// labels flush left, local labels and instructions indented, and operands,
+/// `@define` values, data values and trailing comments each aligned in a
+/// column. Only layout and the case of directives change. Blank lines are kept, but runs of them collapse to one.
+pub fn format(source: &str, file: &str) -> String {
+    let mut source = source.to_owned();
+    if !source.ends_with('\n') { source.push('\n'); }
+    let mut lines: BTreeMap<usize, Vec<Spanned>> = BTreeMap::new();
+    for spanned in Lexer::for_file(&source, file).lex_spanned() {
+        lines.entry(spanned.location.line).or_default().push(spanned);
+    }
+
+    let mut rows = Vec::new();
+    let mut section = None;
+    for (index, line) in source.lines().enumerate() {
+        let tokens = lines.remove(&(index + 1)).unwrap_or_default();
+        let end = tokens.iter().find(|spanned| matches!(spanned.token, Token::Comment(_)))
+                        .map_or(line.len(), |spanned| spanned.location.column - 1);
+        let first = rows.len();
+        for spanned in tokens {
+            let start = spanned.location.column - 1;
+            match spanned.token {
+                Token::Directive(directive) => {
+                    // Directives are lowercased, but an alias such as `@equ`
+                    // keeps the spelling the author chose.
+                    let keyword = word(line, start, end).to_lowercase();
+                    let args = rest(line, start, end);
+                    match directive {
+                        Directive::Code | Directive::Data | Directive::Space => section = Some(directive.clone()),
+                        _ => {},
+                    }
+                    if directive == Directive::Define {
+                        let (name, value) = match args.find(' ') {
+                            Some(index) => (args[..index].to_owned(), args[index + 1..].to_owned()),
+                            None => (args, String::new()),
+                        };
+                        rows.push(Row::Define(format!("{} {}", keyword, name), value, None));
+                    } else {
+                        rows.push(Row::Directive(keyword, args, None));
+                    }
+                },
+                Token::Label(LabelType::Global, name) => match section {
+                    Some(Directive::Data) | Some(Directive::Space) => rows.push(Row::Data(name, rest(line, start, end), None)),
+                    _ => rows.push(Row::Label(LabelType::Global, name, None)),
+                },
+                Token::Label(label_type, name) => rows.push(Row::Label(label_type, name, None)),
+                Token::Instruction(name) => rows.push(Row::Instruction(name, rest(line, start, end), None)),
+                Token::Comment(text) => {
+                    let text = text.trim_end().to_owned();
+                    if rows.len() > first {
+                        *rows.last_mut().unwrap().comment().unwrap() = Some(text);
+                    } else {
+                        rows.push(Row::Comment(text));
+                    }
+                },
+                _ => {},
+            }
+        }
+        if rows.len() == first { rows.push(Row::Blank); }
+    }
+    layout(rows)
+}
+
+fn layout(rows: Vec<Row>) -> String {
+    let width = |widths: Vec<usize>| widths.into_iter().max().unwrap_or(0);
+    let mnemonic = width(rows.iter().filter_map(|row| match *row {
+        Row::Instruction(ref name, ref operands, _) if !operands.is_empty() => Some(name.len()),
+        _ => None,
+    }).collect());
+    let define = width(rows.iter().filter_map(|row| match *row {
+        Row::Define(ref head, _, _) => Some(head.len()),
+        _ => None,
+    }).collect());
+    let data = width(rows.iter().filter_map(|row| match *row {
+        Row::Data(ref name, ref values, _) if !values.is_empty() => Some(name.len() + 1),
+        _ => None,
+    }).collect());
+
+    // Full line comments are indented like the code they precede.
+    let mut indents = vec![false; rows.len()];
+    let mut indented = false;
+    for (index, row) in rows.iter().enumerate().rev() {
+        match *row {
+            Row::Blank | Row::Comment(_) => {},
+            ref row => indented = row.indented(),
+        }
+        indents[index] = indented;
+    }
+
+    let mut lines: Vec<(String, Option<String>)> = Vec::new();
+    for (row, indented) in rows.into_iter().zip(indents) {
+        lines.push(match row {
+            Row::Blank => {
+                // Collapse runs of blank lines and drop leading ones.
+                match lines.last() {
+                    Some(&(ref code, None)) if code.is_empty() => {},
+                    Some(_) => lines.push((String::new(), None)),
+                    None => {},
+                }
+                continue
+            },
+            Row::Comment(text) => (format!("{}; {}", if indented { INDENT } else { "" }, text), None),
+            Row::Directive(name, args, comment) => (join(&name, 0, &args), comment),
+            Row::Define(head, value, comment) => (join(&head, define, &value), comment),
+            Row::Label(LabelType::Global, name, comment) => (format!("{}:", name), comment),
+            Row::Label(LabelType::Local, name, comment) => (format!("{}{}:", INDENT, name), comment),
+            Row::Data(name, values, comment) => (join(&format!("{}:", name), data, &values), comment),
+            Row::Instruction(name, operands, comment) => (format!("{}{}", INDENT, join(&name, mnemonic, &operands)), comment),
+        });
+    }
+    while lines.last().is_some_and(|(code, comment)| code.is_empty() && comment.is_none()) {
+        lines.pop();
+    }
+
+    let column = lines.iter().filter(|&(_, comment)| comment.is_some())
+                      .map(|(code, _)| code.len() + 2).max().unwrap_or(0).max(COMMENT_COLUMN);
+    let mut formatted = String::new();
+    for (code, comment) in lines {
+        match comment {
+            Some(text) => formatted.push_str(&format!("{:<width$}; {}", code, text, width = column)),
+            None => formatted.push_str(&code),
+        }
+        formatted.push('\n');
+    }
+    formatted
+}
+
+/// `head` padded to `width`, then `tail` if there is one.
+fn join(head: &str, width: usize, tail: &str) -> String {
+    if tail.is_empty() { head.to_owned() } else { format!("{:<width$} {}", head, tail, width = width) }
+}
+
+
+#[test]
+fn test_format_layout() {
+    let source = "\n\n@EQU   SLOT 3   ; the slot\n@data\n.seed:   3  4\n.longer_name: 5\n   @code\n  ._entry:   ; start here\n\
+                  const   1+2\n\n\n  ; loop until done\n'loop:\nstore SLOT\n     jmp_rel_ne  'loop ;back\nhalt\n\n";
+    assert!(format(source, "test.asm") == "\
+@equ SLOT 3                             ; the slot
+@data
+.seed:        3 4
+.longer_name: 5
+@code
+._entry:                                ; start here
+  const      1+2
+
+  ; loop until done
+  'loop:
+  store      SLOT
+  jmp_rel_ne 'loop                      ; back
+  halt
+");
+}
+
+#[test]
+fn test_format_is_idempotent() {
+    use std::fs::File;
+    use std::io::Read;
+    for path in &["new.asm", "factorial.asm", "divergent.asm"] {
+        let mut source = String::new();
+        File::open(path).unwrap().read_to_string(&mut source).unwrap();
+        let formatted = format(&source, path);
+        assert!(format(&formatted, path) == formatted);
+    }
+}
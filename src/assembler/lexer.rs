@@ -1,18 +1,24 @@
+use std::fmt;
+use std::rc::Rc;
+
 use regex::Regex;
 
+use super::expr::Expr;
+
 lazy_static! {
     static ref REGEX_DIRECTIVE: Regex = Regex::new(r"^@([a-zA-Z]+$)").unwrap();
     static ref REGEX_GLABEL: Regex = Regex::new(r"^\.\w+:$").unwrap();
     static ref REGEX_LLABEL: Regex = Regex::new(r"^'\w+:$").unwrap();
     static ref REGEX_GLABELREF: Regex = Regex::new(r"^\.\w+$").unwrap();
     static ref REGEX_LLABELREF: Regex = Regex::new(r"^'\w+$").unwrap();
-    static ref REGEX_INSTRUCTION: Regex = Regex::new(r"^[a-zA-Z]+$").unwrap();
+    static ref REGEX_INSTRUCTION: Regex = Regex::new(r"^[a-zA-Z][a-zA-Z_]*$").unwrap();
     static ref REGEX_CONSTANT: Regex = Regex::new(r"^-?\d*\.?\d+$").unwrap();
     static ref REGEX_NEWLINE: Regex = Regex::new(r"^\n$").unwrap();
     static ref REGEX_COMMENT: Regex = Regex::new(r"^;.+").unwrap();
+    static ref REGEX_SYMBOL: Regex = Regex::new(r"^[a-zA-Z_]\w*$").unwrap();
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum LabelType {
     Global,
     Local,
@@ -23,6 +29,10 @@ pub enum Directive {
     Code,
     Data,
     Space,
+    Define,
+    Macro,
+    EndMacro,
+    Include,
 }
 
 impl Directive {
@@ -31,11 +41,35 @@ impl Directive {
             "@code"  => Directive::Code,
             "@data"  => Directive::Data,
             "@space" => Directive::Space,
+            "@define" | "@equ" => Directive::Define,
+            "@macro" => Directive::Macro,
+            "@endmacro" => Directive::EndMacro,
+            "@include" => Directive::Include,
             _ => panic!("{} is not not a valid directive.", string)
         }
     }
 }
 
+/// The file, line and column a token was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Spanned {
+    pub token: Token,
+    pub location: Location,
+}
+
 #[derive(Debug, Clone)]
 pub enum Token {
     Label(LabelType, String),
@@ -43,6 +77,9 @@ pub enum Token {
     Directive(Directive),
     Instruction(String),
     Constant(i64),
+    Expression(Expr),
+    Symbol(String),
+    Str(String),
     NewLine,
     Comment(String),
     Eof,
@@ -74,19 +111,50 @@ impl Token {
         }
         Err(format!("{} did not match any tokens during lexing.", string.clone()))
     }
+    /// The operand expression this token stands for, if it is an operand.
+    pub fn to_expr(&self) -> Option<Expr> {
+        match *self {
+            Token::Constant(value) => Some(Expr::Constant(value)),
+            Token::Reference(ref label_type, ref label) => Some(Expr::Reference(label_type.clone(), label.clone())),
+            Token::Expression(ref expr) => Some(expr.clone()),
+            _ => None,
+        }
+    }
+    /// Turn a parsed operand back into the simplest token that represents it.
+    pub fn from_expr(expr: Expr) -> Token {
+        if let Some(value) = expr.fold() {
+            return Token::Constant(value)
+        }
+        match expr {
+            Expr::Reference(label_type, label) => Token::Reference(label_type, label),
+            _ => Token::Expression(expr),
+        }
+    }
 }
 
 pub struct Lexer<'a> {
     source: &'a String,
     offset: usize,
     base_index: usize,
+    section: Option<Directive>,
+    file: Rc<str>,
+    line: usize,
+    line_start: usize,
 }
 impl<'a> Lexer<'a> {
+    #[cfg(test)]
     pub fn new(source: &'a String) -> Self {
+        Lexer::for_file(source, "<source>")
+    }
+    pub fn for_file(source: &'a String, file: &str) -> Self {
         Lexer {
-            source: source,
+            source,
             offset: 0,
             base_index: 0,
+            section: None,
+            file: Rc::from(file),
+            line: 1,
+            line_start: 0,
         }
     }
     pub fn next_valid(&mut self) -> &str {
@@ -116,16 +184,131 @@ impl<'a> Lexer<'a> {
         self.offset = 1;
         return rv
     }
+    fn location(&self, index: usize) -> Location {
+        Location {
+            file: self.file.clone(),
+            line: self.line,
+            column: index - self.line_start + 1,
+        }
+    }
+    /// Consume the rest of the line up to any comment, returning the trimmed
+    /// text and the location it starts at.
+    fn rest_of_line(&mut self) -> (&'a str, Location) {
+        let source: &'a String = self.source;
+        let start = self.base_index;
+        let bytes = source.as_bytes();
+        while self.base_index < bytes.len() && bytes[self.base_index] != b'\n' && bytes[self.base_index] != b';' {
+            self.base_index += 1;
+        }
+        let text = &source[start..self.base_index];
+        let trimmed = text.trim_start();
+        (trimmed.trim_end(), self.location(start + text.len() - trimmed.len()))
+    }
+    fn lex_operands(&mut self, tokens: &mut Vec<Spanned>) {
+        let (operands, location) = self.rest_of_line();
+        match Expr::parse_list(operands) {
+            Ok(exprs) => tokens.extend(exprs.into_iter().map(|expr| {
+                Spanned { token: Token::from_expr(expr), location: location.clone() }
+            })),
+            Err(err) => panic!("{}: Invalid operand '{}': {}", location, operands, err),
+        }
+    }
+    fn lex_values(&mut self, tokens: &mut Vec<Spanned>) {
+        let (values, location) = self.rest_of_line();
+        match Expr::parse_list(values) {
+            Ok(exprs) => tokens.extend(exprs.into_iter().map(|expr| {
+                Spanned { token: Token::from_expr(expr), location: location.clone() }
+            })),
+            Err(err) => panic!("{}: Invalid data '{}': {}", location, values, err),
+        }
+    }
+    fn lex_macro_header(&mut self, tokens: &mut Vec<Spanned>) {
+        let (header, location) = self.rest_of_line();
+        if header.is_empty() { panic!("{}: @macro must be followed by a macro name.", location) }
+        for name in header.split_whitespace() {
+            if !REGEX_SYMBOL.is_match(name) { panic!("{}: '{}' is not a valid macro or parameter name.", location, name) }
+            tokens.push(Spanned { token: Token::Symbol(name.to_owned()), location: location.clone() });
+        }
+    }
+    fn lex_define(&mut self, tokens: &mut Vec<Spanned>) {
+        let (line, location) = self.rest_of_line();
+        let (name, value) = match line.find(char::is_whitespace) {
+            Some(index) => (&line[..index], line[index..].trim()),
+            None => (line, ""),
+        };
+        if !REGEX_SYMBOL.is_match(name) { panic!("{}: '{}' is not a valid constant name.", location, name) }
+        if value.is_empty() { panic!("{}: Constant {} is missing a value.", location, name) }
+        tokens.push(Spanned { token: Token::Symbol(name.to_owned()), location: location.clone() });
+        match Expr::parse(value) {
+            Ok(expr) => tokens.push(Spanned { token: Token::from_expr(expr), location }),
+            Err(err) => panic!("{}: Invalid value for {}: {}", location, name, err),
+        }
+    }
+    fn lex_path(&mut self, tokens: &mut Vec<Spanned>) {
+        let (path, location) = self.rest_of_line();
+        if path.len() < 2 || !path.starts_with('"') || !path.ends_with('"') {
+            panic!("{}: @include must be followed by a quoted path.", location)
+        }
+        tokens.push(Spanned { token: Token::Str(path[1..path.len() - 1].to_owned()), location });
+    }
+    #[cfg(test)]
     pub fn lex(&mut self) -> Vec<Token> {
-        let mut tokens: Vec<Token> = Vec::new();
+        self.lex_spanned().into_iter().map(|spanned| spanned.token).collect()
+    }
+    pub fn lex_spanned(&mut self) -> Vec<Spanned> {
+        let mut tokens: Vec<Spanned> = Vec::new();
         while self.base_index < self.source.len() {
-            let string = self.next_valid();
-            match Token::from_string(string) {
-                Ok(tok) => { tokens.push(tok); },
-                Err(_) => panic!("Invalid token '{}'", string),
+            let string = self.next_valid().to_owned();
+            let location = self.location(self.base_index - string.len());
+            let token = match Token::from_string(&string) {
+                Ok(tok) => tok,
+                Err(_) => panic!("{}: Invalid token '{}'", location, string),
+            };
+            let spanned = Spanned { token: token.clone(), location };
+            tokens.push(spanned);
+            match token {
+                Token::Instruction(_) => self.lex_operands(&mut tokens),
+                Token::Directive(Directive::Define) => self.lex_define(&mut tokens),
+                Token::Directive(Directive::Macro) => self.lex_macro_header(&mut tokens),
+                Token::Directive(Directive::Include) => self.lex_path(&mut tokens),
+                Token::Directive(Directive::EndMacro) => {},
+                Token::Directive(directive) => { self.section = Some(directive); },
+                Token::Label(LabelType::Global, _) => {
+                    match self.section {
+                        Some(Directive::Data) | Some(Directive::Space) => self.lex_values(&mut tokens),
+                        _ => {},
+                    }
+                },
+                Token::NewLine => {
+                    self.line += 1;
+                    self.line_start = self.base_index;
+                },
+                _ => {},
             }
         }
-        tokens.push(Token::Eof);
+        tokens.push(Spanned { token: Token::Eof, location: self.location(self.base_index) });
         tokens
     }
 }
+
+
+#[test]
+fn test_lex_locations() {
+    let source = "@code\n._entry:\n  const 1 + 2 ; three\n  halt\n".to_owned();
+    let tokens = Lexer::for_file(&source, "test.asm").lex_spanned();
+    let locations: Vec<String> = tokens.iter().filter_map(|spanned| match spanned.token {
+        Token::Instruction(_) | Token::Constant(_) => Some(format!("{}", spanned.location)),
+        _ => None,
+    }).collect();
+    assert!(locations == vec!["test.asm:3:3", "test.asm:3:9", "test.asm:4:3"]);
+}
+
+#[test]
+fn test_lex_include_path() {
+    let source = "@include \"lib/math.asm\"\n".to_owned();
+    let tokens = Lexer::new(&source).lex();
+    match tokens[1] {
+        Token::Str(ref path) => assert!(path == "lib/math.asm"),
+        ref other => panic!("Unexpected token {:?}", other),
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+use super::expr::Expr;
+use super::lexer::{Token, Spanned, LabelType};
+
+
+/// A parameterized `@macro ... @endmacro` body.
+#[derive(Debug, Clone)]
+pub struct Macro {
+    pub name: String,
+    params: Vec<String>,
+    body: Vec<Spanned>,
+}
+
+impl Macro {
+    pub fn new(name: String, params: Vec<String>, body: Vec<Spanned>) -> Self {
+        for spanned in &body {
+            if let Token::Label(LabelType::Global, ref label) = spanned.token {
+                panic!("{}: Global label {} may not appear inside macro {}.", spanned.location, label, name)
+            }
+        }
+        Macro {
+            name,
+            params,
+            body,
+        }
+    }
+    /// Produce the body for one invocation. Local labels declared in the
+    /// body are suffixed with `id` so every expansion gets its own copy.
+    pub fn expand(&self, args: Vec<Expr>, id: usize) -> Result<Vec<Spanned>, String> {
+        if args.len() != self.params.len() {
+            return Err(format!("Macro {} takes {} arguments but was given {}.", self.name, self.params.len(), args.len()))
+        }
+        let locals: Vec<&String> = self.body.iter().filter_map(|spanned| match spanned.token {
+            Token::Label(LabelType::Local, ref label) => Some(label),
+            _ => None,
+        }).collect();
+        let relabel = |label_type: &LabelType, label: &str| match *label_type {
+            LabelType::Local if locals.iter().any(|local| *local == label) => Some(format!("{}.{}", label, id)),
+            _ => None,
+        };
+        let rename = |label_type: &LabelType, label: &str| {
+            relabel(label_type, label).map(|renamed| Expr::Reference(label_type.clone(), renamed))
+        };
+        let substitute = |name: &str| {
+            self.params.iter().position(|param| param == name).map(|index| args[index].clone())
+        };
+        Ok(self.body.iter().map(|spanned| {
+            let token = match spanned.token.to_expr() {
+                Some(expr) => Token::from_expr(expr.replace_references(&rename).substitute(&substitute)),
+                None => match spanned.token {
+                    Token::Label(LabelType::Local, ref label) => {
+                        Token::Label(LabelType::Local, relabel(&LabelType::Local, label).unwrap())
+                    },
+                    ref token => token.clone(),
+                },
+            };
+            Spanned { token, location: spanned.location.clone() }
+        }).collect())
+    }
+}
+
+/// Replace every macro invocation in `tokens` with the macro's body,
+/// expanding invocations nested inside macro bodies as well.
+pub fn expand_macros(tokens: Vec<Spanned>, macros: &HashMap<String, Macro>) -> Vec<Spanned> {
+    let mut count = 0;
+    expand(tokens, macros, &mut count, &mut Vec::new())
+}
+
+fn expand(tokens: Vec<Spanned>, macros: &HashMap<String, Macro>, count: &mut usize, active: &mut Vec<String>) -> Vec<Spanned> {
+    let mut expanded = Vec::new();
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(spanned) = tokens.next() {
+        let invoked = match spanned.token {
+            Token::Instruction(ref name) => macros.get(name),
+            _ => None,
+        };
+        match invoked {
+            Some(mac) => {
+                if active.contains(&mac.name) { panic!("{}: Macro {} expands itself.", spanned.location, mac.name) }
+                let mut args = Vec::new();
+                while let Some(arg) = tokens.peek().and_then(|next| next.token.to_expr()) {
+                    args.push(arg);
+                    tokens.next();
+                }
+                *count += 1;
+                let body = match mac.expand(args, *count) {
+                    Ok(body) => body,
+                    Err(err) => panic!("{}: {}", spanned.location, err),
+                };
+                active.push(mac.name.clone());
+                expanded.append(&mut expand(body, macros, count, active));
+                active.pop();
+            },
+            None => expanded.push(spanned),
+        }
+    }
+    expanded
+}
+
+
+#[cfg(test)]
+fn spanned(tokens: Vec<Token>) -> Vec<Spanned> {
+    use std::rc::Rc;
+    use super::lexer::Location;
+    let location = Location { file: Rc::from("test.asm"), line: 1, column: 1 };
+    tokens.into_iter().map(|token| Spanned { token, location: location.clone() }).collect()
+}
+
+#[test]
+fn test_expand_substitutes_arguments() {
+    let body = spanned(vec![
+        Token::Instruction("const".to_owned()),
+        Token::Expression(Expr::Symbol("value".to_owned())),
+        Token::Instruction("add".to_owned()),
+    ]);
+    let mac = Macro::new("add_to".to_owned(), vec!["value".to_owned()], body);
+    let tokens = mac.expand(vec![Expr::Constant(7)], 1).unwrap();
+    match tokens[1].token {
+        Token::Constant(7) => {},
+        ref other => panic!("Unexpected token {:?}", other),
+    }
+    assert!(mac.expand(Vec::new(), 2).is_err());
+}
+
+#[test]
+fn test_expand_renames_local_labels() {
+    let body = spanned(vec![
+        Token::Label(LabelType::Local, "'loop".to_owned()),
+        Token::Instruction("jmpnz".to_owned()),
+        Token::Reference(LabelType::Local, "'loop".to_owned()),
+        Token::Instruction("jmp".to_owned()),
+        Token::Reference(LabelType::Local, "'outside".to_owned()),
+    ]);
+    let mut macros = HashMap::new();
+    macros.insert("spin".to_owned(), Macro::new("spin".to_owned(), Vec::new(), body));
+    let tokens = spanned(vec![Token::Instruction("spin".to_owned()), Token::Instruction("spin".to_owned())]);
+    let labels: Vec<String> = expand_macros(tokens, &macros).into_iter().filter_map(|spanned| match spanned.token {
+        Token::Label(_, label) | Token::Reference(_, label) => Some(label),
+        _ => None,
+    }).collect();
+    assert!(labels == vec!["'loop.1", "'loop.1", "'outside", "'loop.2", "'loop.2", "'outside"]);
+}
+
+#[test]
+#[should_panic(expected = "Macro forever expands itself.")]
+fn test_expand_recursive_macro() {
+    let body = spanned(vec![Token::Instruction("forever".to_owned())]);
+    let mut macros = HashMap::new();
+    macros.insert("forever".to_owned(), Macro::new("forever".to_owned(), Vec::new(), body));
+    expand_macros(spanned(vec![Token::Instruction("forever".to_owned())]), &macros);
+}
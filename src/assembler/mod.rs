@@ -1,27 +1,71 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::prelude::*;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
 
+pub mod builder;
+pub mod expr;
+pub mod format;
 pub mod lexer;
+pub mod macros;
+pub mod peephole;
+pub mod pseudo;
 
-use self::lexer::{Token, Lexer, Directive, LabelType};
+use image::{Image, SourceLine};
+use opcode::{Opcode, OpcodeInfo, Operand, to_bytes_32};
+use linker::Linker;
+use linker::object::{ObjectFile, Symbol, Segment, Relocation};
+use self::expr::{Expr, BinaryOp};
+use self::macros::{Macro, expand_macros};
+use self::pseudo::expand_pseudo_instructions;
+use self::lexer::{Token, Spanned, Location, Lexer, Directive, LabelType};
 
 
 pub struct Assembler {
     source: String,
+    path: Option<PathBuf>,
+    included: HashSet<PathBuf>,
+    sources: Vec<(String, String)>,
+    listed: Vec<Listed>,
     globals: HashMap<String, usize>,
-    bytecode: Vec<u8>,
+    spaces: Vec<(String, usize)>,
+    space_size: usize,
+    defines: HashMap<String, Expr>,
+    macros: HashMap<String, Macro>,
+    relative_jumps: bool,
+    peephole: bool,
+    data: Vec<u32>,
     current_line: usize,
     errors: Vec<String>,
-    directives: HashMap<Directive, Vec<Token>>,
+    directives: HashMap<Directive, Vec<Spanned>>,
+}
+
+/// The bytes one source line produced in a segment, for the listing.
+struct Listed {
+    location: Location,
+    segment: Segment,
+    offset: usize,
+    bytes: Vec<u8>,
 }
 
 #[derive(Debug)]
 struct GlobalSection<'g> {
     bytes_size: Option<usize>,
-    tokens: Vec<&'g Token>,
+    tokens: Vec<&'g Spanned>,
     locals: HashMap<String, usize>,
     bytecode: Vec<u8>,
 }
 
+/// The global section whose tokens are being emitted, and where it and the
+/// other sections start in the object's code.
+struct Emitting<'a, 'g: 'a> {
+    label: &'a String,
+    section: &'a GlobalSection<'g>,
+    offset: usize,
+    code_labels: &'a HashMap<String, usize>,
+}
+
 impl<'g> GlobalSection<'g> {
     pub fn new() -> GlobalSection<'g> {
         Self {
@@ -33,17 +77,36 @@ impl<'g> GlobalSection<'g> {
     }
     fn record_local_info(&mut self) {
         let mut count: usize = 0;
+        let mut expected: Option<(&Spanned, Operand)> = None;
         let tokens = &self.tokens;
-        for token in tokens {
-            match token {
-                &&Token::Label(LabelType::Local, ref label) => {
+        for spanned in tokens {
+            match spanned.token {
+                Token::Label(LabelType::Local, ref label) => {
                     self.locals.insert(label.clone(), count);
                 },
-                &&Token::Reference(_, _) | &&Token::Constant(_) => { count += 4; },
-                &&Token::Instruction(_) => { count += 1; },
+                Token::Reference(_, _) | Token::Constant(_) | Token::Expression(_) => {
+                    match expected.take() {
+                        Some((_, operand)) => { count += operand.width(); },
+                        None => panic!("{}: Unexpected operand.", spanned.location),
+                    }
+                },
+                Token::Instruction(ref inst) => {
+                    if let Some((instruction, _)) = expected {
+                        panic!("{}: {:?} is missing its operand.", instruction.location, instruction.token)
+                    }
+                    let info = match OpcodeInfo::from_mnemonic(inst) {
+                        Some(info) => info,
+                        None => panic!("{}: {} is not a valid instruction", spanned.location, inst),
+                    };
+                    if info.operand != Operand::None { expected = Some((spanned, info.operand)); }
+                    count += 1;
+                },
                 _ => {}
             }
         }
+        if let Some((instruction, _)) = expected {
+            panic!("{}: {:?} is missing its operand.", instruction.location, instruction.token)
+        }
         self.bytes_size = Some(count)
     }
 }
@@ -51,103 +114,437 @@ impl<'g> GlobalSection<'g> {
 impl Assembler {
     pub fn new(source: String) -> Self {
         Self {
-            source: source,
+            source,
+            path: None,
+            included: HashSet::new(),
+            sources: Vec::new(),
+            listed: Vec::new(),
             globals: HashMap::new(),
-            bytecode: Vec::new(),
+            spaces: Vec::new(),
+            space_size: 0,
+            defines: HashMap::new(),
+            macros: HashMap::new(),
+            relative_jumps: false,
+            peephole: false,
+            data: Vec::new(),
             current_line: 0,
             errors: Vec::new(),
             directives: HashMap::new(),
         }
     }
-    pub fn assemble(&mut self) -> Vec<u8> {
-        let tokens = Lexer::new(&self.source.clone()).lex();
+    pub fn from_file(path: &str) -> Self {
+        let mut source = String::new();
+        let mut file = File::open(path).unwrap();
+        file.read_to_string(&mut source).unwrap();
+        Assembler::with_path(source, path)
+    }
+    /// Assemble `source` as though it were read from `path`, so locations
+    /// and `@include`s resolve against it.
+    pub fn with_path(source: String, path: &str) -> Self {
+        let mut assembler = Assembler::new(source);
+        assembler.path = Some(PathBuf::from(path));
+        assembler
+    }
+    /// Encode `jmp` as `jmp_rel` whenever its target is in the same object,
+    /// so the jump keeps working wherever the linker places the code.
+    pub fn relative_jumps(&mut self, enabled: bool) {
+        self.relative_jumps = enabled;
+    }
+    /// Run the peephole optimizer over the `@code` section before encoding.
+    pub fn peephole(&mut self, enabled: bool) {
+        self.peephole = enabled;
+    }
+    pub fn assemble(&mut self) -> Image {
+        let mut linker = Linker::new();
+        linker.add(self.assemble_object());
+        match linker.link() {
+            Ok(image) => image,
+            Err(err) => panic!("{}", err),
+        }
+    }
+    /// Assemble the source into a relocatable object. Every operand that
+    /// depends on a label address is left for the linker to patch.
+    pub fn assemble_object(&mut self) -> ObjectFile {
+        let tokens = self.lex_root();
         self.load_directives(tokens);
+        self.expand_macros();
+        if self.peephole { self.optimize(); }
         self.handle_data_section();
+        self.handle_space_section();
 
-        let mut datasize = 6;
-        let mut globals = HashMap::new();
-        for (k, v) in self.globals.clone() {
-            globals.insert(k, v + 6);
+        let name = match self.path {
+            Some(ref path) => path.display().to_string(),
+            None => "<source>".to_owned(),
+        };
+        let mut object = ObjectFile::new(&name);
+        object.data = self.data.clone();
+        let mut globals: Vec<(&String, &usize)> = self.globals.iter().collect();
+        globals.sort_by_key(|&(label, offset)| (*offset, label.clone()));
+        for (label, offset) in globals {
+            object.symbols.push(Symbol { name: label.clone(), segment: Segment::Data, offset: *offset });
         }
-
-        if let Some(data) = self.directives.get(&Directive::Data) {
-            for token in data {
-                if let &Token::Constant(_) = token { datasize += 4 };
-            }
+        object.space = self.space_size;
+        for &(ref label, offset) in &self.spaces {
+            object.symbols.push(Symbol { name: label.clone(), segment: Segment::Space, offset });
         }
 
-        let mut secvec: Vec<(GlobalSection, usize)> = Vec::new();
+        let mut offset = 0;
+        let mut secvec: Vec<(String, GlobalSection, usize)> = Vec::new();
         for (label, mut section) in self.make_global_sections() {
-            globals.insert(label, datasize);
             section.record_local_info();
             let bytesize = section.bytes_size.unwrap();
-            secvec.push((section, datasize));
-            datasize += bytesize;
-        }
-        let mut bytecode: Vec<u8> = Vec::new();
-        bytecode.push(match_instruction("jmp".to_owned()));
-        if let Some(addr) = globals.get("._entry") {
-            bytecode.append(&mut to_bytes_32(*addr as i64));
-        } else { panic!("SlangASM requires a global entry point '._entry'")}
-        bytecode.push(0);
-        bytecode.append(&mut self.bytecode.clone());
-        for section in secvec {
-            let mut bytecodes = Vec::new();
-            for tok in &section.0.tokens {
-                match tok {
-                    &&Token::Reference(LabelType::Global, ref label) => {
-                        if let Some(addr) = globals.get(&label.clone()) {
-                            bytecodes.append(&mut to_bytes_32(*addr as i64))
-                        } else { panic!("{} is not a known label.", label)}
-                    },
-                    &&Token::Reference(LabelType::Local, ref label) => {
-                        if let Some(addr) = section.0.locals.get(&label.clone()) {
-                            let baseaddr = section.1 as i64;
-                            bytecodes.append(&mut to_bytes_32(*addr as i64 + baseaddr))
-                        } else { panic!("{} is not a known local label.", label)}
-                    },
-                    &&Token::Constant(value) => {bytecodes.append(&mut to_bytes_32(value))},
-                    &&Token::Instruction(ref inst) => {
-                        bytecodes.push(match_instruction(inst.clone()));
-                    },
-                    _ => {}
+            object.symbols.push(Symbol { name: label.clone(), segment: Segment::Code, offset });
+            secvec.push((label, section, offset));
+            offset += bytesize;
+        }
+        let code_labels: HashMap<String, usize> = secvec.iter().map(|&(ref label, _, offset)| (label.clone(), offset)).collect();
+        let mut listed = Vec::new();
+        for (label, section, section_offset) in secvec {
+            let emitting = Emitting { label: &label, section: &section, offset: section_offset, code_labels: &code_labels };
+            let mut opcode = 0;
+            for spanned in &section.tokens {
+                let start = object.code.len();
+                self.emit_token(&mut object, spanned, &emitting, &mut opcode);
+                list(&mut listed, &spanned.location, Segment::Code, start, &object.code[start..]);
+            }
+        }
+        self.listed.append(&mut listed);
+        for relocation in &object.relocations {
+            for (_, label) in relocation.expr.references() {
+                if object.symbol(label).is_none() && !object.imports.iter().any(|import| import == label) {
+                    object.imports.push(label.to_owned());
+                }
+            }
+        }
+        object
+    }
+    /// Render a listing of the last assembled object: every source line with
+    /// the segment offset and bytes it produced, followed by the symbol table.
+    /// Offsets are from the start of the object's segment, not addresses in
+    /// the linked program, which also depend on the header and link order.
+    pub fn listing(&self, object: &ObjectFile) -> String {
+        let mut rows: HashMap<(&str, usize), Vec<&Listed>> = HashMap::new();
+        for listed in &self.listed {
+            rows.entry((&*listed.location.file, listed.location.line)).or_default().push(listed);
+        }
+        let mut out = String::new();
+        for (name, source) in &self.sources {
+            out.push_str(&format!("{}\n", name));
+            out.push_str(&format!("{:>5}  {:8}{:24}{}\n", "line", "offset", "bytes", "source"));
+            for (index, text) in source.lines().enumerate() {
+                let line = index + 1;
+                let listed = match rows.get(&(&**name, line)) {
+                    Some(listed) => listed,
+                    None => { out.push_str(&format!("{:>5}  {:32}{}\n", line, "", text)); continue },
+                };
+                let mut text = text;
+                for row in listed {
+                    let step = match row.segment { Segment::Code => 8, _ => 2 };
+                    for (n, chunk) in row.bytes.chunks(8).enumerate() {
+                        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+                        out.push_str(&format!("{:>5}  {} {:04X}  {:24}{}\n", line, segment_tag(&row.segment),
+                                              row.offset + n * step, hex.join(" "), text));
+                        text = "";
+                    }
+                }
+            }
+        }
+        let mut symbols: Vec<&Symbol> = object.symbols.iter().collect();
+        symbols.sort_by_key(|symbol| (segment_tag(&symbol.segment), symbol.offset));
+        out.push_str("\nSymbols (segment offsets)\n");
+        for symbol in symbols {
+            out.push_str(&format!("  {} {:04X}  {}\n", segment_tag(&symbol.segment), symbol.offset, symbol.name));
+        }
+        for import in &object.imports {
+            out.push_str(&format!("  ? ----  {}\n", import));
+        }
+        out
+    }
+    /// Append the encoding of one code token, resolving what can be resolved
+    /// within this object and leaving a relocation for the rest.
+    fn emit_token(&self, object: &mut ObjectFile, spanned: &Spanned, emitting: &Emitting, opcode: &mut u8) {
+        let label = emitting.label;
+        if let Token::Instruction(ref inst) = spanned.token {
+            *opcode = match_instruction(inst.clone());
+            object.lines.push(SourceLine {
+                address: object.code.len(),
+                file: spanned.location.file.to_string(),
+                line: spanned.location.line,
+                column: spanned.location.column,
+                label: label.clone(),
+            });
+            object.code.push(*opcode);
+            return
+        }
+        let mut expr = match spanned.token.to_expr() {
+            Some(expr) => self.localize(&self.expand_defines(&expr), label, emitting.section, &spanned.location),
+            None => return,
+        };
+        let mut operand = Opcode::from_value(*opcode).info().operand;
+        let relative = matches!(operand, Operand::Offset(_));
+        let convertible = self.relative_jumps && *opcode == Opcode::Jmp as u8
+                          && Opcode::RelJmp.info().operand.width() == operand.width();
+        if (relative || convertible) && !expr.references().is_empty() {
+            let end = object.code.len() + operand.width();
+            let local = expr.replace_references(&|_: &LabelType, label: &str| {
+                emitting.code_labels.get(label).map(|offset| Expr::Constant(*offset as i64))
+            });
+            match local.fold() {
+                Some(target) => {
+                    if !relative {
+                        *opcode = Opcode::RelJmp as u8;
+                        *object.code.last_mut().unwrap() = *opcode;
+                        operand = Opcode::RelJmp.info().operand;
+                    }
+                    let offset = target - end as i64;
+                    match operand.encode(offset) {
+                        Some(mut bytes) => object.code.append(&mut bytes),
+                        None => panic!("{}: Relative jump offset {} does not fit in {} bytes.", spanned.location, offset, operand.width()),
+                    }
+                    return
+                },
+                None if relative => {
+                    let here = Expr::Binary(
+                        BinaryOp::Add,
+                        Box::new(Expr::Reference(LabelType::Global, label.clone())),
+                        Box::new(Expr::Constant((end - emitting.offset) as i64)),
+                    );
+                    expr = Expr::Binary(BinaryOp::Sub, Box::new(expr), Box::new(here));
+                },
+                None => {},
+            }
+        }
+        match expr.fold() {
+            Some(value) => match operand.encode(value) {
+                Some(mut bytes) => object.code.append(&mut bytes),
+                None => panic!("{}: {} does not fit in a {} byte operand.", spanned.location, value, operand.width()),
+            },
+            None => {
+                if operand.width() != 4 {
+                    panic!("{}: {} must be known at assembly time to fit a {} byte operand.", spanned.location, expr, operand.width())
+                }
+                object.relocations.push(Relocation { offset: object.code.len(), expr });
+                object.code.append(&mut vec![0; 4]);
+            }
+        }
+    }
+    /// Rewrite references to local labels as offsets from the enclosing
+    /// global label, so only global labels are left for the linker.
+    fn localize(&self, expr: &Expr, global: &str, section: &GlobalSection, location: &Location) -> Expr {
+        if let Some(symbol) = expr.symbols().first() {
+            panic!("{}: {} is not a defined symbol.", location, symbol)
+        }
+        for (label_type, label) in expr.references() {
+            if *label_type == LabelType::Local && !section.locals.contains_key(label) {
+                panic!("{}: {} is not a known local label.", location, label)
+            }
+        }
+        expr.replace_references(&|label_type: &LabelType, label: &str| match *label_type {
+            LabelType::Local => section.locals.get(label).map(|offset| Expr::Binary(
+                BinaryOp::Add,
+                Box::new(Expr::Reference(LabelType::Global, global.to_owned())),
+                Box::new(Expr::Constant(*offset as i64)),
+            )),
+            LabelType::Global => None,
+        })
+    }
+    fn lex_root(&mut self) -> Vec<Spanned> {
+        let source = self.source.clone();
+        let mut including = Vec::new();
+        let name = match self.path.clone() {
+            Some(path) => {
+                if let Ok(resolved) = path.canonicalize() {
+                    self.included.insert(resolved.clone());
+                    including.push(resolved);
                 }
+                path.display().to_string()
+            },
+            None => "<source>".to_owned(),
+        };
+        self.lex_source(&source, &name, &mut including)
+    }
+    /// Lex `source`, splicing in the tokens of every file it includes.
+    fn lex_source(&mut self, source: &String, name: &str, including: &mut Vec<PathBuf>) -> Vec<Spanned> {
+        self.sources.push((name.to_owned(), source.clone()));
+        let mut spliced = Vec::new();
+        let mut section: Option<Directive> = None;
+        let mut tokens = Lexer::for_file(source, name).lex_spanned().into_iter();
+        while let Some(spanned) = tokens.next() {
+            match spanned.token {
+                Token::Directive(Directive::Include) => {},
+                Token::Directive(Directive::Code) | Token::Directive(Directive::Data) | Token::Directive(Directive::Space) => {
+                    if let Token::Directive(ref directive) = spanned.token { section = Some(directive.clone()); }
+                    spliced.push(spanned);
+                    continue
+                },
+                _ => { spliced.push(spanned); continue },
             }
-            bytecode.append(&mut bytecodes);
+            let path = match tokens.next() {
+                Some(Spanned { token: Token::Str(path), .. }) => path,
+                _ => panic!("{}: @include must be followed by a quoted path.", spanned.location),
+            };
+            spliced.append(&mut self.include(&path, &spanned.location, including));
+            if let Some(ref directive) = section {
+                spliced.push(Spanned { token: Token::Directive(directive.clone()), location: spanned.location.clone() });
+            }
+        }
+        spliced
+    }
+    fn include(&mut self, path: &str, location: &Location, including: &mut Vec<PathBuf>) -> Vec<Spanned> {
+        let relative = match including.last() {
+            Some(parent) => parent.parent().unwrap_or(Path::new("")).join(path),
+            None => PathBuf::from(path),
+        };
+        let resolved = match relative.canonicalize() {
+            Ok(resolved) => resolved,
+            Err(err) => panic!("{}: Cannot include \"{}\": {}", location, path, err),
+        };
+        if including.contains(&resolved) {
+            let cycle: Vec<String> = including.iter().chain(Some(&resolved))
+                                              .map(|file| file.display().to_string()).collect();
+            panic!("{}: Include cycle {}", location, cycle.join(" -> "))
         }
-        bytecode
+        if !self.included.insert(resolved.clone()) { return Vec::new(); }
+        let mut source = String::new();
+        if let Err(err) = File::open(&resolved).and_then(|mut file| file.read_to_string(&mut source)) {
+            panic!("{}: Cannot include \"{}\": {}", location, path, err)
+        }
+        including.push(resolved);
+        let mut tokens = self.lex_source(&source, &relative.display().to_string(), including);
+        including.pop();
+        if let Some(&Spanned { token: Token::Eof, .. }) = tokens.last() { tokens.pop(); }
+        tokens
     }
+    /// Collect the words of the `@data` section, which the VM copies into
+    /// global memory at startup. Data labels are word offsets into it.
     fn handle_data_section(&mut self) {
         if let Some(directive) = self.directives.get(&Directive::Data) {
-            let mut total_bytes = 0;
+            let mut total_words = 0;
             let mut newline = true;
-            for token in directive {
-                match token {
-                    &Token::NewLine => { newline = true },
-                    &Token::Reference(_, _) => { panic!("References my not appear in the data section.") },
-                    &Token::Label(LabelType::Global, ref label) => {
-                        if !newline { panic!("Global labels must be the first on the line.")};
-                        self.globals.insert(label.clone(), total_bytes);
+            for spanned in directive {
+                match spanned.token {
+                    Token::NewLine => { newline = true },
+                    Token::Reference(_, _) => { panic!("{}: References my not appear in the data section.", spanned.location) },
+                    Token::Label(LabelType::Global, ref label) => {
+                        if !newline { panic!("{}: Global labels must be the first on the line.", spanned.location)};
+                        self.globals.insert(label.clone(), total_words);
                     },
-                    &Token::Constant(val) => {
-                        let mut bytes = to_bytes_32(val);
-                        self.bytecode.append(&mut bytes);
-                        total_bytes += 4;
-                    }
-                    _ => panic!("NOPE")
+                    Token::Constant(val) => {
+                        list(&mut self.listed, &spanned.location, Segment::Data, total_words, &to_bytes_32(val));
+                        self.data.push(data_word(val, &spanned.location));
+                        total_words += 1;
+                    },
+                    Token::Expression(ref expr) => {
+                        let val = match self.expand_defines(expr).evaluate(&|_: &Expr| None) {
+                            Ok(val) => val,
+                            Err(err) => panic!("{}: {}", spanned.location, err),
+                        };
+                        list(&mut self.listed, &spanned.location, Segment::Data, total_words, &to_bytes_32(val));
+                        self.data.push(data_word(val, &spanned.location));
+                        total_words += 1;
+                    },
+                    _ => panic!("{}: NOPE", spanned.location)
+                }
+            }
+        }
+    }
+    /// Reserve a region of global memory for every `.label: size` in the
+    /// `@space` section. Nothing is emitted; the VM's memory starts zeroed.
+    fn handle_space_section(&mut self) {
+        let mut spaces = Vec::new();
+        let mut total_words = 0;
+        if let Some(directive) = self.directives.get(&Directive::Space) {
+            let mut tokens = directive.iter();
+            while let Some(spanned) = tokens.next() {
+                let label = match spanned.token {
+                    Token::Label(LabelType::Global, ref label) => label,
+                    _ => panic!("{}: Every @space entry must be a global label followed by a size.", spanned.location),
+                };
+                let size = match tokens.next().and_then(|size| size.token.to_expr()) {
+                    Some(size) => self.expand_defines(&size).evaluate(&|_: &Expr| None),
+                    None => Err(format!("{} is missing a size.", label)),
+                };
+                match size {
+                    Ok(size) if size >= 0 => {
+                        spaces.push((label.clone(), total_words));
+                        total_words += size as usize;
+                    },
+                    Ok(size) => panic!("{}: {} has a negative size {}.", spanned.location, label, size),
+                    Err(err) => panic!("{}: {}", spanned.location, err),
                 }
             }
         }
+        self.spaces = spaces;
+        self.space_size = total_words;
+    }
+    fn expand_defines(&self, expr: &Expr) -> Expr {
+        expr.substitute(&|name: &str| self.defines.get(name).cloned())
+    }
+    fn define(&mut self, name: String, value: Expr, location: &Location) {
+        if self.defines.contains_key(&name) { panic!("{}: {} is already defined.", location, name) }
+        let value = self.expand_defines(&value);
+        if let Some(symbol) = value.symbols().first() {
+            panic!("{}: {} is not a defined symbol.", location, symbol)
+        }
+        self.defines.insert(name, value);
+    }
+    fn expand_macros(&mut self) {
+        if let Some(tokens) = self.directives.remove(&Directive::Code) {
+            let expanded = expand_pseudo_instructions(expand_macros(tokens, &self.macros));
+            self.directives.insert(Directive::Code, expanded);
+        }
+    }
+    fn optimize(&mut self) {
+        if let Some(tokens) = self.directives.remove(&Directive::Code) {
+            self.directives.insert(Directive::Code, peephole::optimize(tokens));
+        }
+    }
+    fn load_macro<I>(&mut self, tokens: &mut Peekable<I>, location: &Location) where I: Iterator<Item=Spanned> {
+        let name = match tokens.next() {
+            Some(Spanned { token: Token::Symbol(name), .. }) => name,
+            _ => panic!("{}: @macro must be followed by a macro name.", location),
+        };
+        let mut params = Vec::new();
+        while let Some(&Spanned { token: Token::Symbol(_), .. }) = tokens.peek() {
+            if let Some(Spanned { token: Token::Symbol(param), .. }) = tokens.next() { params.push(param); }
+        }
+        let mut body = Vec::new();
+        loop {
+            match tokens.next() {
+                Some(Spanned { token: Token::Directive(Directive::EndMacro), .. }) => break,
+                Some(Spanned { token: Token::Directive(_), location }) => {
+                    panic!("{}: Directives may not appear inside macro {}.", location, name)
+                },
+                Some(Spanned { token: Token::Eof, .. }) | None => panic!("{}: Macro {} is missing @endmacro.", location, name),
+                Some(Spanned { token: Token::NewLine, .. }) | Some(Spanned { token: Token::Comment(_), .. }) => {},
+                Some(spanned) => body.push(spanned),
+            }
+        }
+        if self.macros.contains_key(&name) { panic!("{}: Macro {} is already defined.", location, name) }
+        self.macros.insert(name.clone(), Macro::new(name, params, body));
     }
-    fn load_directives(&mut self, tokens: Vec<Token>) {
+    fn load_directives(&mut self, tokens: Vec<Spanned>) {
         let mut curdir: Option<Directive> = None;
-        let mut curvec: Vec<Token> = Vec::new();
-        for t in tokens {
-            match t {
+        let mut curvec: Vec<Spanned> = Vec::new();
+        let mut tokens = tokens.into_iter().peekable();
+        while let Some(t) = tokens.next() {
+            match t.token {
                 Token::NewLine | Token::Comment(_) => {},
+                Token::Directive(Directive::Macro) => self.load_macro(&mut tokens, &t.location),
+                Token::Directive(Directive::EndMacro) => panic!("{}: @endmacro without a matching @macro.", t.location),
+                Token::Directive(Directive::Define) => {
+                    let name = match tokens.next() {
+                        Some(Spanned { token: Token::Symbol(name), .. }) => name,
+                        _ => panic!("{}: @define must be followed by a constant name.", t.location),
+                    };
+                    match tokens.next().and_then(|value| value.token.to_expr()) {
+                        Some(value) => self.define(name, value, &t.location),
+                        None => panic!("{}: Constant {} is missing a value.", t.location, name),
+                    }
+                },
                 Token::Directive(dir) => {
                     match curdir {
-                        Some(directive) => { self.directives.insert(directive, curvec.clone()); },
+                        Some(directive) => { self.directives.entry(directive).or_default().append(&mut curvec); },
                         None => {}
                     };
                     curdir = Some(dir);
@@ -155,7 +552,7 @@ impl Assembler {
                 },
                 Token::Eof => {
                     match curdir {
-                        Some(directive) => { self.directives.insert(directive, curvec.clone()); },
+                        Some(directive) => { self.directives.entry(directive).or_default().append(&mut curvec); },
                         None => {}
                     };
                     break
@@ -163,89 +560,78 @@ impl Assembler {
                 _ => {
                     match curdir {
                         Some(_) => curvec.push(t),
-                        None => panic!("Tokens must fall within a directive."),
+                        None => panic!("{}: Tokens must fall within a directive.", t.location),
                     }
                 }
             }
         }
     }
-    fn make_global_sections<'a>(&'a self) -> HashMap<String, GlobalSection> {
+    /// Split the `@code` section at its global labels, in source order.
+    fn make_global_sections<'a>(&'a self) -> Vec<(String, GlobalSection<'a>)> {
         let tokens = match self.directives.get(&Directive::Code) {
             Some(token_vec) => token_vec,
             None => panic!("NO TOKENS IN @CODE!!!!"),
         };
         let mut current_label: Option<String> = None;
         let mut section = GlobalSection::new();
-        let mut sections: HashMap<String, GlobalSection> = HashMap::new();
-        for token in tokens {
-            match token {
-                &Token::Label(LabelType::Global, ref label) => {
+        let mut sections: Vec<(String, GlobalSection)> = Vec::new();
+        for spanned in tokens {
+            match spanned.token {
+                Token::Label(LabelType::Global, ref label) => {
+                    if current_label.as_ref() == Some(label) || sections.iter().any(|(defined, _)| defined == label) {
+                        panic!("{}: Global label {} is already defined.", spanned.location, label)
+                    }
                     match current_label.clone() {
                         None => { current_label = Some(label.clone()); },
                         Some(clabel) => {
-                            sections.insert(clabel, section);
+                            sections.push((clabel, section));
                             section = GlobalSection::new();
                             current_label = Some(label.clone());
                         }
                     };
                 },
-                &Token::Comment(_) => {},
-                _ => { section.tokens.push(token); }
+                Token::Comment(_) => {},
+                _ => { section.tokens.push(spanned); }
             }
         }
-        sections.insert(current_label.unwrap().clone(), section);
+        sections.push((current_label.unwrap().clone(), section));
         sections
     }
 }
 
 fn match_instruction(inst: String) -> u8 {
-    match &*inst.to_lowercase() {
-        "noop"        => 0x00,
-        "const"       => 0x10,
-        "load"        => 0x11,
-        "gload"      => 0x12,
-        "store"       => 0x14,
-        "gstore"     => 0x15,
-        "call"        => 0x18,
-        "dup"         => 0x30,
-        "swap"        => 0x31,
-        "add"         => 0x40,
-        "sub"         => 0x41,
-        "mul"         => 0x42,
-        "div"         => 0x43,
-        "pow"         => 0x44,
-        "mod"         => 0x45,
-        "shl"         => 0x50,
-        "shr"         => 0x51,
-        "and"         => 0x52,
-        "or"          => 0x53,
-        "xor"         => 0x54,
-        "not"         => 0x55,
-        "cmp_eq"      => 0x61,
-        "cmp_ne"      => 0x62,
-        "cmp_gt"      => 0x63,
-        "cmp_lt"      => 0x64,
-        "jmp_rel"     => 0x80,
-        "jmp_rel_eq"  => 0x81,
-        "jmp_rel_ne"  => 0x82,
-        "jmp_rel_gt"  => 0x83,
-        "jmp_rel_lt"  => 0x84,
-        "jmp"         => 0x88,
-        "jmpnz"       => 0x89,
-        "ret"         => 0xA0,
-        "print"       => 0xE0,
-        "halt"        => 0xF0,
-        _ => panic!("{} is not a valid instruction", inst),
+    match OpcodeInfo::from_mnemonic(&inst) {
+        Some(info) => info.opcode as u8,
+        None => panic!("{} is not a valid instruction", inst),
+    }
+}
+/// The data word holding `value`, which must fit in 32 bits like a code
+/// operand.
+fn data_word(value: i64, location: &Location) -> u32 {
+    match Operand::Immediate(4).encode(value) {
+        Some(_) => value as u32,
+        None => panic!("{}: {} does not fit in a 4 byte data word.", location, value),
     }
 }
-fn to_bytes_32(value: i64) -> Vec<u8> {
-    let val = (value & 0xFFFFFFFF) as i32;
-    let mut bytes: Vec<u8> = Vec::new();
-    for n in 0..4 {
-        let shift = (3 - n) * 8;
-        bytes.push((val >> shift & 0xFF) as u8);
+/// Record `bytes` emitted at `offset` for the listing, extending the previous
+/// entry when it belongs to the same source line.
+fn list(listed: &mut Vec<Listed>, location: &Location, segment: Segment, offset: usize, bytes: &[u8]) {
+    if bytes.is_empty() { return }
+    if let Some(last) = listed.last_mut() {
+        if last.location.file == location.file && last.location.line == location.line && last.segment == segment {
+            last.bytes.extend_from_slice(bytes);
+            return
+        }
+    }
+    listed.push(Listed { location: location.clone(), segment, offset, bytes: bytes.to_vec() });
+}
+
+fn segment_tag(segment: &Segment) -> char {
+    match *segment {
+        Segment::Code => 'C',
+        Segment::Data => 'D',
+        Segment::Space => 'S',
     }
-    bytes
 }
 
 #[test]
@@ -258,9 +644,214 @@ fn test_new_assembler() {
 }
 
 #[test]
-fn test_to_bytes_32() {
-    let value = 999999;
-    let exp: Vec<u8> = vec![0, 15, 66, 63];
-    let res = to_bytes_32(value);
-    assert!(res == exp);
+fn test_assemble_expression_operands() {
+    let source = "@code\n._entry:\n  const 'target + 4*2\n  const (3 + 4) << 1\n  'target:\n  halt\n";
+    let bytes = Assembler::new(source.to_owned()).assemble().code;
+    assert!(bytes[6..11] == [0x10, 0, 0, 0, 24]);
+    assert!(bytes[11..16] == [0x10, 0, 0, 0, 14]);
+}
+
+#[test]
+fn test_assemble_relative_jumps() {
+    let source = "@code\n._entry:\n  'top:\n  const 1\n  jmp_rel 'top\n  jmp_rel_ne 'end\n  jmp_rel -3\n  'end:\n  jmp 'top\n  halt\n";
+    let bytes = Assembler::new(source.to_owned()).assemble().code;
+    assert!(bytes[11..16] == [0x80, 0xFF, 0xFF, 0xFF, 0xF6]);
+    assert!(bytes[16..21] == [0x82, 0, 0, 0, 5]);
+    assert!(bytes[21..26] == [0x80, 0xFF, 0xFF, 0xFF, 0xFD]);
+    assert!(bytes[26..31] == [0x88, 0, 0, 0, 6]);
+
+    let mut assembler = Assembler::new(source.to_owned());
+    assembler.relative_jumps(true);
+    let bytes = assembler.assemble().code;
+    assert!(bytes[26..31] == [0x80, 0xFF, 0xFF, 0xFF, 0xE7]);
+}
+
+#[test]
+fn test_assemble_relative_jump_across_objects() {
+    let mut linker = Linker::new();
+    linker.add(Assembler::new("@code\n._entry:\n  jmp_rel .lib\n".to_owned()).assemble_object());
+    linker.add(Assembler::new("@code\n.lib:\n  halt\n".to_owned()).assemble_object());
+    let image = linker.link().unwrap();
+    assert!(image.code[6..11] == [0x80, 0, 0, 0, 0]);
+}
+
+#[test]
+fn test_assemble_pseudo_instructions() {
+    let source = "@code\n._entry:\n  push 3 4\n  swap_drop\n  'loop:\n  dec\n  dup\n  jmpz 'done\n  jmp 'loop\n  'done:\n  halt\n";
+    let bytes = Assembler::new(source.to_owned()).assemble().code;
+    assert!(bytes[16..18] == [0x31, 0x32]);
+    assert!(bytes[18..25] == [0x10, 0xFF, 0xFF, 0xFF, 0xFF, 0x40, 0x30]);
+    assert!(bytes[25..36] == [0x10, 0, 0, 0, 0, 0x61, 0x89, 0, 0, 0, 41]);
+    assert!(bytes[36..41] == [0x88, 0, 0, 0, 18]);
+    assert!(bytes[41] == 0xF0);
+    let source = "@code\n._entry:\n  const 1\n  jmpz 6\n  halt\n";
+    let bytes = Assembler::new(source.to_owned()).assemble().code;
+    assert!(bytes[11..22] == [0x10, 0, 0, 0, 0, 0x61, 0x89, 0, 0, 0, 6]);
+}
+
+#[test]
+fn test_assemble_peephole() {
+    let source = "@code\n._entry:\n  push 1 2\n  add\n  jmp 'skip\n  'skip:\n  jmp 'end\n  'end:\n  store 4\n  load 4\n  halt\n";
+    let plain = Assembler::new(source.to_owned()).assemble().code;
+    assert!(plain.len() == 6 + 32);
+    let mut assembler = Assembler::new(source.to_owned());
+    assembler.peephole(true);
+    let bytes = assembler.assemble().code;
+    assert!(bytes[6..] == [0x10, 0, 0, 0, 3, 0x88, 0, 0, 0, 21, 0x88, 0, 0, 0, 21, 0x30, 0x14, 0, 0, 0, 4, 0xF0]);
+}
+
+#[test]
+#[should_panic(expected = "<source>:3:3: Instruction(\"const\") is missing its operand.")]
+fn test_assemble_missing_operand() {
+    Assembler::new("@code\n._entry:\n  const\n  halt\n".to_owned()).assemble();
+}
+
+#[test]
+#[should_panic(expected = "4294967296 does not fit in a 4 byte operand.")]
+fn test_assemble_operand_range() {
+    Assembler::new("@code\n._entry:\n  const 1 << 32\n  halt\n".to_owned()).assemble();
+}
+
+#[test]
+fn test_assemble_source_order() {
+    let source = "@code\n._entry:\n  call .second\n  halt\n.first:\n  ret\n.second:\n  call .first\n  ret\n";
+    for _ in 0..4 {
+        let bytes = Assembler::new(source.to_owned()).assemble().code;
+        assert!(bytes[6..] == [0x18, 0, 0, 0, 13, 0xF0, 0xA0, 0x18, 0, 0, 0, 12, 0xA0]);
+    }
+}
+
+#[test]
+fn test_assemble_source_lines() {
+    let source = "@code\n._entry:\n  call .double\n  halt\n.double:\n  dup\n  add\n  ret\n";
+    let image = Assembler::new(source.to_owned()).assemble();
+    let lines: Vec<String> = image.debug.iter().map(|line| format!("{:02} {}:{}", line.address, line, line.column)).collect();
+    assert!(lines == vec!["06 <source>:3 in ._entry:3", "11 <source>:4 in ._entry:3", "12 <source>:6 in .double:3",
+                          "13 <source>:7 in .double:3", "14 <source>:8 in .double:3"]);
+}
+
+#[test]
+fn test_assemble_listing() {
+    let source = "@data\n.seven: 7\n@code\n._entry:\n  gload .seven ; load it\n  halt\n";
+    let mut assembler = Assembler::new(source.to_owned());
+    let object = assembler.assemble_object();
+    let listing = assembler.listing(&object);
+    let lines: Vec<&str> = listing.lines().collect();
+    assert!(lines[0] == "<source>");
+    assert!(lines[1] == " line  offset  bytes                   source");
+    assert!(lines[3] == "    2  D 0000  00 00 00 07             .seven: 7");
+    assert!(lines[6] == "    5  C 0000  12 00 00 00 00            gload .seven ; load it");
+    assert!(lines[7] == "    6  C 0005  F0                        halt");
+    assert!(lines[9] == "Symbols (segment offsets)");
+    assert!(lines[10..] == ["  C 0000  ._entry", "  D 0000  .seven"]);
+}
+
+#[test]
+#[should_panic(expected = "Global label .first is already defined.")]
+fn test_assemble_duplicate_global_label() {
+    let source = "@code\n._entry:\n  halt\n.first:\n  ret\n.first:\n  ret\n";
+    Assembler::new(source.to_owned()).assemble();
+}
+
+#[test]
+#[should_panic(expected = "UNKNOWN is not a defined symbol.")]
+fn test_assemble_undefined_symbol() {
+    let source = "@code\n._entry:\n  gload UNKNOWN + 3\n  halt\n";
+    Assembler::new(source.to_owned()).assemble();
+}
+
+#[test]
+fn test_assemble_defines() {
+    let source = "@define SLOT 200\n@equ NEXT SLOT + 1\n@code\n._entry:\n  store NEXT\n  load SLOT * 2\n  halt\n";
+    let bytes = Assembler::new(source.to_owned()).assemble().code;
+    assert!(bytes[6..11] == [0x14, 0, 0, 0, 201]);
+    assert!(bytes[11..16] == [0x11, 0, 0, 1, 144]);
+}
+
+#[test]
+fn test_assemble_defines_in_data() {
+    let source = "@define SIZE 4\n@data\n.value: SIZE * 2 7\n@code\n._entry:\n  halt\n";
+    let image = Assembler::new(source.to_owned()).assemble();
+    assert!(image.data == vec![8, 7]);
+}
+
+#[test]
+fn test_assemble_data_labels() {
+    let source = "@data\n.first: 1 2\n.second: 3\n@space\n.buffer: 4\n@code\n._entry:\n  gload .second\n  gstore .buffer\n  halt\n";
+    let image = Assembler::new(source.to_owned()).assemble();
+    assert!(image.data == vec![1, 2, 3]);
+    assert!(image.code[6..11] == [0x12, 0, 0, 0, 2]);
+    assert!(image.code[11..16] == [0x15, 0, 0, 0, 3]);
+}
+
+#[test]
+fn test_assemble_negative_data() {
+    let source = "@data\n.vals: 1 -2 3\n.diff: 1 - 2\n@code\n._entry:\n  halt\n";
+    let image = Assembler::new(source.to_owned()).assemble();
+    assert!(image.data == vec![1, -2i32 as u32, 3, -1i32 as u32]);
+}
+
+#[test]
+#[should_panic(expected = "<source>:2:7: 1099511627776 does not fit in a 4 byte data word.")]
+fn test_assemble_data_range() {
+    Assembler::new("@data\n.big: 1 << 40\n@code\n._entry:\n  halt\n".to_owned()).assemble();
+}
+
+#[test]
+#[should_panic(expected = "SLOT is already defined.")]
+fn test_assemble_duplicate_define() {
+    let source = "@define SLOT 1\n@define SLOT 2\n@code\n._entry:\n  halt\n";
+    Assembler::new(source.to_owned()).assemble();
+}
+
+#[test]
+fn test_assemble_macros() {
+    let source = "@macro countdown slot\n  'loop:\n  load slot\n  const 1\n  sub\n  dup\n  store slot\n  jmpnz 'loop\n@endmacro\n\
+                  @code\n._entry:\n  countdown 1\n  countdown 2\n  halt\n";
+    let bytes = Assembler::new(source.to_owned()).assemble().code;
+    assert!(bytes.len() == 6 + 2 * 22 + 1);
+    assert!(bytes[6..11] == [0x11, 0, 0, 0, 1]);
+    assert!(bytes[23..28] == [0x89, 0, 0, 0, 6]);
+    assert!(bytes[28..33] == [0x11, 0, 0, 0, 2]);
+    assert!(bytes[45..50] == [0x89, 0, 0, 0, 28]);
+}
+
+#[test]
+fn test_assemble_includes() {
+    use std::env;
+    use std::fs;
+    let dir = env::temp_dir().join(format!("slang-include-{}", ::std::process::id()));
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    fs::write(dir.join("lib/one.asm"), "@define ONE 1\n").unwrap();
+    fs::write(dir.join("lib/both.asm"), "@include \"one.asm\"\n@include \"one.asm\"\n").unwrap();
+    fs::write(dir.join("main.asm"), "@include \"lib/both.asm\"\n@code\n._entry:\n  const ONE\n  halt\n").unwrap();
+    let bytes = Assembler::from_file(dir.join("main.asm").to_str().unwrap()).assemble().code;
+    assert!(bytes[6..11] == [0x10, 0, 0, 0, 1]);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_assemble_include_cycle() {
+    use std::env;
+    use std::fs;
+    use std::panic;
+    let dir = env::temp_dir().join(format!("slang-cycle-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("a.asm"), "@include \"b.asm\"\n").unwrap();
+    fs::write(dir.join("b.asm"), "\n@include \"a.asm\"\n").unwrap();
+    let path = dir.join("a.asm").to_str().unwrap().to_owned();
+    let result = panic::catch_unwind(|| Assembler::from_file(&path).assemble());
+    fs::remove_dir_all(&dir).unwrap();
+    let err = result.unwrap_err();
+    let message = err.downcast_ref::<String>().unwrap();
+    assert!(message.contains("b.asm:2:1: Include cycle"));
+}
+
+#[test]
+fn test_assemble_space() {
+    let source = "@define LINE 16\n@space\n.buffer: LINE * 4\n.cursor: 1\n@code\n._entry:\n  gload .cursor\n  gstore .buffer + 3\n  halt\n";
+    let bytes = Assembler::new(source.to_owned()).assemble().code;
+    assert!(bytes.len() == 6 + 11);
+    assert!(bytes[6..11] == [0x12, 0, 0, 0, 64]);
+    assert!(bytes[11..16] == [0x15, 0, 0, 0, 3]);
 }
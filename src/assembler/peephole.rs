@@ -0,0 +1,225 @@
+use std::collections::{HashMap, HashSet};
+
+use super::expr::Expr;
+use super::lexer::{Token, Spanned, LabelType};
+
+
+/// One instruction with its operands, or a label between instructions.
+#[derive(Debug, Clone)]
+enum Item {
+    Instruction(Spanned, Vec<Spanned>),
+    Label(Spanned),
+}
+
+impl Item {
+    fn name(&self) -> Option<String> {
+        match *self {
+            Item::Instruction(Spanned { token: Token::Instruction(ref name), .. }, _) => Some(name.to_lowercase()),
+            _ => None,
+        }
+    }
+    fn operand(&self) -> Option<Expr> {
+        match *self {
+            Item::Instruction(_, ref operands) if operands.len() == 1 => operands[0].token.to_expr(),
+            _ => None,
+        }
+    }
+    fn constant(&self) -> Option<i64> {
+        match self.name() {
+            Some(ref name) if name == "const" => self.operand().and_then(|operand| operand.fold()),
+            _ => None,
+        }
+    }
+}
+
+/// Rewrite wasteful instruction sequences in the `@code` tokens. Rewrites
+/// never cross a label, and labels are kept, so every label still marks the
+/// same instruction once sections are measured.
+pub fn optimize(tokens: Vec<Spanned>) -> Vec<Spanned> {
+    let mut items = items(tokens);
+    while rewrite(&mut items) || thread_jumps(&mut items) {}
+    let mut tokens = Vec::new();
+    for item in items {
+        match item {
+            Item::Instruction(instruction, mut operands) => {
+                tokens.push(instruction);
+                tokens.append(&mut operands);
+            },
+            Item::Label(label) => tokens.push(label),
+        }
+    }
+    tokens
+}
+
+fn items(tokens: Vec<Spanned>) -> Vec<Item> {
+    let mut items = Vec::new();
+    for spanned in tokens {
+        match spanned.token {
+            Token::Instruction(_) => items.push(Item::Instruction(spanned, Vec::new())),
+            Token::Label(_, _) => items.push(Item::Label(spanned)),
+            Token::NewLine | Token::Comment(_) => {},
+            _ => match items.last_mut() {
+                Some(&mut Item::Instruction(_, ref mut operands)) => operands.push(spanned),
+                _ => panic!("{}: Operand without an instruction.", spanned.location),
+            },
+        }
+    }
+    items
+}
+
+fn instruction(like: &Spanned, name: &str, operand: Option<Token>) -> Item {
+    let location = like.location.clone();
+    let operands = operand.into_iter().map(|token| Spanned { token, location: location.clone() }).collect();
+    Item::Instruction(Spanned { token: Token::Instruction(name.to_owned()), location }, operands)
+}
+
+/// Names of the run of up to `count` instructions starting at `index`,
+/// stopping at the first label.
+fn names(items: &[Item], index: usize, count: usize) -> Vec<String> {
+    items[index..].iter().take(count).map(|item| item.name()).take_while(|name| name.is_some())
+                  .map(|name| name.unwrap()).collect()
+}
+
+/// Apply the first sequence rewrite found. Returns whether anything changed.
+fn rewrite(items: &mut Vec<Item>) -> bool {
+    for index in 0..items.len() {
+        let first = match items[index] {
+            Item::Instruction(ref spanned, _) => spanned.clone(),
+            Item::Label(_) => continue,
+        };
+        let names = names(items, index, 3);
+        let names: Vec<&str> = names.iter().map(|name| &**name).collect();
+        let replacement = if (names.starts_with(&["const", "add"]) && items[index].constant() == Some(0))
+                             || names.starts_with(&["dup", "drop"]) || names.starts_with(&["swap", "swap"]) {
+            Some((2, Vec::new()))
+        } else if names.starts_with(&["store", "load"]) && items[index].operand().is_some()
+                  && items[index].operand() == items[index + 1].operand() {
+            let slot = Token::from_expr(items[index].operand().unwrap());
+            Some((2, vec![instruction(&first, "dup", None), instruction(&first, "store", Some(slot))]))
+        } else if names == ["const", "const", "add"] {
+            match (items[index].constant(), items[index + 1].constant()) {
+                (Some(a), Some(b)) => {
+                    let sum = (a as u32).wrapping_add(b as u32) as i64;
+                    Some((3, vec![instruction(&first, "const", Some(Token::Constant(sum)))]))
+                },
+                _ => None,
+            }
+        } else {
+            None
+        };
+        if let Some((count, replacement)) = replacement {
+            items.splice(index..index + count, replacement);
+            return true
+        }
+    }
+    false
+}
+
+fn is_jump(name: &str) -> bool {
+    matches!(name, "jmp" | "jmp_rel" | "jmp_rel_eq" | "jmp_rel_ne" | "jmp_rel_gt" | "jmp_rel_lt" | "jmpnz")
+}
+
+/// Retarget jumps whose destination is itself an unconditional jump to a
+/// label. Returns whether anything changed.
+fn thread_jumps(items: &mut Vec<Item>) -> bool {
+    // Where each label's first instruction jumps to, and the global section
+    // the label is in. Local labels are keyed by their global section.
+    let mut forwards: HashMap<(String, String), (String, Token)> = HashMap::new();
+    let mut global = String::new();
+    for (index, item) in items.iter().enumerate() {
+        let (label_type, label) = match *item {
+            Item::Label(Spanned { token: Token::Label(ref label_type, ref label), .. }) => (label_type, label),
+            _ => continue,
+        };
+        if *label_type == LabelType::Global { global = label.clone(); }
+        let target = match items[index..].iter().find(|item| item.name().is_some()) {
+            Some(target) => target,
+            None => continue,
+        };
+        match (target.name(), target) {
+            (Some(ref name), Item::Instruction(_, operands)) if (name == "jmp" || name == "jmp_rel") && operands.len() == 1 => {
+                if let Token::Reference(_, _) = operands[0].token {
+                    forwards.insert(scoped(&global, label_type, label), (global.clone(), operands[0].token.clone()));
+                }
+            },
+            _ => {},
+        }
+    }
+
+    let mut changed = false;
+    let mut global = String::new();
+    for item in items.iter_mut() {
+        if let Item::Label(Spanned { token: Token::Label(LabelType::Global, ref label), .. }) = *item {
+            global = label.clone();
+        }
+        match item.name() {
+            Some(ref name) if is_jump(name) => {},
+            _ => continue,
+        }
+        let operands = match *item {
+            Item::Instruction(_, ref mut operands) if operands.len() == 1 => operands,
+            _ => continue,
+        };
+        let mut target = operands[0].token.clone();
+        let mut seen = HashSet::new();
+        while let Token::Reference(ref label_type, ref label) = target.clone() {
+            let key = scoped(&global, label_type, label);
+            if !seen.insert(key.clone()) {
+                // The jumps form a cycle; leave this one alone.
+                target = operands[0].token.clone();
+                break
+            }
+            match forwards.get(&key) {
+                // A local label can only be named from its own section.
+                Some(&(ref section, Token::Reference(LabelType::Local, _))) if *section != global => break,
+                Some((_, next)) => target = next.clone(),
+                None => break,
+            }
+        }
+        if operands[0].token.to_expr() != target.to_expr() {
+            operands[0].token = target;
+            changed = true;
+        }
+    }
+    changed
+}
+
+fn scoped(global: &str, label_type: &LabelType, label: &str) -> (String, String) {
+    match *label_type {
+        LabelType::Global => (String::new(), label.to_owned()),
+        LabelType::Local => (global.to_owned(), label.to_owned()),
+    }
+}
+
+
+#[cfg(test)]
+fn assemble(source: &str) -> Vec<String> {
+    use super::lexer::Lexer;
+    let source = source.to_owned();
+    optimize(Lexer::new(&source).lex_spanned().into_iter().filter(|spanned| {
+        !matches!(spanned.token, Token::Eof)
+    }).collect()).into_iter().map(|spanned| match spanned.token {
+        Token::Instruction(name) => name,
+        Token::Constant(value) => format!("{}", value),
+        Token::Label(_, label) => format!("{}:", label),
+        Token::Reference(_, label) => label,
+        other => format!("{:?}", other),
+    }).collect()
+}
+
+#[test]
+fn test_optimize_sequences() {
+    assert!(assemble("const 0\nadd\ndup\ndrop\nswap\nswap\nhalt\n") == vec!["halt"]);
+    assert!(assemble("store 3\nload 3\nstore 3\nload 4\n") == vec!["dup", "store", "3", "store", "3", "load", "4"]);
+    assert!(assemble("const 2\nconst 3\nadd\nconst 4\nadd\n") == vec!["const", "9"]);
+    assert!(assemble("const 0\n'here:\nadd\n") == vec!["const", "0", "'here:", "add"]);
+}
+
+#[test]
+fn test_optimize_threads_jumps() {
+    let source = "._entry:\njmp_rel_eq 'a\n'a:\njmp 'b\n'b:\njmp .other\n'c:\njmp 'c\n.other:\njmp 'x\n'x:\nhalt\n";
+    assert!(assemble(source) == vec![
+        "._entry:", "jmp_rel_eq", ".other", "'a:", "jmp", ".other", "'b:", "jmp", ".other",
+        "'c:", "jmp", "'c", ".other:", "jmp", "'x", "'x:", "halt",
+    ]);
+}
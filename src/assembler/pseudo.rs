@@ -0,0 +1,117 @@
+use super::lexer::{Token, Spanned};
+
+
+/// Rewrite convenience mnemonics into the real instructions they stand for.
+/// Expansion happens before sections are measured, so local label offsets
+/// account for the full size of every expansion. Like `jmp` and `jmpnz`,
+/// `jmpz` takes an absolute address.
+pub fn expand_pseudo_instructions(tokens: Vec<Spanned>) -> Vec<Spanned> {
+    let mut expanded = Vec::new();
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(spanned) = tokens.next() {
+        let name = match spanned.token {
+            Token::Instruction(ref name) if is_pseudo_instruction(name) => name.to_lowercase(),
+            _ => { expanded.push(spanned); continue },
+        };
+        let mut operands = Vec::new();
+        while let Some(operand) = tokens.peek().and_then(|next| next.token.to_expr()) {
+            operands.push(operand);
+            tokens.next();
+        }
+        let arity = match &*name {
+            "push" if operands.is_empty() => panic!("{}: push needs at least one operand.", spanned.location),
+            "push" => operands.len(),
+            "jmpz" | "ret_value" => 1,
+            _ => 0,
+        };
+        if operands.len() != arity {
+            panic!("{}: {} takes {} operands but was given {}.", spanned.location, name, arity, operands.len())
+        }
+        let mut emit = |token: Token| expanded.push(Spanned { token, location: spanned.location.clone() });
+        let instruction = |name: &str| Token::Instruction(name.to_owned());
+        match &*name {
+            "inc" => {
+                emit(instruction("const"));
+                emit(Token::Constant(1));
+                emit(instruction("add"));
+            },
+            "dec" => {
+                emit(instruction("const"));
+                emit(Token::Constant(-1));
+                emit(instruction("add"));
+            },
+            "neg" => {
+                emit(instruction("not"));
+                emit(instruction("const"));
+                emit(Token::Constant(1));
+                emit(instruction("add"));
+            },
+            "jmpz" => {
+                emit(instruction("const"));
+                emit(Token::Constant(0));
+                emit(instruction("cmp_eq"));
+                emit(instruction("jmpnz"));
+                emit(Token::from_expr(operands.remove(0)));
+            },
+            "push" => {
+                for operand in operands {
+                    emit(instruction("const"));
+                    emit(Token::from_expr(operand));
+                }
+            },
+            "ret_value" => {
+                emit(instruction("const"));
+                emit(Token::from_expr(operands.remove(0)));
+                emit(instruction("ret"));
+            },
+            "swap_drop" => {
+                emit(instruction("swap"));
+                emit(instruction("drop"));
+            },
+            _ => unreachable!(),
+        }
+    }
+    expanded
+}
+
+fn is_pseudo_instruction(name: &str) -> bool {
+    matches!(&*name.to_lowercase(), "inc" | "dec" | "neg" | "jmpz" | "push" | "ret_value" | "swap_drop")
+}
+
+
+#[cfg(test)]
+fn names(tokens: Vec<Spanned>) -> Vec<String> {
+    tokens.into_iter().map(|spanned| match spanned.token {
+        Token::Instruction(name) => name,
+        Token::Constant(value) => format!("{}", value),
+        Token::Reference(_, label) => label,
+        other => format!("{:?}", other),
+    }).collect()
+}
+
+#[test]
+fn test_expand_pseudo_instructions() {
+    use super::lexer::Lexer;
+    let source = "dec\nneg\npush 1 2 3\npush 5 -1\njmpz 'done\nret_value 4\nswap_drop\nhalt\n".to_owned();
+    let tokens: Vec<Spanned> = Lexer::new(&source).lex_spanned().into_iter()
+        .filter(|spanned| !matches!(spanned.token, Token::NewLine | Token::Eof))
+        .collect();
+    assert!(names(expand_pseudo_instructions(tokens)) == vec![
+        "const", "-1", "add",
+        "not", "const", "1", "add",
+        "const", "1", "const", "2", "const", "3",
+        "const", "5", "const", "-1",
+        "const", "0", "cmp_eq", "jmpnz", "'done",
+        "const", "4", "ret",
+        "swap", "drop",
+        "halt",
+    ]);
+}
+
+#[test]
+#[should_panic(expected = "jmpz takes 1 operands but was given 0.")]
+fn test_expand_pseudo_instruction_arity() {
+    use super::lexer::Lexer;
+    let source = "jmpz\n".to_owned();
+    expand_pseudo_instructions(Lexer::new(&source).lex_spanned());
+}
@@ -0,0 +1,172 @@
+use super::lex::Position;
+use super::types::Type;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Associativity {
+    Left,
+    Right,
+}
+
+impl UnaryOp {
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        match symbol {
+            "-" => Some(UnaryOp::Neg),
+            "!" => Some(UnaryOp::Not),
+            _ => None,
+        }
+    }
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            UnaryOp::Neg => "-",
+            UnaryOp::Not => "!",
+        }
+    }
+    /// Unary operators bind tighter than every binary operator but `^`, so
+    /// `-x * y` is `(-x) * y` and `-x ^ 2` is `-(x ^ 2)`.
+    pub fn precedence(&self) -> u8 {
+        7
+    }
+}
+
+impl BinaryOp {
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        match symbol {
+            "+"  => Some(BinaryOp::Add),
+            "-"  => Some(BinaryOp::Sub),
+            "*"  => Some(BinaryOp::Mul),
+            "/"  => Some(BinaryOp::Div),
+            "%"  => Some(BinaryOp::Mod),
+            "^"  => Some(BinaryOp::Pow),
+            "==" => Some(BinaryOp::Eq),
+            "!=" => Some(BinaryOp::Ne),
+            "<"  => Some(BinaryOp::Lt),
+            ">"  => Some(BinaryOp::Gt),
+            "<=" => Some(BinaryOp::Le),
+            ">=" => Some(BinaryOp::Ge),
+            "&&" => Some(BinaryOp::And),
+            "||" => Some(BinaryOp::Or),
+            _ => None,
+        }
+    }
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::Pow => "^",
+            BinaryOp::Eq  => "==",
+            BinaryOp::Ne  => "!=",
+            BinaryOp::Lt  => "<",
+            BinaryOp::Gt  => ">",
+            BinaryOp::Le  => "<=",
+            BinaryOp::Ge  => ">=",
+            BinaryOp::And => "&&",
+            BinaryOp::Or  => "||",
+        }
+    }
+    pub fn precedence(&self) -> u8 {
+        match *self {
+            BinaryOp::Or                                          => 1,
+            BinaryOp::And                                         => 2,
+            BinaryOp::Eq | BinaryOp::Ne                           => 3,
+            BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge => 4,
+            BinaryOp::Add | BinaryOp::Sub                         => 5,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod         => 6,
+            BinaryOp::Pow                                         => 8,
+        }
+    }
+    pub fn associativity(&self) -> Associativity {
+        match *self {
+            BinaryOp::Pow => Associativity::Right,
+            _ => Associativity::Left,
+        }
+    }
+}
+
+/// An expression and where it starts in the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub position: Position,
+    /// The type the checker found, which code generation reads.
+    pub ty: Option<Type>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Variable(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+/// A statement and where it starts in the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub position: Position,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    /// A declaration, with the type it is annotated with if any.
+    Let(String, Option<Type>, Expr),
+    Assign(String, Expr),
+    Print(Expr),
+    Expr(Expr),
+    Block(Vec<Stmt>),
+    /// `else if` is an `else` block holding just another `If`.
+    If(Expr, Vec<Stmt>, Option<Vec<Stmt>>),
+    While(Expr, Vec<Stmt>),
+    Break,
+    Continue,
+    Fn(Function),
+    Return(Option<Expr>),
+}
+
+/// A function definition. Unannotated parameters and results are `int`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub parameters: Vec<(String, Option<Type>)>,
+    pub returns: Option<Type>,
+    pub body: Vec<Stmt>,
+}
+
+impl Function {
+    /// The function's type, with unannotated parameters and results `int`.
+    pub fn signature(&self) -> Type {
+        let parameters = self.parameters.iter().map(|(_, ty)| ty.clone().unwrap_or(Type::Int)).collect();
+        Type::Fn(parameters, Box::new(self.returns.clone().unwrap_or(Type::Int)))
+    }
+}
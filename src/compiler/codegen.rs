@@ -0,0 +1,643 @@
+use assembler::builder::ProgramBuilder;
+use assembler::format;
+use image::Image;
+use opcode::Opcode;
+
+use super::ast::{Expr, ExprKind, Stmt, StmtKind, Function, UnaryOp, BinaryOp};
+use super::types::{self, Type};
+
+
+/// One generated instruction. Code is kept symbolic so the same program can
+/// be built into bytecode or written out as SlangASM.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Plain(Opcode),
+    Value(Opcode, i64),
+    /// Pushes the bits of a float.
+    Float(f32),
+    /// An instruction addressing the global memory word under a label.
+    Global(Opcode, String),
+    /// A jump to a code label.
+    Jump(Opcode, String),
+    /// Marks the next instruction as the target of jumps to the label.
+    Label(String),
+    /// Starts the global section of a function.
+    Section(String),
+    Call(String),
+    /// Marks where the code for a source line starts. Only SlangASM shows it.
+    Line(usize),
+}
+
+/// Where a variable lives.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Variable {
+    Local(u32),
+    Global(String),
+}
+
+/// Generates stack machine code for the Slang language. Variables declared
+/// at the top level are globals in `@space`; those declared in blocks are
+/// locals of the current frame, whose slots are reused once the block ends.
+/// Functions are compiled after the top level code, each into its own global
+/// section with a frame of its own, and take their arguments from the stack.
+/// Globals are labelled `.var_<name>` and functions `.fn_<name>`, so neither
+/// can clash with the other or with `._entry`.
+/// Programs must have passed the type checker: scopes, calls and loops are
+/// not checked again, and the types it recorded in the expressions only pick
+/// between integer and float instructions here.
+#[derive(Clone)]
+pub struct CodeGen {
+    ops: Vec<Op>,
+    /// Where the functions of the current program start in `ops`.
+    entry: usize,
+    /// Functions compiled by earlier programs.
+    library: Vec<Op>,
+    /// The source line of the last statement compiled.
+    line: usize,
+    scopes: Vec<Vec<(String, Variable)>>,
+    next_slot: u32,
+    globals: Vec<String>,
+    labels: usize,
+    /// The `continue` and `break` targets of the loops being compiled.
+    loops: Vec<(String, String)>,
+}
+
+impl CodeGen {
+    pub fn new() -> Self {
+        CodeGen {
+            ops: Vec::new(),
+            entry: 0,
+            library: Vec::new(),
+            line: 0,
+            scopes: vec![Vec::new()],
+            next_slot: 0,
+            globals: Vec::new(),
+            labels: 0,
+            loops: Vec::new(),
+        }
+    }
+    pub fn emit(&mut self, op: Op) -> &mut Self {
+        self.ops.push(op);
+        self
+    }
+    /// A fresh code label. Jumps may be emitted to it before it is placed;
+    /// they are patched with its address when the program is built.
+    fn label(&mut self, name: &str) -> String {
+        self.labels += 1;
+        format!("{}_{}", name, self.labels - 1)
+    }
+    /// Emit code that jumps to `label` when the top of the stack is 0.
+    fn jump_unless(&mut self, label: &str) {
+        self.truth(Opcode::CmpEq);
+        self.emit(Op::Jump(Opcode::JmpNZ, label.to_owned()));
+    }
+    pub fn begin_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+    pub fn end_scope(&mut self) {
+        let scope = self.scopes.pop().expect("The global scope cannot end.");
+        let locals = scope.iter().filter(|&(_, variable)| matches!(*variable, Variable::Local(_))).count();
+        self.next_slot -= locals as u32;
+    }
+    /// Declare `name` in the innermost scope.
+    pub fn declare(&mut self, name: &str) -> Variable {
+        let variable = if self.scopes.len() == 1 {
+            let label = format!("var_{}", name);
+            self.globals.push(label.clone());
+            Variable::Global(label)
+        } else {
+            self.next_slot += 1;
+            Variable::Local(self.next_slot - 1)
+        };
+        self.scopes.last_mut().unwrap().push((name.to_owned(), variable.clone()));
+        variable
+    }
+    /// The innermost declaration of `name` in scope.
+    pub fn lookup(&self, name: &str) -> Option<Variable> {
+        self.scopes.iter().rev()
+            .filter_map(|scope| scope.iter().rev().find(|&(declared, _)| declared == name))
+            .map(|(_, variable)| variable.clone())
+            .next()
+    }
+    pub fn load(&mut self, variable: Variable) {
+        match variable {
+            Variable::Local(slot) => self.emit(Op::Value(Opcode::Load, slot as i64)),
+            Variable::Global(label) => self.emit(Op::Global(Opcode::GLoad, label)),
+        };
+    }
+    pub fn store(&mut self, variable: Variable) {
+        match variable {
+            Variable::Local(slot) => self.emit(Op::Value(Opcode::Store, slot as i64)),
+            Variable::Global(label) => self.emit(Op::Global(Opcode::GStore, label)),
+        };
+    }
+    /// The variable `name` refers to, which the checker made sure is in scope.
+    fn variable(&self, name: &str) -> Variable {
+        self.lookup(name).expect("Variables are declared before they are used.")
+    }
+    /// Emit code for the top level statements of a program, then `halt`,
+    /// then its functions.
+    pub fn program(&mut self, statements: &[Stmt]) {
+        self.begin_program();
+        for statement in statements {
+            self.statement(statement);
+        }
+        self.end_program(statements)
+    }
+    /// Start the top level code of a program. The code of an earlier program
+    /// is dropped, but its globals and functions are kept, so each input of a
+    /// REPL can be a program of its own.
+    pub fn begin_program(&mut self) {
+        let functions = self.ops.split_off(self.entry);
+        self.library.extend(functions);
+        self.ops.clear();
+        self.entry = 0;
+    }
+    /// End the top level code with `halt`, then compile the functions
+    /// defined among `statements`.
+    pub fn end_program(&mut self, statements: &[Stmt]) {
+        self.emit(Op::Plain(Opcode::Halt));
+        self.entry = self.ops.len();
+        for statement in statements {
+            if let StmtKind::Fn(ref function) = statement.kind {
+                self.mark_line(statement.position.line);
+                self.function(function);
+            }
+        }
+    }
+    /// A function gets a fresh frame whose first locals are its parameters,
+    /// stored from the stack in reverse. Falling off the end returns 0.
+    fn function(&mut self, function: &Function) {
+        self.emit(Op::Section(format!("fn_{}", function.name)));
+        let slots = self.next_slot;
+        self.next_slot = 0;
+        self.begin_scope();
+        let variables: Vec<Variable> = function.parameters.iter().map(|(name, _)| self.declare(name)).collect();
+        for variable in variables.into_iter().rev() {
+            self.store(variable);
+        }
+        for statement in &function.body {
+            self.statement(statement);
+        }
+        self.emit(Op::Value(Opcode::Const, 0)).emit(Op::Plain(Opcode::Ret));
+        self.end_scope();
+        self.next_slot = slots;
+    }
+    fn mark_line(&mut self, line: usize) {
+        if line != self.line {
+            self.emit(Op::Line(line));
+            self.line = line;
+        }
+    }
+    /// Emit code for a statement, which leaves the operand stack as it was.
+    pub fn statement(&mut self, statement: &Stmt) {
+        match statement.kind {
+            // Definitions are compiled later, and mark their own line then.
+            StmtKind::Fn(_) => {},
+            _ => self.mark_line(statement.position.line),
+        }
+        match statement.kind {
+            StmtKind::Let(ref name, _, ref value) => {
+                // The value is compiled first, so it sees any outer `name`.
+                self.expression(value);
+                let variable = self.declare(name);
+                self.store(variable);
+            },
+            StmtKind::Assign(ref name, ref value) => {
+                let variable = self.variable(name);
+                self.expression(value);
+                self.store(variable);
+            },
+            StmtKind::Print(ref value) => {
+                self.expression(value);
+                let print = match *checked(value) {
+                    Type::Float => Opcode::FPrint,
+                    _ => Opcode::Print,
+                };
+                self.emit(Op::Plain(print)).emit(Op::Plain(Opcode::Drop));
+            },
+            StmtKind::Expr(ref value) => {
+                self.expression(value);
+                self.emit(Op::Plain(Opcode::Drop));
+            },
+            StmtKind::Block(ref body) => self.block(body),
+            StmtKind::If(ref condition, ref then, ref otherwise) => {
+                let (otherwise_label, end) = (self.label("else"), self.label("end_if"));
+                self.expression(condition);
+                self.jump_unless(&otherwise_label);
+                self.block(then);
+                if let Some(ref otherwise) = *otherwise {
+                    self.emit(Op::Jump(Opcode::Jmp, end.clone()));
+                    self.emit(Op::Label(otherwise_label));
+                    self.block(otherwise);
+                } else {
+                    self.emit(Op::Label(otherwise_label));
+                }
+                self.emit(Op::Label(end));
+            },
+            StmtKind::While(ref condition, ref body) => {
+                let (start, end) = (self.label("while"), self.label("end_while"));
+                self.emit(Op::Label(start.clone()));
+                self.expression(condition);
+                self.jump_unless(&end);
+                self.loops.push((start.clone(), end.clone()));
+                self.block(body);
+                self.loops.pop();
+                self.emit(Op::Jump(Opcode::Jmp, start));
+                self.emit(Op::Label(end));
+            },
+            StmtKind::Break | StmtKind::Continue => {
+                let (start, end) = self.loops.last().cloned().expect("break and continue are inside loops.");
+                let target = if statement.kind == StmtKind::Break { end } else { start };
+                self.emit(Op::Jump(Opcode::Jmp, target));
+            },
+            // Definitions are compiled after the code around them.
+            StmtKind::Fn(_) => {},
+            StmtKind::Return(ref value) => {
+                match *value {
+                    Some(ref value) => self.expression(value),
+                    None => { self.emit(Op::Value(Opcode::Const, 0)); },
+                }
+                self.emit(Op::Plain(Opcode::Ret));
+            },
+        }
+    }
+    /// Emit code that leaves the value of `expr` on the operand stack.
+    /// Comparisons and logical operators leave 1 for true and 0 for false.
+    pub fn expression(&mut self, expr: &Expr) {
+        match expr.kind {
+            ExprKind::Int(value) => { self.emit(Op::Value(Opcode::Const, value)); },
+            ExprKind::Float(value) => { self.emit(Op::Float(value as f32)); },
+            ExprKind::Bool(value) => { self.emit(Op::Value(Opcode::Const, value as i64)); },
+            ExprKind::Variable(ref name) => {
+                let variable = self.variable(name);
+                self.load(variable);
+            },
+            ExprKind::Unary(op, ref operand) => {
+                // 0 is the bits of 0.0 too, so negation is a subtraction from
+                // it either way.
+                if op == UnaryOp::Neg { self.emit(Op::Value(Opcode::Const, 0)); }
+                self.expression(operand);
+                match (op, *checked(expr) == Type::Float) {
+                    (UnaryOp::Neg, false) => { self.emit(Op::Plain(Opcode::Sub)); },
+                    (UnaryOp::Neg, true) => { self.emit(Op::Plain(Opcode::FSub)); },
+                    (UnaryOp::Not, _) => self.truth(Opcode::CmpEq),
+                }
+            },
+            // The right hand side of `&&` and `||` only runs when the left
+            // does not decide the result, which is left on the stack for it.
+            ExprKind::Binary(op, ref lhs, ref rhs) if op == BinaryOp::And || op == BinaryOp::Or => {
+                let end = self.label("end_logic");
+                self.expression(lhs);
+                self.truth(Opcode::CmpNe);
+                self.emit(Op::Plain(Opcode::Dup));
+                if op == BinaryOp::And {
+                    self.jump_unless(&end);
+                } else {
+                    self.emit(Op::Jump(Opcode::JmpNZ, end.clone()));
+                }
+                self.emit(Op::Plain(Opcode::Drop));
+                self.expression(rhs);
+                self.truth(Opcode::CmpNe);
+                self.emit(Op::Label(end));
+            },
+            ExprKind::Binary(op, ref lhs, ref rhs) => {
+                self.expression(lhs);
+                self.expression(rhs);
+                // Comparisons and `mod`/`pow` are defined in terms of the top
+                // of the stack, which holds the right hand side.
+                let float = *checked(lhs) == Type::Float;
+                let pick = |int, float_op| if float { float_op } else { int };
+                match op {
+                    BinaryOp::Add => { self.emit(Op::Plain(pick(Opcode::Add, Opcode::FAdd))); },
+                    BinaryOp::Sub => { self.emit(Op::Plain(pick(Opcode::Sub, Opcode::FSub))); },
+                    BinaryOp::Mul => { self.emit(Op::Plain(pick(Opcode::Mul, Opcode::FMul))); },
+                    BinaryOp::Div => { self.emit(Op::Plain(pick(Opcode::Div, Opcode::FDiv))); },
+                    BinaryOp::Mod => { self.emit(Op::Plain(Opcode::Swap)).emit(Op::Plain(pick(Opcode::Mod, Opcode::FMod))); },
+                    BinaryOp::Pow => { self.emit(Op::Plain(Opcode::Swap)).emit(Op::Plain(pick(Opcode::Pow, Opcode::FPow))); },
+                    BinaryOp::Eq => { self.emit(Op::Plain(pick(Opcode::CmpEq, Opcode::FCmpEq))); },
+                    BinaryOp::Ne => { self.emit(Op::Plain(pick(Opcode::CmpNe, Opcode::FCmpNe))); },
+                    BinaryOp::Lt => { self.emit(Op::Plain(pick(Opcode::CmpGt, Opcode::FCmpGt))); },
+                    BinaryOp::Gt => { self.emit(Op::Plain(pick(Opcode::CmpLt, Opcode::FCmpLt))); },
+                    BinaryOp::Le => { self.emit(Op::Plain(pick(Opcode::CmpLt, Opcode::FCmpLt))); self.truth(Opcode::CmpEq); },
+                    BinaryOp::Ge => { self.emit(Op::Plain(pick(Opcode::CmpGt, Opcode::FCmpGt))); self.truth(Opcode::CmpEq); },
+                    BinaryOp::And | BinaryOp::Or => unreachable!(),
+                }
+            },
+            // Builtins cannot be redefined, so a call to one is never a call
+            // to a function of the program.
+            ExprKind::Call(ref name, ref arguments) if types::BUILTINS.contains(&&**name) => {
+                self.expression(&arguments[0]);
+                match (checked(&arguments[0]), checked(expr)) {
+                    (&Type::Int, &Type::Float) => { self.emit(Op::Plain(Opcode::IToF)); },
+                    (&Type::Float, &Type::Int) => { self.emit(Op::Plain(Opcode::FToI)); },
+                    _ => {},
+                }
+            },
+            ExprKind::Call(ref name, ref arguments) => {
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                self.emit(Op::Call(format!("fn_{}", name)));
+            },
+        }
+    }
+    fn block(&mut self, body: &[Stmt]) {
+        self.begin_scope();
+        for statement in body {
+            self.statement(statement);
+        }
+        self.end_scope();
+    }
+    /// Compare the top of the stack with 0: `CmpNe` makes any true value 1
+    /// and `CmpEq` negates it.
+    fn truth(&mut self, compare: Opcode) {
+        self.emit(Op::Value(Opcode::Const, 0)).emit(Op::Plain(compare));
+    }
+    /// The generated code as bytecode, entered at `._entry`.
+    pub fn build(&self) -> Result<Image, String> {
+        let mut builder = ProgramBuilder::new("<compiler>");
+        for global in &self.globals {
+            builder.space(global, 1);
+        }
+        builder.label("_entry");
+        let mut section = "._entry".to_owned();
+        for op in self.ops.iter().chain(&self.library) {
+            match *op {
+                Op::Plain(opcode) => builder.op(opcode),
+                Op::Value(opcode, value) => builder.op_value(opcode, value),
+                Op::Float(value) => builder.const_(value.to_bits() as i64),
+                Op::Global(Opcode::GLoad, ref label) => builder.gload(&**label),
+                Op::Global(Opcode::GStore, ref label) => builder.gstore(&**label),
+                Op::Global(opcode, _) => panic!("{:?} does not address global memory.", opcode),
+                // Code labels are local to their section in SlangASM, and
+                // scoped the same way here.
+                Op::Jump(Opcode::Jmp, ref label) => builder.jmp(&local(&section, label)),
+                Op::Jump(Opcode::JmpNZ, ref label) => builder.jmpnz(&local(&section, label)),
+                Op::Jump(opcode, _) => panic!("{:?} is not an absolute jump.", opcode),
+                Op::Label(ref label) => builder.label(&local(&section, label)),
+                Op::Section(ref name) => {
+                    section = format!(".{}", name);
+                    builder.label(name)
+                },
+                Op::Call(ref name) => builder.call(name),
+                Op::Line(_) => continue,
+            };
+        }
+        builder.build()
+    }
+    /// The generated code as SlangASM source the assembler accepts.
+    pub fn asm(&self) -> String {
+        self.write_asm(None)
+    }
+    /// The generated code as SlangASM in the layout of `slang fmt`, with a
+    /// comment giving the file, line and text of the source before the code
+    /// compiled from it.
+    pub fn annotated_asm(&self, file: &str, source: &str) -> String {
+        format::format(&self.write_asm(Some((file, source))), file)
+    }
+    fn write_asm(&self, source: Option<(&str, &str)>) -> String {
+        let lines: Vec<&str> = source.map_or(Vec::new(), |(_, text)| text.lines().collect());
+        let mut asm = String::new();
+        if !self.globals.is_empty() {
+            asm.push_str("@space\n");
+            for global in &self.globals {
+                asm.push_str(&format!(".{}: 1\n", global));
+            }
+        }
+        asm.push_str("@code\n._entry:\n");
+        let mut ops = self.ops.iter().chain(&self.library).peekable();
+        while let Some(op) = ops.next() {
+            match *op {
+                Op::Plain(opcode) => asm.push_str(&format!("  {}\n", opcode.info().mnemonic)),
+                Op::Float(value) => asm.push_str(&format!("  const {} ; {:?}\n", value.to_bits(), value)),
+                Op::Value(opcode, value) => asm.push_str(&format!("  {} {}\n", opcode.info().mnemonic, value)),
+                Op::Global(opcode, ref label) => asm.push_str(&format!("  {} .{}\n", opcode.info().mnemonic, label)),
+                Op::Jump(opcode, ref label) => asm.push_str(&format!("  {} '{}\n", opcode.info().mnemonic, label)),
+                Op::Label(ref label) => asm.push_str(&format!("  '{}:\n", label)),
+                Op::Section(ref name) => asm.push_str(&format!(".{}:\n", name)),
+                Op::Call(ref name) => asm.push_str(&format!("  call .{}\n", name)),
+                Op::Line(line) => if let Some((file, _)) = source {
+                    // A function starts after a blank line, with its comment
+                    // flush left like its label.
+                    let indent = match ops.peek() {
+                        Some(&&Op::Section(_)) => { asm.push('\n'); "" },
+                        _ => "  ",
+                    };
+                    let text = lines.get(line - 1).map_or("", |text| text.trim());
+                    asm.push_str(&format!("{}; {}:{}: {}\n", indent, file, line, text));
+                },
+            }
+        }
+        asm
+    }
+}
+
+fn local(section: &str, label: &str) -> String {
+    format!("{}'{}", section, label)
+}
+
+/// The type the checker recorded for `expr`.
+fn checked(expr: &Expr) -> &Type {
+    expr.ty.as_ref().expect("Expressions are type checked before code generation.")
+}
+
+
+#[cfg(test)]
+fn compile(source: &str) -> Result<CodeGen, String> {
+    use super::parser::parse_expression;
+    use super::types::Checker;
+    let mut expr = parse_expression(source)?;
+    let mut checker = Checker::new();
+    checker.begin_scope();
+    checker.declare("x", Type::Int)?;
+    checker.expression(&mut expr)?;
+    let mut codegen = CodeGen::new();
+    codegen.begin_scope();
+    codegen.declare("x");
+    codegen.expression(&expr);
+    Ok(codegen)
+}
+
+#[test]
+fn test_codegen_expression() {
+    let codegen = compile("(1 + 2) * x").unwrap();
+    assert!(codegen.ops == vec![
+        Op::Value(Opcode::Const, 1), Op::Value(Opcode::Const, 2), Op::Plain(Opcode::Add),
+        Op::Value(Opcode::Load, 0), Op::Plain(Opcode::Mul),
+    ]);
+    assert!(codegen.asm() == "@code\n._entry:\n  const 1\n  const 2\n  add\n  load 0\n  mul\n");
+}
+
+#[test]
+fn test_codegen_matches_assembler() {
+    use assembler::Assembler;
+    let mut codegen = compile("x / 4 - 1 <= x % 3").unwrap();
+    codegen.emit(Op::Plain(Opcode::Halt));
+    assert!(codegen.build().unwrap().code == Assembler::new(codegen.asm()).assemble().code);
+}
+
+#[test]
+fn test_codegen_errors() {
+    assert!(compile("y + 1").err().unwrap() == "1:1: y is not declared.");
+    assert!(compile("2 * 3000000000").err().unwrap() == "1:5: 3000000000 is not a 32 bit integer.");
+    assert!(compile("2 * 1.5").err().unwrap() == "1:1: Cannot apply * to int and float.");
+    assert!(compile("f(x)").err().unwrap() == "1:1: f is not a function.");
+}
+
+#[test]
+fn test_codegen_scopes() {
+    let compile = |source| super::compile(source, false);
+    let codegen = compile("let x = 1;\n{ let y = x; { let x = y; x = 2; } print x + y; }\n{ let z = 3; }").unwrap();
+    assert!(codegen.ops == vec![
+        Op::Line(1), Op::Value(Opcode::Const, 1), Op::Global(Opcode::GStore, "var_x".to_owned()),
+        Op::Line(2), Op::Global(Opcode::GLoad, "var_x".to_owned()), Op::Value(Opcode::Store, 0),
+        Op::Value(Opcode::Load, 0), Op::Value(Opcode::Store, 1),
+        Op::Value(Opcode::Const, 2), Op::Value(Opcode::Store, 1),
+        Op::Global(Opcode::GLoad, "var_x".to_owned()), Op::Value(Opcode::Load, 0), Op::Plain(Opcode::Add),
+        Op::Plain(Opcode::Print), Op::Plain(Opcode::Drop),
+        Op::Line(3), Op::Value(Opcode::Const, 3), Op::Value(Opcode::Store, 0),
+        Op::Plain(Opcode::Halt),
+    ]);
+    assert!(codegen.asm().starts_with("@space\n.var_x: 1\n@code\n._entry:\n  const 1\n  gstore .var_x\n"));
+    assert!(compile("{ let y = 1; }\nprint y;").err().unwrap() == "2:7: y is not declared.");
+    assert!(compile("let x = 1;\nlet x = 2;").err().unwrap() == "2:1: x is already declared in this scope.");
+    assert!(compile("z = 1;").err().unwrap() == "1:1: z is not declared.");
+}
+
+/// Run the statements of `source`, then leave the value of `result` on the
+/// stack and return it.
+#[cfg(test)]
+fn run(source: &str) -> Result<i32, String> {
+    use super::parser::parse_program;
+    use super::types::Checker;
+    use vm::VirtualMachine;
+    let mut codegen = CodeGen::new();
+    let mut statements = parse_program(source)?;
+    Checker::new().program(&mut statements)?;
+    codegen.begin_program();
+    for statement in &statements {
+        codegen.statement(statement);
+    }
+    let result = codegen.lookup("result").ok_or("result is not declared.")?;
+    codegen.load(result);
+    codegen.end_program(&statements);
+    let mut vm = VirtualMachine::from_image(codegen.build()?)?;
+    vm.run()?;
+    Ok(vm.stack().top().unwrap() as i32)
+}
+
+#[test]
+fn test_codegen_control_flow() {
+    let sum = "let result = 0;\nlet i = 10;\nwhile i > 0 { result = result + i; i = i - 1; }";
+    assert!(run(sum) == Ok(55));
+    let classify = |n| run(&format!("let n = {};\nlet result = 0;\n\
+                                     if n < 0 {{ result = -1; }} else if n == 0 {{ result = 100; }} else {{ result = n * 2; }}", n));
+    assert!(classify(-5) == Ok(-1) && classify(0) == Ok(100) && classify(4) == Ok(8));
+    let loops = "let result = 0;\nlet i = 0;\nwhile true {\n  i = i + 1;\n  if i > 10 { break; }\n  if i % 2 == 0 { continue; }\n  result = result + i;\n}";
+    assert!(run(loops) == Ok(25));
+    assert!(run("let result = 0;\nif true { break; }") == Err("2:11: break is outside of a loop.".to_owned()));
+}
+
+#[test]
+fn test_codegen_short_circuit() {
+    let guarded = |x| run(&format!("let x = {};\nlet result = 0;\n\
+                                    if x != 0 && 10 / x > 1 {{ result = 1; }}\nif x == 0 || 10 / x < 3 {{ result = result + 2; }}", x));
+    assert!(guarded(0) == Ok(2) && guarded(2) == Ok(1) && guarded(5) == Ok(3));
+    assert!(run("let result = 0;\nlet t = result == 0 || result / result == 1;\nif t && !false { result = 7; }") == Ok(7));
+}
+
+#[test]
+fn test_codegen_control_flow_asm() {
+    use assembler::Assembler;
+    let codegen = super::compile("let i = 3;\nwhile i != 0 { if i == 2 { i = 0; } else { i = i - 1; } }", false).unwrap();
+    let asm = codegen.asm();
+    assert!(asm.contains("  'while_0:\n  gload .var_i\n  const 0\n  cmp_ne\n  const 0\n  cmp_eq\n  jmpnz 'end_while_1\n"));
+    assert!(Assembler::new(asm).assemble().code == codegen.build().unwrap().code);
+}
+
+#[test]
+fn test_codegen_functions() {
+    let fib = "fn fib(n) {\n  if n < 2 { return n; }\n  return fib(n - 1) + fib(n - 2);\n}\nlet result = fib(10);";
+    assert!(run(fib) == Ok(55));
+    let locals = "let result = 0;\nfn sub(a, b) { let c = a - b; return c; }\n{ let x = 7; result = sub(x, 2) * 10 + x; }";
+    assert!(run(locals) == Ok(57));
+    assert!(run("fn none() { }\nlet result = none() + 1;") == Ok(1));
+    assert!(run("fn f(a) { return a; }\nlet result = f(1, 2);") == Err("2:14: f takes 1 arguments but was given 2.".to_owned()));
+    assert!(run("fn f(a, a) { return a; }\nlet result = 0;") == Err("1:1: a is already declared in this scope.".to_owned()));
+    assert!(run("fn f() { }\nfn f() { }\nlet result = 0;") == Err("2:1: f is already defined.".to_owned()));
+    assert!(run("let x = 1;\nfn var_x() { return 2; }\nfn _entry() { return 3; }\nlet result = var_x() + _entry() + x;") == Ok(6));
+    assert!(run("let result = 0;\nreturn 1;") == Err("2:1: return is outside of a function.".to_owned()));
+    assert!(run("let result = 0;\n{ fn f() { } }") == Err("2:3: Functions can only be defined at the top level.".to_owned()));
+}
+
+#[test]
+fn test_codegen_functions_asm() {
+    use assembler::Assembler;
+    let codegen = super::compile("fn down(n) { while n > 0 { n = n - 1; } return n; }\nprint down(3);", false).unwrap();
+    let asm = codegen.asm();
+    assert!(asm.contains("  call .fn_down\n  print\n  drop\n  halt\n.fn_down:\n  store 0\n  'while_0:\n"));
+    assert!(Assembler::new(asm).assemble().code == codegen.build().unwrap().code);
+}
+
+#[test]
+fn test_codegen_floats() {
+    assert!(run("let x = 1.5;\nlet y: float = x * 3.0 - 0.5;\nlet result = int(y * 10.0);") == Ok(40));
+    assert!(run("fn half(x: float) -> float { return x / 2.0; }\nlet result = int(-half(float(7)));") == Ok(-3));
+    assert!(run("let a = 2.5;\nlet result = int(a > 2.0 && a <= 2.5 && a != 3.0) + int(2.0 ^ 3.0);") == Ok(9));
+    let codegen = super::compile("print 1.5 + 2.0;", false).unwrap();
+    assert!(codegen.ops[..5] == [
+        Op::Line(1), Op::Float(1.5), Op::Float(2.0), Op::Plain(Opcode::FAdd), Op::Plain(Opcode::FPrint),
+    ]);
+    assert!(codegen.asm().contains("  const 1069547520 ; 1.5\n"));
+}
+
+#[test]
+fn test_codegen_annotated_asm() {
+    use std::fs::File;
+    use std::io::Read;
+    use assembler::Assembler;
+    let source = "// Count down.\nfn down(n: int) -> int {\n  while n > 0 {\n    n = n - 1;\n  }\n  return n;\n}\nprint down(3);";
+    let codegen = super::compile(source, false).unwrap();
+    let asm = codegen.annotated_asm("down.sl", source);
+    assert!(asm == "\
+@code
+._entry:
+  ; down.sl:8: print down(3);
+  const 3
+  call  .fn_down
+  print
+  drop
+  halt
+
+; down.sl:2: fn down(n: int) -> int {
+.fn_down:
+  store 0
+  ; down.sl:3: while n > 0 {
+  'while_0:
+  load  0
+  const 0
+  cmp_lt
+  const 0
+  cmp_eq
+  jmpnz 'end_while_1
+  ; down.sl:4: n = n - 1;
+  load  0
+  const 1
+  sub
+  store 0
+  jmp   'while_0
+  'end_while_1:
+  ; down.sl:6: return n;
+  load  0
+  ret
+  const 0
+  ret
+");
+    assert!(Assembler::new(asm).assemble().code == codegen.build().unwrap().code);
+
+    let mut source = String::new();
+    File::open("divergent.sl").unwrap().read_to_string(&mut source).unwrap();
+    let codegen = super::compile(&source, true).unwrap();
+    assert!(Assembler::new(codegen.annotated_asm("divergent.sl", &source)).assemble().code == codegen.build().unwrap().code);
+}
@@ -1,19 +1,50 @@
+use std::fmt;
 use std::iter::Peekable;
 use std::str::Chars;
 use itertools::Itertools;
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Ident(String),
-    Number(f64),
-    Operator(String, u8),
+    Int(i64),
+    Float(f64),
+    Operator(String),
     LeftParen,
     RightParen,
+    LeftBrace,
+    RightBrace,
+    Comma,
+    Semicolon,
+    Colon,
+    Arrow,
+    Assign,
+}
+
+/// The line and column a token starts at, both counted from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Spanned {
+    pub token: Token,
+    pub position: Position,
 }
 
 enum ConsumeType { Ident, Number }
 
+/// Operators, longest first so `<=` is not read as `<` then `=`.
+const OPERATORS: &[&str] = &["==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "^", "<", ">", "!"];
+
 pub struct Tokenizer<'a> {
     string: &'a str,
 }
@@ -23,78 +54,113 @@ impl<'a> Tokenizer<'a> {
             string: input,
         }
     }
-    pub fn tokenize(&mut self) -> Vec<Token> {
-        println!("Tokenizing Expression: {}", self.string);
+    pub fn tokenize(&mut self) -> Result<Vec<Spanned>, String> {
+        debug!("Tokenizing Expression: {}", self.string);
         let mut chars = &mut self.string.chars().peekable();
-        let mut tokens: Vec<Token> = Vec::new();
+        let mut tokens: Vec<Spanned> = Vec::new();
+        let mut position = Position { line: 1, column: 1 };
 
         loop {
-            match chars.peek() {
+            let start = position;
+            let (token, width) = match chars.peek() {
                 Some(&ch) => match ch {
-                    'a'...'z' | '_' => tokens.push(consume_ident(&mut chars)),
-                    '0'...'9'       => tokens.push(consume_number(&mut chars)),
-                    '+' | '-'       => tokens.push(Token::Operator(chars.take(1).collect::<String>(), 2)),
-                    '*' | '/'       => tokens.push(Token::Operator(chars.take(1).collect::<String>(), 3)),
-                    ')'             => {tokens.push(Token::RightParen); chars.take(1).collect::<String>();},
-                    '('             => {tokens.push(Token::LeftParen); chars.take(1).collect::<String>();},
-                    ' '             => { chars.take(1).collect::<String>(); }
-                    _ => panic!("Syntax Error: {} is not a valid character.", ch)
+                    'a'..='z' | 'A'..='Z' | '_' => consume_ident(&mut chars),
+                    '0'..='9'       => consume_number(&mut chars).map_err(|err| format!("{}: {}", start, err))?,
+                    ')'             => { chars.next(); (Token::RightParen, 1) },
+                    '('             => { chars.next(); (Token::LeftParen, 1) },
+                    '{'             => { chars.next(); (Token::LeftBrace, 1) },
+                    '}'             => { chars.next(); (Token::RightBrace, 1) },
+                    ','             => { chars.next(); (Token::Comma, 1) },
+                    ';'             => { chars.next(); (Token::Semicolon, 1) },
+                    ':'             => { chars.next(); (Token::Colon, 1) },
+                    '-' if chars.clone().nth(1) == Some('>') => { chars.next(); chars.next(); (Token::Arrow, 2) },
+                    '/' if chars.clone().nth(1) == Some('/') => {
+                        // A comment runs to the end of the line.
+                        while chars.peek().is_some_and(|&ch| ch != '\n') { chars.next(); }
+                        continue
+                    },
+                    '\n'            => {
+                        chars.next();
+                        position = Position { line: position.line + 1, column: 1 };
+                        continue
+                    },
+                    ch if ch.is_whitespace() => { chars.next(); position.column += 1; continue },
+                    _ => match consume_operator(&mut chars) {
+                        Some(operator) => (Token::Operator(operator.to_owned()), operator.len()),
+                        None if ch == '=' => { chars.next(); (Token::Assign, 1) },
+                        None => return Err(format!("{}: {} is not a valid character.", start, ch)),
+                    },
                 },
-                None => return tokens
-            }
+                None => return Ok(tokens)
+            };
+            position.column += width;
+            tokens.push(Spanned { token, position: start });
         }
     }
 }
-fn consume_ident(peekable: &mut Peekable<Chars>) -> Token {
+
+fn consume_ident(peekable: &mut Peekable<Chars>) -> (Token, usize) {
     let ret = peekable.by_ref().peeking_take_while(|x| valid_token_char(x, ConsumeType::Ident)).collect::<String>();
-    Token::Ident(ret)
+    let width = ret.len();
+    (Token::Ident(ret), width)
 }
 
-fn consume_number(peekable: &mut Peekable<Chars>) -> Token {
+fn consume_number(peekable: &mut Peekable<Chars>) -> Result<(Token, usize), String> {
     let ret = peekable.by_ref().peeking_take_while(|x| valid_token_char(x, ConsumeType::Number)).collect::<String>();
-    Token::Number(ret.parse::<f64>().unwrap())
+    // Literals with a decimal point are floats.
+    let token = match ret.contains('.') {
+        true => ret.parse::<f64>().ok().map(Token::Float),
+        false => ret.parse::<i64>().ok().map(Token::Int),
+    };
+    match token {
+        Some(token) => Ok((token, ret.len())),
+        None => Err(format!("{} is not a valid number.", ret)),
+    }
+}
+
+fn consume_operator(peekable: &mut Peekable<Chars>) -> Option<&'static str> {
+    let rest: String = peekable.clone().take(2).collect();
+    let operator = OPERATORS.iter().find(|operator| rest.starts_with(*operator))?;
+    for _ in 0..operator.len() { peekable.next(); }
+    Some(operator)
 }
 
 fn valid_token_char(ch: &char, tt: ConsumeType) -> bool {
     match tt {
         ConsumeType::Ident => match *ch {
-            'a'...'z' | '_' => true,
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => true,
             _ => false,
         },
         ConsumeType::Number => match *ch {
-            '0'...'9' | '.' => true,
+            '0'..='9' | '.' => true,
             _ => false,
         },
     }
 }
 
-pub fn shunting_yard(tokens: Vec<Token>) -> Vec<Token> {
-    let mut output: Vec<Token> = Vec::new();
-    let mut operators: Vec<Token> = Vec::new();
 
-    for token in tokens {
-        match token {
-            Token::Ident(_) | Token::Number(_) => output.push(token),
-            Token::Operator(_, cp) => {
-                match operators.last() {
-                    Some(&Token::Operator(_, lp)) => {
-                        if cp > lp { output.push(operators.pop().unwrap()); }
-                    }, _ => {}
-                };
-                operators.push(token);
-            },
-            Token::LeftParen => { operators.push(token); },
-            Token::RightParen => {
-                while let Some(operator) = operators.pop() {
-                    match operator {
-                        Token::LeftParen => { break; },
-                        _ => output.push(operator)
-                    }
-                }
-            },
-        };
-    }
-    operators.reverse();
-    output.append(&mut operators);
-    output
+#[test]
+fn test_tokenize_positions() {
+    let tokens = Tokenizer::new("a1 <= 2.5\n  (b != -c)").tokenize().unwrap();
+    let found: Vec<(Token, String)> = tokens.into_iter().map(|spanned| (spanned.token, spanned.position.to_string())).collect();
+    assert!(found == vec![
+        (Token::Ident("a1".to_owned()), "1:1".to_owned()),
+        (Token::Operator("<=".to_owned()), "1:4".to_owned()),
+        (Token::Float(2.5), "1:7".to_owned()),
+        (Token::LeftParen, "2:3".to_owned()),
+        (Token::Ident("b".to_owned()), "2:4".to_owned()),
+        (Token::Operator("!=".to_owned()), "2:6".to_owned()),
+        (Token::Operator("-".to_owned()), "2:9".to_owned()),
+        (Token::Ident("c".to_owned()), "2:10".to_owned()),
+        (Token::RightParen, "2:11".to_owned()),
+    ]);
+    let tokens = Tokenizer::new("x = y == 1; // done\n}").tokenize().unwrap();
+    assert!(tokens.into_iter().map(|spanned| spanned.token).collect::<Vec<_>>() == vec![
+        Token::Ident("x".to_owned()), Token::Assign, Token::Ident("y".to_owned()), Token::Operator("==".to_owned()),
+        Token::Int(1), Token::Semicolon, Token::RightBrace,
+    ]);
+    assert!(Tokenizer::new("1 + $").tokenize().unwrap_err() == "1:5: $ is not a valid character.");
+    assert!(Tokenizer::new("1..2").tokenize().unwrap_err() == "1:1: 1..2 is not a valid number.");
+    let tokens = Tokenizer::new("fn f(x: float) -> int").tokenize().unwrap();
+    assert!(tokens[4].token == Token::Colon && tokens[7].token == Token::Arrow && tokens[8].position.column == 19);
 }
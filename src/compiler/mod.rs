@@ -1 +1,66 @@
+pub mod ast;
+pub mod codegen;
 pub mod lex;
+pub mod optimize;
+pub mod parser;
+pub mod types;
+
+use opcode::Opcode;
+use vm::VirtualMachine;
+use self::codegen::{CodeGen, Op};
+use self::parser::{parse_expression, parse_program};
+use self::types::{Checker, Type};
+
+
+/// Compile an expression, with each `(name, value)` binding stored in an
+/// `int` variable beforehand, into code that leaves its value on the stack.
+/// Returns the code and the type of the value.
+pub fn compile_expression(source: &str, bindings: &[(String, i64)]) -> Result<(CodeGen, Type), String> {
+    let mut expr = parse_expression(source)?;
+    let mut checker = Checker::new();
+    let mut codegen = CodeGen::new();
+    checker.begin_scope();
+    codegen.begin_scope();
+    for &(ref name, value) in bindings {
+        checker.declare(name, Type::Int)?;
+        let variable = codegen.declare(name);
+        codegen.emit(Op::Value(Opcode::Const, value));
+        codegen.store(variable);
+    }
+    let ty = checker.expression(&mut expr)?;
+    codegen.expression(&expr);
+    codegen.emit(Op::Plain(Opcode::Halt));
+    Ok((codegen, ty))
+}
+
+/// Compile and run an expression on the VM, returning its value as text.
+pub fn eval(source: &str, bindings: &[(String, i64)]) -> Result<String, String> {
+    let (codegen, ty) = compile_expression(source, bindings)?;
+    let mut vm = VirtualMachine::from_image(codegen.build()?)?;
+    vm.run()?;
+    vm.stack().top().map(|value| ty.show(value)).ok_or_else(|| "The expression left nothing on the stack.".to_owned())
+}
+
+/// Type check and compile a Slang program, optimizing it unless `optimize`
+/// is off.
+pub fn compile(source: &str, optimize: bool) -> Result<CodeGen, String> {
+    let mut statements = parse_program(source)?;
+    Checker::new().program(&mut statements)?;
+    if optimize {
+        statements = optimize::program(statements);
+    }
+    let mut codegen = CodeGen::new();
+    codegen.program(&statements);
+    Ok(codegen)
+}
+
+
+#[test]
+fn test_eval() {
+    assert!(eval("(1 + 2) * x", &[("x".to_owned(), 4)]) == Ok("12".to_owned()));
+    assert!(eval("x - 10 / y - 1", &[("x".to_owned(), 3), ("y".to_owned(), 2)]) == Ok("-3".to_owned()));
+    assert!(eval("2 * 3 + 4 == 10 && -x ^ 2 < 0 && 7 % x >= 1", &[("x".to_owned(), 3)]) == Ok("true".to_owned()));
+    assert!(eval("float(x) / 2.0", &[("x".to_owned(), 3)]) == Ok("1.5".to_owned()));
+    assert!(eval("x", &[]) == Err("1:1: x is not declared.".to_owned()));
+    assert!(eval("x + true", &[("x".to_owned(), 3)]) == Err("1:1: Cannot apply + to int and bool.".to_owned()));
+}
@@ -0,0 +1,305 @@
+use super::ast::{Expr, ExprKind, Stmt, StmtKind, UnaryOp, BinaryOp};
+use super::lex::Position;
+use super::types::{Type, BUILTINS};
+
+
+/// Optimize a type checked program. Constant subexpressions are folded with
+/// the same 32 bit arithmetic the VM uses, identities like `x * 1` are
+/// simplified, and code that cannot run or whose result is never used is
+/// dropped. Anything with a side effect, such as a call, is kept.
+pub fn program(statements: Vec<Stmt>) -> Vec<Stmt> {
+    // Top level variables are globals, which functions may read, so only the
+    // locals of blocks and functions are dropped when unused.
+    block(statements, false)
+}
+
+fn block(statements: Vec<Stmt>, locals: bool) -> Vec<Stmt> {
+    let mut optimized = Vec::new();
+    for statement in statements {
+        let jumps = matches!(statement.kind, StmtKind::Return(_) | StmtKind::Break | StmtKind::Continue);
+        optimized.extend(self::statement(statement));
+        // Nothing after a jump in the same block can run.
+        if jumps { break }
+    }
+    if locals { drop_unused(&mut optimized); }
+    optimized
+}
+
+fn statement(statement: Stmt) -> Option<Stmt> {
+    let position = statement.position;
+    let kind = match statement.kind {
+        StmtKind::Let(name, ty, value) => StmtKind::Let(name, ty, expression(value)),
+        StmtKind::Assign(name, value) => StmtKind::Assign(name, expression(value)),
+        StmtKind::Print(value) => StmtKind::Print(expression(value)),
+        StmtKind::Expr(value) => {
+            let value = expression(value);
+            if pure(&value) { return None }
+            StmtKind::Expr(value)
+        },
+        StmtKind::Block(body) => {
+            let body = block(body, true);
+            if body.is_empty() { return None }
+            StmtKind::Block(body)
+        },
+        // A branch with a constant condition becomes a block, which keeps the
+        // scope of its variables.
+        StmtKind::If(condition, then, otherwise) => match expression(condition) {
+            Expr { kind: ExprKind::Bool(true), .. } => return self::statement(Stmt { kind: StmtKind::Block(then), position }),
+            Expr { kind: ExprKind::Bool(false), .. } => {
+                return otherwise.and_then(|otherwise| self::statement(Stmt { kind: StmtKind::Block(otherwise), position }))
+            },
+            condition => StmtKind::If(condition, block(then, true), otherwise.map(|otherwise| block(otherwise, true))),
+        },
+        StmtKind::While(condition, body) => match expression(condition) {
+            Expr { kind: ExprKind::Bool(false), .. } => return None,
+            condition => StmtKind::While(condition, block(body, true)),
+        },
+        StmtKind::Fn(mut function) => {
+            function.body = block(function.body, true);
+            StmtKind::Fn(function)
+        },
+        StmtKind::Return(value) => StmtKind::Return(value.map(expression)),
+        kind => kind,
+    };
+    Some(Stmt { kind, position })
+}
+
+/// Drop each `let` whose variable is never read afterwards, with any
+/// assignments to it. Values with side effects are still evaluated.
+fn drop_unused(statements: &mut Vec<Stmt>) {
+    // Going backwards, dropping a variable can leave an earlier one unused.
+    for index in (0..statements.len()).rev() {
+        let name = match statements[index].kind {
+            StmtKind::Let(ref name, _, _) => name.clone(),
+            _ => continue,
+        };
+        if statements[index + 1..].iter().any(|statement| mentions(statement, &name)) { continue }
+        let rest: Vec<Stmt> = statements.drain(index + 1..).collect();
+        let rest = strip_assignments(rest, &name);
+        let statement = statements.pop().unwrap();
+        if let StmtKind::Let(_, _, value) = statement.kind {
+            if !pure(&value) {
+                statements.push(Stmt { kind: StmtKind::Expr(value), position: statement.position });
+            }
+        }
+        statements.extend(rest);
+    }
+}
+
+/// Whether `statement` reads `name`, or declares another variable called
+/// `name` that its assignments might be meant for.
+fn mentions(statement: &Stmt, name: &str) -> bool {
+    let any = |body: &[Stmt]| body.iter().any(|statement| mentions(statement, name));
+    match statement.kind {
+        StmtKind::Let(ref declared, _, ref value) => declared == name || reads(value, name),
+        StmtKind::Assign(_, ref value) | StmtKind::Print(ref value) | StmtKind::Expr(ref value) => reads(value, name),
+        StmtKind::Block(ref body) => any(body),
+        StmtKind::If(ref condition, ref then, ref otherwise) => {
+            reads(condition, name) || any(then) || otherwise.as_ref().is_some_and(|otherwise| any(otherwise))
+        },
+        StmtKind::While(ref condition, ref body) => reads(condition, name) || any(body),
+        StmtKind::Return(ref value) => value.as_ref().is_some_and(|value| reads(value, name)),
+        StmtKind::Break | StmtKind::Continue | StmtKind::Fn(_) => false,
+    }
+}
+
+fn reads(expr: &Expr, name: &str) -> bool {
+    match expr.kind {
+        ExprKind::Variable(ref variable) => variable == name,
+        ExprKind::Unary(_, ref operand) => reads(operand, name),
+        ExprKind::Binary(_, ref lhs, ref rhs) => reads(lhs, name) || reads(rhs, name),
+        ExprKind::Call(_, ref arguments) => arguments.iter().any(|argument| reads(argument, name)),
+        _ => false,
+    }
+}
+
+fn strip_assignments(statements: Vec<Stmt>, name: &str) -> Vec<Stmt> {
+    statements.into_iter().filter_map(|statement| {
+        let position = statement.position;
+        let kind = match statement.kind {
+            StmtKind::Assign(assigned, value) => {
+                if assigned != name { StmtKind::Assign(assigned, value) }
+                else if pure(&value) { return None }
+                else { StmtKind::Expr(value) }
+            },
+            StmtKind::Block(body) => StmtKind::Block(strip_assignments(body, name)),
+            StmtKind::If(condition, then, otherwise) => {
+                StmtKind::If(condition, strip_assignments(then, name), otherwise.map(|otherwise| strip_assignments(otherwise, name)))
+            },
+            StmtKind::While(condition, body) => StmtKind::While(condition, strip_assignments(body, name)),
+            kind => kind,
+        };
+        Some(Stmt { kind, position })
+    }).collect()
+}
+
+/// Whether evaluating `expr` has no effect but its value. Only user functions
+/// can print or loop forever, and only an integer division by zero can fault;
+/// `i32::MIN / -1` wraps like the VM does.
+fn pure(expr: &Expr) -> bool {
+    match expr.kind {
+        ExprKind::Unary(_, ref operand) => pure(operand),
+        ExprKind::Binary(BinaryOp::Div, ref lhs, ref rhs) | ExprKind::Binary(BinaryOp::Mod, ref lhs, ref rhs) => match rhs.kind {
+            ExprKind::Int(0) => false,
+            ExprKind::Int(_) | ExprKind::Float(_) => pure(lhs),
+            _ => false,
+        },
+        ExprKind::Binary(_, ref lhs, ref rhs) => pure(lhs) && pure(rhs),
+        ExprKind::Call(ref name, ref arguments) => BUILTINS.contains(&&**name) && arguments.iter().all(pure),
+        _ => true,
+    }
+}
+
+/// Fold the constant subexpressions of `expr`. Folding never changes the
+/// type of an expression, so the types the checker recorded still hold.
+pub fn expression(expr: Expr) -> Expr {
+    let (position, ty) = (expr.position, expr.ty);
+    let kind = match expr.kind {
+        ExprKind::Unary(op, operand) => {
+            let operand = expression(*operand);
+            match (op, operand.kind) {
+                (UnaryOp::Neg, ExprKind::Int(value)) if int(value).is_some() => ExprKind::Int(0i32.wrapping_sub(value as i32) as i64),
+                // Negation subtracts from 0.0, which is not the same as
+                // flipping the sign of 0.0.
+                (UnaryOp::Neg, ExprKind::Float(value)) => ExprKind::Float((0.0 - value as f32) as f64),
+                (UnaryOp::Not, ExprKind::Bool(value)) => ExprKind::Bool(!value),
+                (UnaryOp::Not, ExprKind::Unary(UnaryOp::Not, inner)) => return *inner,
+                (op, kind) => ExprKind::Unary(op, Box::new(Expr { kind, position: operand.position, ty: operand.ty })),
+            }
+        },
+        ExprKind::Binary(op, lhs, rhs) => return binary(op, expression(*lhs), expression(*rhs), position, ty),
+        ExprKind::Call(name, arguments) => {
+            let arguments: Vec<Expr> = arguments.into_iter().map(expression).collect();
+            match (&*name, arguments.first().map(|argument| &argument.kind), arguments.len()) {
+                ("int", Some(&ExprKind::Int(value)), 1) => ExprKind::Int(value),
+                ("int", Some(&ExprKind::Float(value)), 1) => ExprKind::Int((value as f32) as i32 as i64),
+                ("int", Some(&ExprKind::Bool(value)), 1) => ExprKind::Int(value as i64),
+                ("float", Some(&ExprKind::Int(value)), 1) if int(value).is_some() => ExprKind::Float((value as i32) as f32 as f64),
+                ("float", Some(&ExprKind::Float(value)), 1) => ExprKind::Float(value),
+                _ => ExprKind::Call(name, arguments),
+            }
+        },
+        kind => kind,
+    };
+    Expr { kind, position, ty }
+}
+
+/// The value of an integer literal, if it fits the VM's 32 bits.
+fn int(value: i64) -> Option<i32> {
+    if value >= i32::MIN as i64 && value <= i32::MAX as i64 { Some(value as i32) } else { None }
+}
+
+fn binary(op: BinaryOp, lhs: Expr, rhs: Expr, position: Position, ty: Option<Type>) -> Expr {
+    let constant = |kind| Expr { kind, position, ty: ty.clone() };
+    let folded = match (&lhs.kind, &rhs.kind) {
+        (&ExprKind::Int(a), &ExprKind::Int(b)) if int(a).is_some() && int(b).is_some() => {
+            let (a, b) = (a as i32, b as i32);
+            match op {
+                BinaryOp::Add => Some(ExprKind::Int(a.wrapping_add(b) as i64)),
+                BinaryOp::Sub => Some(ExprKind::Int(a.wrapping_sub(b) as i64)),
+                BinaryOp::Mul => Some(ExprKind::Int(a.wrapping_mul(b) as i64)),
+                // A division by zero is left for the VM to report.
+                BinaryOp::Div if b != 0 => Some(ExprKind::Int(a.wrapping_div(b) as i64)),
+                BinaryOp::Mod if b != 0 => Some(ExprKind::Int(a.wrapping_rem(b) as i64)),
+                BinaryOp::Div | BinaryOp::Mod => None,
+                BinaryOp::Pow => Some(ExprKind::Int(a.wrapping_pow(b as u32) as i64)),
+                BinaryOp::Eq => Some(ExprKind::Bool(a == b)),
+                BinaryOp::Ne => Some(ExprKind::Bool(a != b)),
+                BinaryOp::Lt => Some(ExprKind::Bool(a < b)),
+                BinaryOp::Gt => Some(ExprKind::Bool(a > b)),
+                BinaryOp::Le => Some(ExprKind::Bool(a <= b)),
+                BinaryOp::Ge => Some(ExprKind::Bool(a >= b)),
+                BinaryOp::And | BinaryOp::Or => None,
+            }
+        },
+        (&ExprKind::Float(a), &ExprKind::Float(b)) => {
+            let (a, b) = (a as f32, b as f32);
+            let float = |value: f32| Some(ExprKind::Float(value as f64));
+            match op {
+                BinaryOp::Add => float(a + b),
+                BinaryOp::Sub => float(a - b),
+                BinaryOp::Mul => float(a * b),
+                BinaryOp::Div => float(a / b),
+                BinaryOp::Mod => float(a % b),
+                BinaryOp::Pow => float(a.powf(b)),
+                BinaryOp::Eq => Some(ExprKind::Bool(a == b)),
+                BinaryOp::Ne => Some(ExprKind::Bool(a != b)),
+                BinaryOp::Lt => Some(ExprKind::Bool(a < b)),
+                BinaryOp::Gt => Some(ExprKind::Bool(a > b)),
+                BinaryOp::Le => Some(ExprKind::Bool(a <= b)),
+                BinaryOp::Ge => Some(ExprKind::Bool(a >= b)),
+                BinaryOp::And | BinaryOp::Or => None,
+            }
+        },
+        (&ExprKind::Bool(a), &ExprKind::Bool(b)) => match op {
+            BinaryOp::And => Some(ExprKind::Bool(a && b)),
+            BinaryOp::Or => Some(ExprKind::Bool(a || b)),
+            BinaryOp::Eq => Some(ExprKind::Bool(a == b)),
+            BinaryOp::Ne => Some(ExprKind::Bool(a != b)),
+            _ => None,
+        },
+        _ => None,
+    };
+    if let Some(kind) = folded {
+        return constant(kind)
+    }
+    // Identities. Both sides are always evaluated, so a side is only dropped
+    // when it is pure. Floats are left alone: `x + 0.0` is not `x` for -0.0.
+    match (op, &lhs.kind, &rhs.kind) {
+        (BinaryOp::Add, _, &ExprKind::Int(0)) | (BinaryOp::Sub, _, &ExprKind::Int(0))
+            | (BinaryOp::Mul, _, &ExprKind::Int(1)) | (BinaryOp::Div, _, &ExprKind::Int(1))
+            | (BinaryOp::Pow, _, &ExprKind::Int(1)) => lhs,
+        (BinaryOp::Add, &ExprKind::Int(0), _) | (BinaryOp::Mul, &ExprKind::Int(1), _) => rhs,
+        (BinaryOp::Mul, _, &ExprKind::Int(0)) if pure(&lhs) => constant(ExprKind::Int(0)),
+        (BinaryOp::Mul, &ExprKind::Int(0), _) if pure(&rhs) => constant(ExprKind::Int(0)),
+        (BinaryOp::Pow, _, &ExprKind::Int(0)) if pure(&lhs) => constant(ExprKind::Int(1)),
+        (BinaryOp::And, _, &ExprKind::Bool(true)) | (BinaryOp::Or, _, &ExprKind::Bool(false)) => lhs,
+        (BinaryOp::And, &ExprKind::Bool(true), _) | (BinaryOp::Or, &ExprKind::Bool(false), _) => rhs,
+        (BinaryOp::And, _, &ExprKind::Bool(false)) if pure(&lhs) => constant(ExprKind::Bool(false)),
+        (BinaryOp::And, &ExprKind::Bool(false), _) if pure(&rhs) => constant(ExprKind::Bool(false)),
+        (BinaryOp::Or, _, &ExprKind::Bool(true)) if pure(&lhs) => constant(ExprKind::Bool(true)),
+        (BinaryOp::Or, &ExprKind::Bool(true), _) if pure(&rhs) => constant(ExprKind::Bool(true)),
+        _ => constant(ExprKind::Binary(op, Box::new(lhs), Box::new(rhs))),
+    }
+}
+
+
+#[cfg(test)]
+fn assert_optimizes(source: &str, expected: &str) {
+    let optimized = ::compiler::compile(source, true).unwrap().asm();
+    let expected = ::compiler::compile(expected, false).unwrap().asm();
+    assert!(optimized == expected, "\n{}\nis not\n{}", optimized, expected);
+}
+
+#[test]
+fn test_optimize_constants() {
+    assert_optimizes("let x = 2 * 3 + 4 ^ 2;\nprint -x % 7;", "let x = 22;\nprint -x % 7;");
+    assert_optimizes("print 2147483647 + 1 == -2147483647 - 1;", "print true;");
+    assert_optimizes("print float(3) / 2.0 + float(int(2.7));", "print 3.5;");
+    assert_optimizes("print 1 < 2 && !!(3 >= 4 || !false);", "print true;");
+    // Division by zero is left to fault at run time.
+    assert_optimizes("print 7 / (1 - 1);", "print 7 / 0;");
+    assert_optimizes("print (-2147483647 - 1) / -1 == -2147483647 - 1;\nprint (-2147483647 - 1) % -1 == 0;", "print true;\nprint true;");
+}
+
+#[test]
+fn test_optimize_identities() {
+    assert_optimizes("fn f(x) { return (x + 0) * 1 - 0 ^ 1; }\nprint f(1) * 0;", "fn f(x) { return x; }\nprint f(1) * 0;");
+    assert_optimizes("let y = 3;\nprint y * 0 + y ^ 0;\nprint true && y > 1 || false;", "let y = 3;\nprint 1;\nprint y > 1;");
+}
+
+#[test]
+fn test_optimize_dead_code() {
+    let source = "fn f(x) { if x > 0 { return 1; print 2; } return 0; print 3; }\n\
+                  if 1 > 2 { print 4; } else { print 5; }\nif false { print 6; }\n\
+                  while false { print 7; }\nwhile true { break; print 8; }\n{ }\n1 + 2;";
+    assert_optimizes(source, "fn f(x) { if x > 0 { return 1; } return 0; }\n{ print 5; }\nwhile true { break; }");
+}
+
+#[test]
+fn test_optimize_unused_locals() {
+    let source = "fn f(x) { let a = x * 2; let b = a + 1; let c = f(x - 1); c = 4; a = 5; let d = x / a; return x; }\n\
+                  let g = 1;\n{ let y = 1; { let y = 2; print y; } }";
+    assert_optimizes(source, "fn f(x) { let a = x * 2; f(x - 1); a = 5; x / a; return x; }\n\
+                              let g = 1;\n{ let y = 1; { let y = 2; print y; } }");
+}
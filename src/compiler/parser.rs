@@ -0,0 +1,428 @@
+use super::ast::{Expr, ExprKind, Stmt, StmtKind, Function, UnaryOp, BinaryOp, Associativity};
+use super::lex::{Token, Spanned, Position, Tokenizer};
+use super::types::Type;
+
+
+/// Words that begin statements and cannot name variables.
+const KEYWORDS: &[&str] = &["let", "print", "if", "else", "while", "break", "continue", "fn", "return", "true", "false"];
+
+/// A Pratt parser over the tokens of the Slang language.
+pub struct Parser {
+    tokens: Vec<Spanned>,
+    index: usize,
+    end: Position,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<Spanned>) -> Self {
+        let end = tokens.last().map_or(Position { line: 1, column: 1 }, |spanned| spanned.position);
+        Parser {
+            tokens,
+            index: 0,
+            end,
+        }
+    }
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|spanned| &spanned.token)
+    }
+    /// Where the next token starts, or the last token for errors at the end.
+    fn position(&self) -> Position {
+        self.tokens.get(self.index).map_or(self.end, |spanned| spanned.position)
+    }
+    fn next(&mut self) -> Option<Spanned> {
+        let spanned = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        spanned
+    }
+    fn error<T>(&self, message: &str) -> Result<T, String> {
+        match self.tokens.get(self.index) {
+            Some(spanned) => Err(format!("{}: {}, found {}.", spanned.position, message, describe(&spanned.token))),
+            None => Err(format!("{}: {}, found the end of the input.", self.end, message)),
+        }
+    }
+    fn expect(&mut self, token: Token, message: &str) -> Result<(), String> {
+        if self.peek() == Some(&token) {
+            self.index += 1;
+            Ok(())
+        } else {
+            self.error(message)
+        }
+    }
+    pub fn at_end(&self) -> bool {
+        self.index >= self.tokens.len()
+    }
+    /// A variable name, after checking it is not a keyword.
+    fn name(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Ident(name)) if !KEYWORDS.contains(&&**name) => {},
+            _ => return self.error("Expected a name"),
+        }
+        match self.next() {
+            Some(Spanned { token: Token::Ident(name), .. }) => Ok(name),
+            _ => unreachable!(),
+        }
+    }
+    /// The type after the `:` or `->` of an annotation.
+    fn type_name(&mut self) -> Result<Type, String> {
+        let ty = match self.peek() {
+            Some(Token::Ident(name)) => Type::from_name(name),
+            _ => None,
+        };
+        match ty {
+            Some(ty) => { self.next(); Ok(ty) },
+            None => self.error("Expected a type"),
+        }
+    }
+    /// An optional `: type` annotation.
+    fn annotation(&mut self) -> Result<Option<Type>, String> {
+        if self.peek() != Some(&Token::Colon) { return Ok(None) }
+        self.next();
+        self.type_name().map(Some)
+    }
+    pub fn statement(&mut self) -> Result<Stmt, String> {
+        let position = self.position();
+        let keyword = match self.peek() {
+            Some(Token::Ident(name)) => name.clone(),
+            Some(&Token::LeftBrace) => {
+                self.next();
+                return Ok(Stmt { kind: StmtKind::Block(self.block()?), position })
+            },
+            _ => String::new(),
+        };
+        let kind = match &*keyword {
+            "if" => return self.if_statement(),
+            "while" => {
+                self.next();
+                let condition = self.expression(0)?;
+                let body = self.body()?;
+                return Ok(Stmt { kind: StmtKind::While(condition, body), position })
+            },
+            "fn" => {
+                self.next();
+                let name = self.name()?;
+                self.expect(Token::LeftParen, "Expected '('")?;
+                let mut parameters = Vec::new();
+                if self.peek() == Some(&Token::RightParen) {
+                    self.next();
+                } else {
+                    loop {
+                        let parameter = self.name()?;
+                        parameters.push((parameter, self.annotation()?));
+                        match self.peek() {
+                            Some(&Token::Comma) => { self.next(); },
+                            Some(&Token::RightParen) => { self.next(); break },
+                            _ => return self.error("Expected ',' or ')' in parameters"),
+                        }
+                    }
+                }
+                let returns = match self.peek() {
+                    Some(&Token::Arrow) => { self.next(); Some(self.type_name()?) },
+                    _ => None,
+                };
+                let body = self.body()?;
+                let function = Function { name, parameters, returns, body };
+                return Ok(Stmt { kind: StmtKind::Fn(function), position })
+            },
+            "return" => {
+                self.next();
+                match self.peek() {
+                    Some(&Token::Semicolon) => StmtKind::Return(None),
+                    _ => StmtKind::Return(Some(self.expression(0)?)),
+                }
+            },
+            "break" => {
+                self.next();
+                StmtKind::Break
+            },
+            "continue" => {
+                self.next();
+                StmtKind::Continue
+            },
+            "let" => {
+                self.next();
+                let name = self.name()?;
+                let ty = self.annotation()?;
+                self.expect(Token::Assign, "Expected '='")?;
+                StmtKind::Let(name, ty, self.expression(0)?)
+            },
+            "print" => {
+                self.next();
+                StmtKind::Print(self.expression(0)?)
+            },
+            _ if self.tokens.get(self.index + 1).map(|spanned| &spanned.token) == Some(&Token::Assign) => {
+                let name = self.name()?;
+                self.next();
+                StmtKind::Assign(name, self.expression(0)?)
+            },
+            _ => StmtKind::Expr(self.expression(0)?),
+        };
+        self.expect(Token::Semicolon, "Expected ';'")?;
+        Ok(Stmt { kind, position })
+    }
+    /// An `if` statement with any `else if` and `else` branches.
+    fn if_statement(&mut self) -> Result<Stmt, String> {
+        let position = self.position();
+        self.next();
+        let condition = self.expression(0)?;
+        let then = self.body()?;
+        let otherwise = if self.peek() == Some(&Token::Ident("else".to_owned())) {
+            self.next();
+            if self.peek() == Some(&Token::Ident("if".to_owned())) {
+                Some(vec![self.if_statement()?])
+            } else {
+                Some(self.body()?)
+            }
+        } else {
+            None
+        };
+        Ok(Stmt { kind: StmtKind::If(condition, then, otherwise), position })
+    }
+    /// A braced block, as the body of a branch or loop.
+    fn body(&mut self) -> Result<Vec<Stmt>, String> {
+        self.expect(Token::LeftBrace, "Expected '{'")?;
+        self.block()
+    }
+    /// The statements of a block, after its `{`.
+    fn block(&mut self) -> Result<Vec<Stmt>, String> {
+        let mut statements = Vec::new();
+        while self.peek() != Some(&Token::RightBrace) {
+            if self.at_end() { return self.error("Expected '}'") }
+            statements.push(self.statement()?);
+        }
+        self.next();
+        Ok(statements)
+    }
+    /// Parse an expression whose binary operators all bind at least as
+    /// tightly as `min_precedence`.
+    pub fn expression(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut lhs = self.prefix()?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Operator(symbol)) => match BinaryOp::from_symbol(symbol) {
+                    Some(op) => op,
+                    None => break,
+                },
+                _ => break,
+            };
+            if op.precedence() < min_precedence { break }
+            self.index += 1;
+            let next = match op.associativity() {
+                Associativity::Left => op.precedence() + 1,
+                Associativity::Right => op.precedence(),
+            };
+            let rhs = self.expression(next)?;
+            let position = lhs.position;
+            lhs = Expr { kind: ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)), position, ty: None };
+        }
+        Ok(lhs)
+    }
+    fn prefix(&mut self) -> Result<Expr, String> {
+        let position = self.position();
+        let token = match self.peek() {
+            Some(token) => token.clone(),
+            None => return self.error("Expected an expression"),
+        };
+        let kind = match token {
+            Token::Int(value) => {
+                self.next();
+                ExprKind::Int(value)
+            },
+            Token::Float(value) => {
+                self.next();
+                ExprKind::Float(value)
+            },
+            Token::Ident(ref name) if name == "true" || name == "false" => {
+                self.next();
+                ExprKind::Bool(name == "true")
+            },
+            Token::Ident(ref name) if KEYWORDS.contains(&&**name) => return self.error("Expected an expression"),
+            Token::Ident(name) => {
+                self.next();
+                if self.peek() == Some(&Token::LeftParen) {
+                    self.next();
+                    ExprKind::Call(name, self.arguments()?)
+                } else {
+                    ExprKind::Variable(name)
+                }
+            },
+            Token::LeftParen => {
+                self.next();
+                let inner = self.expression(0)?;
+                self.expect(Token::RightParen, "Expected ')'")?;
+                return Ok(inner)
+            },
+            Token::Operator(ref symbol) if UnaryOp::from_symbol(symbol).is_some() => {
+                self.next();
+                let op = UnaryOp::from_symbol(symbol).unwrap();
+                ExprKind::Unary(op, Box::new(self.expression(op.precedence())?))
+            },
+            _ => return self.error("Expected an expression"),
+        };
+        Ok(Expr { kind, position, ty: None })
+    }
+    /// The arguments of a call, after its `(`.
+    fn arguments(&mut self) -> Result<Vec<Expr>, String> {
+        let mut arguments = Vec::new();
+        if self.peek() == Some(&Token::RightParen) {
+            self.next();
+            return Ok(arguments)
+        }
+        loop {
+            arguments.push(self.expression(0)?);
+            match self.peek() {
+                Some(&Token::Comma) => { self.next(); },
+                Some(&Token::RightParen) => { self.next(); return Ok(arguments) },
+                _ => return self.error("Expected ',' or ')' in arguments"),
+            }
+        }
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match *token {
+        Token::Ident(ref name) => format!("'{}'", name),
+        Token::Int(value) => format!("{}", value),
+        Token::Float(value) => format!("{:?}", value),
+        Token::Operator(ref symbol) => format!("'{}'", symbol),
+        Token::LeftParen => "'('".to_owned(),
+        Token::RightParen => "')'".to_owned(),
+        Token::LeftBrace => "'{'".to_owned(),
+        Token::RightBrace => "'}'".to_owned(),
+        Token::Comma => "','".to_owned(),
+        Token::Semicolon => "';'".to_owned(),
+        Token::Colon => "':'".to_owned(),
+        Token::Arrow => "'->'".to_owned(),
+        Token::Assign => "'='".to_owned(),
+    }
+}
+
+/// Parse `source` as a single expression.
+pub fn parse_expression(source: &str) -> Result<Expr, String> {
+    let mut parser = Parser::new(Tokenizer::new(source).tokenize()?);
+    let expr = parser.expression(0)?;
+    if !parser.at_end() { return parser.error("Expected the end of the expression") }
+    Ok(expr)
+}
+
+/// Parse `source` as a program: a sequence of statements.
+pub fn parse_program(source: &str) -> Result<Vec<Stmt>, String> {
+    let mut parser = Parser::new(Tokenizer::new(source).tokenize()?);
+    let mut statements = Vec::new();
+    while !parser.at_end() {
+        statements.push(parser.statement()?);
+    }
+    Ok(statements)
+}
+
+
+/// The expression with every operation parenthesized.
+#[cfg(test)]
+fn show(expr: &Expr) -> String {
+    match expr.kind {
+        ExprKind::Int(value) => format!("{}", value),
+        ExprKind::Float(value) => format!("{:?}", value),
+        ExprKind::Bool(value) => format!("{}", value),
+        ExprKind::Variable(ref name) => name.clone(),
+        ExprKind::Unary(op, ref operand) => format!("({:?} {})", op, show(operand)),
+        ExprKind::Binary(op, ref lhs, ref rhs) => format!("({:?} {} {})", op, show(lhs), show(rhs)),
+        ExprKind::Call(ref name, ref args) => format!("{}({})", name, args.iter().map(show).collect::<Vec<_>>().join(", ")),
+    }
+}
+
+#[test]
+fn test_parse_precedence_and_associativity() {
+    let parse = |source| show(&parse_expression(source).unwrap());
+    assert!(parse("1 - 2 - 3") == "(Sub (Sub 1 2) 3)");
+    assert!(parse("2 * 3 + 4") == "(Add (Mul 2 3) 4)");
+    assert!(parse("2 + 3 * 4") == "(Add 2 (Mul 3 4))");
+    assert!(parse("2 ^ 3 ^ 2") == "(Pow 2 (Pow 3 2))");
+    assert!(parse("-x ^ 2 * y") == "(Mul (Neg (Pow x 2)) y)");
+    assert!(parse("a < b == !c || d && e") == "(Or (Eq (Lt a b) (Not c)) (And d e))");
+    assert!(parse("f(1, g(), (2 + 3)) % 4") == "(Mod f(1, g(), (Add 2 3)) 4)");
+}
+
+#[test]
+fn test_parse_errors() {
+    assert!(parse_expression("(1 + 2").unwrap_err() == "1:6: Expected ')', found the end of the input.");
+    assert!(parse_expression("1 + * 2").unwrap_err() == "1:5: Expected an expression, found '*'.");
+    assert!(parse_expression("1 2").unwrap_err() == "1:3: Expected the end of the expression, found 2.");
+    assert!(parse_expression("f(1 2)").unwrap_err() == "1:5: Expected ',' or ')' in arguments, found 2.");
+    assert!(parse_expression("").unwrap_err() == "1:1: Expected an expression, found the end of the input.");
+}
+
+#[test]
+fn test_parse_statements() {
+    let program = parse_program("let x = 1;\nx = x + 1;\n{ print -x; f(x); }").unwrap();
+    let kinds: Vec<String> = program.iter().map(|stmt| match stmt.kind {
+        StmtKind::Let(ref name, _, ref value) => format!("{}:let {} {}", stmt.position, name, show(value)),
+        StmtKind::Assign(ref name, ref value) => format!("{}:{} = {}", stmt.position, name, show(value)),
+        StmtKind::Block(ref body) => format!("{}:block of {}", stmt.position, body.len()),
+        ref other => format!("{:?}", other),
+    }).collect();
+    assert!(kinds == vec!["1:1:let x 1", "2:1:x = (Add x 1)", "3:1:block of 2"]);
+    assert!(parse_program("let print = 1;").unwrap_err() == "1:5: Expected a name, found 'print'.");
+    assert!(parse_program("let x = 1").unwrap_err() == "1:9: Expected ';', found the end of the input.");
+    assert!(parse_program("{ x = 1;").unwrap_err() == "1:8: Expected '}', found the end of the input.");
+    assert!(parse_program("x = let;").unwrap_err() == "1:5: Expected an expression, found 'let'.");
+}
+
+#[test]
+fn test_parse_control_flow() {
+    let program = parse_program("while i < 3 { if i == 1 { break; } else if i == 2 { continue; } else { i = i + 1; } }").unwrap();
+    let (condition, body) = match program[0].kind {
+        StmtKind::While(ref condition, ref body) => (condition, body),
+        ref other => panic!("Unexpected {:?}", other),
+    };
+    assert!(show(condition) == "(Lt i 3)");
+    match body[0].kind {
+        StmtKind::If(_, ref then, Some(ref otherwise)) => {
+            assert!(then[0].kind == StmtKind::Break);
+            match otherwise[0].kind {
+                StmtKind::If(_, ref then, Some(ref otherwise)) => {
+                    assert!(then[0].kind == StmtKind::Continue);
+                    assert!(otherwise.len() == 1);
+                },
+                ref other => panic!("Unexpected {:?}", other),
+            }
+        },
+        ref other => panic!("Unexpected {:?}", other),
+    }
+    assert!(parse_program("if x print x;").unwrap_err() == "1:6: Expected '{', found 'print'.");
+}
+
+#[test]
+fn test_parse_functions() {
+    let program = parse_program("fn add(a, b: float) -> float { return a + b; }\nfn nothing() { return; }\nprint add(1, nothing());").unwrap();
+    match program[0].kind {
+        StmtKind::Fn(ref function) => {
+            assert!(function.name == "add" && function.returns == Some(Type::Float));
+            assert!(function.parameters == vec![("a".to_owned(), None), ("b".to_owned(), Some(Type::Float))]);
+            assert!(function.signature().to_string() == "fn(int, float) -> float");
+            match function.body[0].kind {
+                StmtKind::Return(Some(ref value)) => assert!(show(value) == "(Add a b)"),
+                ref other => panic!("Unexpected {:?}", other),
+            }
+        },
+        ref other => panic!("Unexpected {:?}", other),
+    }
+    assert!(program[1].kind == StmtKind::Fn(Function { name: "nothing".to_owned(), parameters: Vec::new(), returns: None, body: vec![
+        Stmt { kind: StmtKind::Return(None), position: Position { line: 2, column: 16 } },
+    ] }));
+    assert!(parse_program("fn f(a b) {}").unwrap_err() == "1:8: Expected ',' or ')' in parameters, found 'b'.");
+    assert!(parse_program("fn f() -> string {}").unwrap_err() == "1:11: Expected a type, found 'string'.");
+}
+
+#[test]
+fn test_parse_literals_and_annotations() {
+    let program = parse_program("let x: float = 1.5 * 2;\nlet b = !true;").unwrap();
+    match program[0].kind {
+        StmtKind::Let(ref name, Some(Type::Float), ref value) => assert!(name == "x" && show(value) == "(Mul 1.5 2)"),
+        ref other => panic!("Unexpected {:?}", other),
+    }
+    match program[1].kind {
+        StmtKind::Let(_, None, ref value) => assert!(show(value) == "(Not true)"),
+        ref other => panic!("Unexpected {:?}", other),
+    }
+    assert!(parse_program("let true = 1;").unwrap_err() == "1:5: Expected a name, found 'true'.");
+    assert!(parse_program("let x: = 1;").unwrap_err() == "1:8: Expected a type, found '='.");
+}
@@ -0,0 +1,335 @@
+use std::fmt;
+
+use super::ast::{Expr, ExprKind, Stmt, StmtKind, Function, UnaryOp, BinaryOp};
+
+
+/// The type of a Slang value. Every value is one 32 bit word: floats are
+/// stored as their bits and bools as 0 or 1.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Int,
+    Float,
+    Bool,
+    /// A function, from its parameter types to its result type.
+    Fn(Vec<Type>, Box<Type>),
+}
+
+impl Type {
+    /// The type a name stands for in an annotation.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "int" => Some(Type::Int),
+            "float" => Some(Type::Float),
+            "bool" => Some(Type::Bool),
+            _ => None,
+        }
+    }
+    /// Show a word of the stack as a value of this type.
+    pub fn show(&self, word: u32) -> String {
+        match *self {
+            Type::Float => format!("{:?}", f32::from_bits(word)),
+            Type::Bool => format!("{}", word != 0),
+            _ => format!("{}", word as i32),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Type::Int => write!(f, "int"),
+            Type::Float => write!(f, "float"),
+            Type::Bool => write!(f, "bool"),
+            Type::Fn(ref parameters, ref result) => {
+                let parameters: Vec<String> = parameters.iter().map(|ty| ty.to_string()).collect();
+                write!(f, "fn({}) -> {}", parameters.join(", "), result)
+            },
+        }
+    }
+}
+
+/// The type of `op` applied to a value of type `operand`.
+pub fn unary(op: UnaryOp, operand: &Type) -> Result<Type, String> {
+    match (op, operand) {
+        (UnaryOp::Neg, &Type::Int) | (UnaryOp::Neg, &Type::Float) => Ok(operand.clone()),
+        (UnaryOp::Not, &Type::Bool) => Ok(Type::Bool),
+        _ => Err(format!("Cannot apply {} to {}.", op.symbol(), operand)),
+    }
+}
+
+/// The type of `op` applied to values of types `lhs` and `rhs`. Both sides
+/// must have the same type: ints are never converted to floats implicitly.
+pub fn binary(op: BinaryOp, lhs: &Type, rhs: &Type) -> Result<Type, String> {
+    let numeric = *lhs == Type::Int || *lhs == Type::Float;
+    let allowed = lhs == rhs && match op {
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod | BinaryOp::Pow
+            | BinaryOp::Lt | BinaryOp::Gt | BinaryOp::Le | BinaryOp::Ge => numeric,
+        BinaryOp::Eq | BinaryOp::Ne => numeric || *lhs == Type::Bool,
+        BinaryOp::And | BinaryOp::Or => *lhs == Type::Bool,
+    };
+    if !allowed {
+        return Err(format!("Cannot apply {} to {} and {}.", op.symbol(), lhs, rhs))
+    }
+    match op {
+        BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod | BinaryOp::Pow => Ok(lhs.clone()),
+        _ => Ok(Type::Bool),
+    }
+}
+
+/// Functions every program can call: conversions named after their result.
+pub const BUILTINS: &[&str] = &["int", "float"];
+
+/// The type of calling the builtin `name` on a value of type `argument`.
+/// Floats convert to ints by truncating, and bools to 0 or 1.
+pub fn builtin(name: &str, argument: &Type) -> Result<Type, String> {
+    let result = Type::from_name(name).expect("Builtins are named after types.");
+    match (&result, argument) {
+        (&Type::Int, &Type::Int) | (&Type::Int, &Type::Float) | (&Type::Int, &Type::Bool)
+            | (&Type::Float, &Type::Int) | (&Type::Float, &Type::Float) => Ok(result),
+        _ => Err(format!("Cannot convert {} to {}.", argument, result)),
+    }
+}
+
+/// Checks the types of a Slang program before it is compiled, recording the
+/// type of every expression in it for code generation. Variables take the
+/// type they are annotated with, or else the type of their initial value.
+#[derive(Clone)]
+pub struct Checker {
+    scopes: Vec<Vec<(String, Type)>>,
+    functions: Vec<(String, Type)>,
+    /// The result type of the function being checked.
+    returns: Option<Type>,
+    /// How many loops the statement being checked is in.
+    loops: usize,
+}
+
+impl Checker {
+    pub fn new() -> Self {
+        Checker {
+            scopes: vec![Vec::new()],
+            functions: Vec::new(),
+            returns: None,
+            loops: 0,
+        }
+    }
+    pub fn begin_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+    pub fn end_scope(&mut self) {
+        self.scopes.pop().expect("The global scope cannot end.");
+    }
+    /// Declare `name` in the innermost scope.
+    pub fn declare(&mut self, name: &str, ty: Type) -> Result<(), String> {
+        if self.scopes.last().unwrap().iter().any(|(declared, _)| declared == name) {
+            return Err(format!("{} is already declared in this scope.", name))
+        }
+        self.scopes.last_mut().unwrap().push((name.to_owned(), ty));
+        Ok(())
+    }
+    /// The type of the innermost declaration of `name` in scope.
+    pub fn lookup(&self, name: &str) -> Result<Type, String> {
+        for scope in self.scopes.iter().rev() {
+            if let Some((_, ty)) = scope.iter().rev().find(|&(declared, _)| declared == name) {
+                return Ok(ty.clone())
+            }
+        }
+        match self.functions.iter().find(|&(declared, _)| declared == name) {
+            Some((_, ty)) => Err(format!("{} is a {}, not a variable.", name, ty)),
+            None => Err(format!("{} is not declared.", name)),
+        }
+    }
+    /// Check the top level statements of a program, then its functions, in
+    /// the order they are compiled.
+    pub fn program(&mut self, statements: &mut [Stmt]) -> Result<(), String> {
+        for statement in statements.iter() {
+            if let StmtKind::Fn(ref function) = statement.kind {
+                let name = &function.name;
+                if BUILTINS.contains(&&**name) || self.functions.iter().any(|(declared, _)| declared == name) {
+                    return Err(format!("{}: {} is already defined.", statement.position, name))
+                }
+                self.functions.push((name.clone(), function.signature()));
+            }
+        }
+        for statement in statements.iter_mut() {
+            self.statement(statement)?;
+        }
+        for statement in statements.iter_mut() {
+            let position = statement.position;
+            if let StmtKind::Fn(ref mut function) = statement.kind {
+                self.function(function).map_err(|err| match err.starts_with(char::is_numeric) {
+                    true => err,
+                    false => format!("{}: {}", position, err),
+                })?;
+            }
+        }
+        Ok(())
+    }
+    fn function(&mut self, function: &mut Function) -> Result<(), String> {
+        let (parameters, result) = match function.signature() {
+            Type::Fn(parameters, result) => (parameters, *result),
+            _ => unreachable!(),
+        };
+        self.returns = Some(result);
+        self.begin_scope();
+        for ((name, _), ty) in function.parameters.iter().zip(parameters) {
+            self.declare(name, ty)?;
+        }
+        let checked = self.block(&mut function.body);
+        self.end_scope();
+        self.returns = None;
+        checked
+    }
+    pub fn statement(&mut self, statement: &mut Stmt) -> Result<(), String> {
+        let position = statement.position;
+        let at = |err: String| format!("{}: {}", position, err);
+        match statement.kind {
+            StmtKind::Let(ref name, ref annotation, ref mut value) => {
+                let ty = match *annotation {
+                    Some(ref ty) => { self.expect(value, ty)?; ty.clone() },
+                    None => self.expression(value)?,
+                };
+                self.declare(name, ty).map_err(at)?;
+            },
+            StmtKind::Assign(ref name, ref mut value) => {
+                let ty = self.lookup(name).map_err(at)?;
+                self.expect(value, &ty)?;
+            },
+            StmtKind::Print(ref mut value) | StmtKind::Expr(ref mut value) => { self.expression(value)?; },
+            StmtKind::Block(ref mut body) => self.block(body)?,
+            StmtKind::If(ref mut condition, ref mut then, ref mut otherwise) => {
+                self.expect(condition, &Type::Bool)?;
+                self.block(then)?;
+                if let Some(ref mut otherwise) = *otherwise {
+                    self.block(otherwise)?;
+                }
+            },
+            StmtKind::While(ref mut condition, ref mut body) => {
+                self.expect(condition, &Type::Bool)?;
+                self.loops += 1;
+                let checked = self.block(body);
+                self.loops -= 1;
+                checked?;
+            },
+            // Code generation relies on these being reported here, before
+            // the optimizer can drop the unreachable code they are in.
+            StmtKind::Break if self.loops == 0 => return Err(at("break is outside of a loop.".to_owned())),
+            StmtKind::Continue if self.loops == 0 => return Err(at("continue is outside of a loop.".to_owned())),
+            StmtKind::Break | StmtKind::Continue => {},
+            StmtKind::Fn(_) if self.scopes.len() > 1 || self.returns.is_some() => {
+                return Err(at("Functions can only be defined at the top level.".to_owned()))
+            },
+            StmtKind::Fn(_) => {},
+            StmtKind::Return(ref mut value) => {
+                let result = match self.returns {
+                    Some(ref result) => result.clone(),
+                    None => return Err(at("return is outside of a function.".to_owned())),
+                };
+                // A bare `return` gives the zero of any type.
+                if let Some(ref mut value) = *value {
+                    self.expect(value, &result)?;
+                }
+            },
+        }
+        Ok(())
+    }
+    fn block(&mut self, body: &mut [Stmt]) -> Result<(), String> {
+        self.begin_scope();
+        let checked = body.iter_mut().map(|statement| self.statement(statement)).collect::<Result<Vec<()>, String>>();
+        self.end_scope();
+        checked.map(|_| ())
+    }
+    /// Check that `expr` has type `expected`.
+    fn expect(&self, expr: &mut Expr, expected: &Type) -> Result<(), String> {
+        let ty = self.expression(expr)?;
+        if ty != *expected {
+            return Err(format!("{}: Expected {}, found {}.", expr.position, expected, ty))
+        }
+        Ok(())
+    }
+    /// The type of `expr`, which is recorded in it and each of its
+    /// subexpressions.
+    pub fn expression(&self, expr: &mut Expr) -> Result<Type, String> {
+        let position = expr.position;
+        let at = |err: String| format!("{}: {}", position, err);
+        let ty = match expr.kind {
+            ExprKind::Int(value) if value > i32::MAX as i64 => Err(at(format!("{} is not a 32 bit integer.", value))),
+            ExprKind::Int(_) => Ok(Type::Int),
+            ExprKind::Float(_) => Ok(Type::Float),
+            ExprKind::Bool(_) => Ok(Type::Bool),
+            ExprKind::Variable(ref name) => self.lookup(name).map_err(at),
+            ExprKind::Unary(op, ref mut operand) => unary(op, &self.expression(operand)?).map_err(at),
+            ExprKind::Binary(op, ref mut lhs, ref mut rhs) => binary(op, &self.expression(lhs)?, &self.expression(rhs)?).map_err(at),
+            ExprKind::Call(ref name, ref mut arguments) => {
+                let signature = self.functions.iter().find(|&(declared, _)| declared == name).map(|(_, ty)| ty.clone());
+                match signature {
+                    Some(Type::Fn(parameters, result)) => {
+                        if arguments.len() != parameters.len() {
+                            return Err(at(format!("{} takes {} arguments but was given {}.", name, parameters.len(), arguments.len())))
+                        }
+                        for (argument, parameter) in arguments.iter_mut().zip(&parameters) {
+                            self.expect(argument, parameter)?;
+                        }
+                        Ok(*result)
+                    },
+                    _ if BUILTINS.contains(&&**name) && arguments.len() == 1 => {
+                        builtin(name, &self.expression(&mut arguments[0])?).map_err(at)
+                    },
+                    _ if BUILTINS.contains(&&**name) => Err(at(format!("{} takes 1 arguments but was given {}.", name, arguments.len()))),
+                    _ => Err(at(format!("{} is not a function.", name))),
+                }
+            },
+        }?;
+        expr.ty = Some(ty.clone());
+        Ok(ty)
+    }
+}
+
+
+#[cfg(test)]
+fn check(source: &str) -> Result<(), String> {
+    Checker::new().program(&mut super::parser::parse_program(source)?)
+}
+
+#[test]
+fn test_check_inference_and_annotations() {
+    assert!(check("let x = 1.5;\nlet y: float = x * 2.0;\nlet b = y > x && !false;\nif b { print y; }").is_ok());
+    assert!(check("let x = 1;\nx = 2.0;") == Err("2:5: Expected int, found float.".to_owned()));
+    assert!(check("let x: bool = 1;") == Err("1:15: Expected bool, found int.".to_owned()));
+    assert!(check("let x = 1 + true;") == Err("1:9: Cannot apply + to int and bool.".to_owned()));
+    assert!(check("let x = 1.0 < 2;") == Err("1:9: Cannot apply < to float and int.".to_owned()));
+    assert!(check("let x = -true;") == Err("1:9: Cannot apply - to bool.".to_owned()));
+    assert!(check("while 1 { }") == Err("1:7: Expected bool, found int.".to_owned()));
+    assert!(check("let x = float(true);") == Err("1:9: Cannot convert bool to float.".to_owned()));
+}
+
+#[test]
+fn test_check_records_types() {
+    let mut expr = super::parser::parse_expression("float(x) / 2.0 > 1.0").unwrap();
+    let mut checker = Checker::new();
+    checker.declare("x", Type::Int).unwrap();
+    checker.expression(&mut expr).unwrap();
+    assert!(expr.ty == Some(Type::Bool));
+    let quotient = match expr.kind { ExprKind::Binary(_, ref lhs, _) => lhs, _ => unreachable!() };
+    assert!(quotient.ty == Some(Type::Float));
+    match quotient.kind {
+        ExprKind::Binary(_, ref call, _) => match call.kind {
+            ExprKind::Call(_, ref arguments) => assert!(call.ty == Some(Type::Float) && arguments[0].ty == Some(Type::Int)),
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn test_check_functions() {
+    let program = "fn avg(a: float, b: float) -> float { return (a + b) / 2.0; }\n\
+                   fn even(n) -> bool { return n % 2 == 0; }\nlet x = avg(1.0, float(3));\nprint even(int(x));";
+    assert!(check(program).is_ok());
+    assert!(check("fn f() -> bool { return 1; }") == Err("1:25: Expected bool, found int.".to_owned()));
+    assert!(check("fn f(x: float) { }\nf(1);") == Err("2:3: Expected float, found int.".to_owned()));
+    assert!(check("fn f(x: float) -> int { return 0; }\nlet y = f;") == Err("2:9: f is a fn(float) -> int, not a variable.".to_owned()));
+    assert!(check("fn int(x) { }") == Err("1:1: int is already defined.".to_owned()));
+    assert!(check("let x = int(1, 2);") == Err("1:9: int takes 1 arguments but was given 2.".to_owned()));
+    assert!(check("if false { break; }") == Err("1:12: break is outside of a loop.".to_owned()));
+    assert!(check("fn f() { return 0; fn g() { } }") == Err("1:20: Functions can only be defined at the top level.".to_owned()));
+}
@@ -0,0 +1,157 @@
+use std::fmt;
+
+use vm::MEMORY_SIZE;
+
+
+const MAGIC: &'static [u8] = b"SLNG";
+const VERSION: u8 = 2;
+
+/// Debug information tying the instruction at `address` back to its source
+/// and the global label it belongs to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub address: usize,
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub label: String,
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{} in {}", self.file, self.line, self.label)
+    }
+}
+
+/// The entry for the instruction containing `address`, given entries sorted
+/// by address.
+pub fn source_line(lines: &[SourceLine], address: usize) -> Option<&SourceLine> {
+    match lines.binary_search_by_key(&address, |line| line.address) {
+        Ok(index) => Some(&lines[index]),
+        Err(0) => None,
+        Err(index) => Some(&lines[index - 1]),
+    }
+}
+
+/// A linked program as loaded by the VM: the words copied into global
+/// memory at startup, the code the program counter runs over and the
+/// source lines of that code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub data: Vec<u32>,
+    pub code: Vec<u8>,
+    pub debug: Vec<SourceLine>,
+}
+
+impl Image {
+    pub fn new(data: Vec<u32>, code: Vec<u8>) -> Self {
+        Image {
+            data: data,
+            code: code,
+            debug: Vec::new(),
+        }
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        write_u32(&mut bytes, self.data.len() as u32);
+        for word in &self.data {
+            write_u32(&mut bytes, *word);
+        }
+        write_blob(&mut bytes, &self.code);
+        write_u32(&mut bytes, self.debug.len() as u32);
+        for line in &self.debug {
+            write_u32(&mut bytes, line.address as u32);
+            write_blob(&mut bytes, line.file.as_bytes());
+            write_u32(&mut bytes, line.line as u32);
+            write_u32(&mut bytes, line.column as u32);
+            write_blob(&mut bytes, line.label.as_bytes());
+        }
+        bytes
+    }
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if !bytes.starts_with(MAGIC) { return Err("Not a Slang program image.".to_owned()) }
+        if bytes.get(MAGIC.len()) != Some(&VERSION) { return Err("Unsupported program image version.".to_owned()) }
+        let mut pos = MAGIC.len() + 1;
+        let words = read_u32(bytes, &mut pos)? as usize;
+        if words > MEMORY_SIZE {
+            return Err(format!("Program image has {} data words, more than the {} words of VM memory.", words, MEMORY_SIZE))
+        }
+        let mut data = Vec::new();
+        for _ in 0..words {
+            data.push(read_u32(bytes, &mut pos)?);
+        }
+        let mut image = Image::new(data, read_blob(bytes, &mut pos)?.to_vec());
+        for _ in 0..read_u32(bytes, &mut pos)? {
+            image.debug.push(SourceLine {
+                address: read_u32(bytes, &mut pos)? as usize,
+                file: read_string(bytes, &mut pos)?,
+                line: read_u32(bytes, &mut pos)? as usize,
+                column: read_u32(bytes, &mut pos)? as usize,
+                label: read_string(bytes, &mut pos)?,
+            });
+        }
+        if pos != bytes.len() { return Err("Program image has trailing bytes.".to_owned()) }
+        Ok(image)
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: u32) {
+    for n in 0..4 {
+        bytes.push((value >> ((3 - n) * 8) & 0xFF) as u8);
+    }
+}
+
+fn write_blob(bytes: &mut Vec<u8>, blob: &[u8]) {
+    write_u32(bytes, blob.len() as u32);
+    bytes.extend_from_slice(blob);
+}
+
+fn read_u32(bytes: &[u8], pos: &mut usize) -> Result<u32, String> {
+    if *pos + 4 > bytes.len() { return Err("Program image ends unexpectedly.".to_owned()) }
+    let value = bytes[*pos..*pos + 4].iter().fold(0, |acc, byte| acc << 8 | *byte as u32);
+    *pos += 4;
+    Ok(value)
+}
+
+fn read_blob<'a>(bytes: &'a [u8], pos: &mut usize) -> Result<&'a [u8], String> {
+    let len = read_u32(bytes, pos)? as usize;
+    if *pos + len > bytes.len() { return Err("Program image ends unexpectedly.".to_owned()) }
+    *pos += len;
+    Ok(&bytes[*pos - len..*pos])
+}
+
+fn read_string(bytes: &[u8], pos: &mut usize) -> Result<String, String> {
+    String::from_utf8(read_blob(bytes, pos)?.to_vec()).map_err(|_| "Program image contains an invalid string.".to_owned())
+}
+
+
+#[test]
+fn test_image_round_trip() {
+    let mut image = Image::new(vec![7, 0xFFFFFFFF], vec![0x88, 0, 0, 0, 6, 0, 0xF0]);
+    image.debug.push(SourceLine { address: 6, file: "main.asm".to_owned(), line: 3, column: 3, label: "._entry".to_owned() });
+    let restored = Image::from_bytes(&image.to_bytes()).unwrap();
+    assert!(restored == image);
+}
+
+#[test]
+fn test_image_rejects_garbage() {
+    assert!(Image::from_bytes(&[0x88, 0, 0, 0, 6, 0]).is_err());
+    let mut bytes = Image::new(vec![1], vec![0xF0]).to_bytes();
+    bytes.pop();
+    assert!(Image::from_bytes(&bytes).is_err());
+    let huge = Image::new(vec![0; MEMORY_SIZE + 1], vec![0xF0]).to_bytes();
+    assert!(Image::from_bytes(&huge) == Err("Program image has 65536 data words, more than the 65535 words of VM memory.".to_owned()));
+}
+
+#[test]
+fn test_source_line_lookup() {
+    let line = |address: usize, line: usize| SourceLine {
+        address: address, file: "main.asm".to_owned(), line: line, column: 3, label: "._entry".to_owned(),
+    };
+    let lines = vec![line(6, 3), line(11, 4), line(12, 6)];
+    assert!(source_line(&lines, 0).is_none());
+    assert!(source_line(&lines, 11).unwrap().line == 4);
+    assert!(source_line(&lines, 9).unwrap().line == 3);
+    assert!(format!("{}", source_line(&lines, 40).unwrap()) == "main.asm:6 in ._entry");
+}
@@ -1,4 +1,5 @@
-use opcode::Opcode;
+use image::SourceLine;
+use opcode::{Opcode, OpcodeInfo, Operand};
 use stack::Stack;
 
 
@@ -17,11 +18,106 @@ impl Instruction {
             value: value,
         }
     }
-    pub fn trace(&self, pc: usize, stack: &Stack) {
+    pub fn trace(&self, pc: usize, source: Option<&SourceLine>, stack: &Stack) {
         let value = match self.value {
             Some(val) => format!("{}", val),
             None => format!(""),
         };
-        debug!("{:04X}: {:04X} -> {:?} {}\t{:?}", pc, self.code as i32, self.opcode, value, stack);
+        let location = match source {
+            Some(line) => format!("{}", line),
+            None => format!("{:04X}", pc),
+        };
+        debug!("{}: {:04X} -> {:?} {}\t{:?}", location, self.code as i32, self.opcode, value, stack);
+    }
+}
+
+/// Decode the instruction at `pc`, returning it with the address of the
+/// instruction after it.
+pub fn decode(code: &[u8], pc: usize) -> Result<(Instruction, usize), String> {
+    let info = match OpcodeInfo::from_byte(code[pc]) {
+        Some(info) => info,
+        None => return Err(format!("{:04X}: {:02X} is not a valid opcode.", pc, code[pc])),
+    };
+    let end = pc + 1 + info.operand.width();
+    if end > code.len() { return Err(format!("{:04X}: {} is missing its operand.", pc, info.mnemonic)) }
+    let value = match info.operand {
+        Operand::None => None,
+        operand => Some(operand.decode(&code[pc + 1..end])),
+    };
+    Ok((Instruction::new(code[pc], value), end))
+}
+
+/// Where a jump or call at `pc` goes, if the instruction has a code target.
+fn target(instruction: &Instruction, next: usize) -> Option<usize> {
+    match (instruction.opcode.info().operand, instruction.value) {
+        (Operand::Address(_), Some(value)) => Some(value as usize),
+        (Operand::Offset(_), Some(value)) => Some((next as i64 + value as i32 as i64) as usize),
+        _ => None,
+    }
+}
+
+/// One line per instruction: address, mnemonic, operand and, for relative
+/// jumps, the address they go to.
+pub fn disassemble(code: &[u8]) -> Result<String, String> {
+    let mut out = String::new();
+    let mut pc = 0;
+    while pc < code.len() {
+        let (instruction, next) = decode(code, pc)?;
+        let info = instruction.opcode.info();
+        let line = match (info.operand, instruction.value) {
+            (Operand::Offset(_), Some(value)) => {
+                format!("{} {} ; -> {:04X}", info.mnemonic, value as i32, target(&instruction, next).unwrap())
+            },
+            (_, Some(value)) => format!("{} {}", info.mnemonic, value),
+            (_, None) => info.mnemonic.to_owned(),
+        };
+        out.push_str(&format!("{:04X}  {}\n", pc, line));
+        pc = next;
+    }
+    Ok(out)
+}
+
+/// Check that `code` decodes cleanly and that every jump and call lands on
+/// the start of an instruction.
+pub fn verify(code: &[u8]) -> Result<(), String> {
+    let mut starts = Vec::new();
+    let mut targets = Vec::new();
+    let mut pc = 0;
+    while pc < code.len() {
+        let (instruction, next) = decode(code, pc)?;
+        if let Some(target) = target(&instruction, next) { targets.push((pc, target)); }
+        starts.push(pc);
+        pc = next;
+    }
+    for (pc, target) in targets {
+        if starts.binary_search(&target).is_err() {
+            return Err(format!("{:04X}: {:04X} is not the start of an instruction.", pc, target))
+        }
     }
+    Ok(())
+}
+
+
+#[test]
+fn test_decode_uses_operand_width() {
+    let code = vec![0x10, 0, 0, 0, 7, 0x30, 0x82, 0xFF, 0xFF, 0xFF, 0xF6];
+    let (instruction, next) = decode(&code, 0).unwrap();
+    assert!(instruction.opcode == Opcode::Const && instruction.value == Some(7) && next == 5);
+    let (instruction, next) = decode(&code, 5).unwrap();
+    assert!(instruction.opcode == Opcode::Dup && instruction.value.is_none() && next == 6);
+    assert!(decode(&code[..9], 6).is_err());
+    assert!(decode(&[0x99], 0).is_err());
+}
+
+#[test]
+fn test_disassemble() {
+    let code = vec![0x88, 0, 0, 0, 6, 0, 0x10, 0, 0, 0, 7, 0x82, 0xFF, 0xFF, 0xFF, 0xF6, 0xF0];
+    assert!(disassemble(&code).unwrap() == "0000  jmp 6\n0005  noop\n0006  const 7\n000B  jmp_rel_ne -10 ; -> 0006\n0010  halt\n");
+}
+
+#[test]
+fn test_verify() {
+    assert!(verify(&[0x88, 0, 0, 0, 6, 0, 0xF0]).is_ok());
+    assert!(verify(&[0x88, 0, 0, 0, 3, 0, 0xF0]).unwrap_err() == "0000: 0003 is not the start of an instruction.");
+    assert!(verify(&[0x10, 0, 0]).is_err());
 }
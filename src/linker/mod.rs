@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+pub mod object;
+
+use assembler::expr::Expr;
+use image::Image;
+use opcode::{Opcode, to_bytes_32};
+use vm::MEMORY_SIZE;
+use self::object::{ObjectFile, Segment};
+
+
+const ENTRY_POINT: &str = "._entry";
+const HEADER_SIZE: usize = 6;
+
+/// Lays out relocatable objects into a single program image: the `._entry`
+/// jump header followed by every object's code, with every object's data
+/// and then its reserved `@space` placed in global memory from address zero.
+pub struct Linker {
+    objects: Vec<ObjectFile>,
+}
+
+impl Linker {
+    pub fn new() -> Self {
+        Linker {
+            objects: Vec::new(),
+        }
+    }
+    pub fn add(&mut self, object: ObjectFile) {
+        self.objects.push(object);
+    }
+    pub fn link(&self) -> Result<Image, String> {
+        let mut code_base = Vec::new();
+        let mut address = HEADER_SIZE;
+        for object in &self.objects {
+            code_base.push(address);
+            address += object.code.len();
+        }
+        let mut data_base = Vec::new();
+        let mut words = 0;
+        for object in &self.objects {
+            data_base.push(words);
+            words += object.data.len();
+        }
+        let mut space_base = Vec::new();
+        for object in &self.objects {
+            space_base.push(words);
+            words += object.space;
+        }
+        if words > MEMORY_SIZE {
+            return Err(format!("Data and reserved space of {} words exceed the {} words of VM memory.", words, MEMORY_SIZE))
+        }
+
+        let mut symbols: HashMap<&str, (usize, &str)> = HashMap::new();
+        for (index, object) in self.objects.iter().enumerate() {
+            for symbol in &object.symbols {
+                let base = match symbol.segment {
+                    Segment::Code => code_base[index],
+                    Segment::Data => data_base[index],
+                    Segment::Space => space_base[index],
+                };
+                if let Some(&(_, other)) = symbols.get(&*symbol.name) {
+                    return Err(format!("{} is defined in both {} and {}.", symbol.name, other, object.name))
+                }
+                symbols.insert(&symbol.name, (base + symbol.offset, &object.name));
+            }
+        }
+        for object in &self.objects {
+            for import in &object.imports {
+                if !symbols.contains_key(&**import) {
+                    return Err(format!("{} is not a known label (imported by {}).", import, object.name))
+                }
+            }
+        }
+
+        let mut bytecode: Vec<u8> = Vec::new();
+        bytecode.push(Opcode::Jmp as u8);
+        match symbols.get(ENTRY_POINT) {
+            Some(&(addr, _)) => bytecode.extend_from_slice(&to_bytes_32(addr as i64)),
+            None => return Err(format!("SlangASM requires a global entry point '{}'", ENTRY_POINT)),
+        }
+        bytecode.push(0);
+        let mut data = Vec::new();
+        let mut debug = Vec::new();
+        for (index, object) in self.objects.iter().enumerate() {
+            data.extend_from_slice(&object.data);
+            for line in &object.lines {
+                let mut line = line.clone();
+                line.address += code_base[index];
+                debug.push(line);
+            }
+        }
+        for object in &self.objects {
+            let mut code = object.code.clone();
+            for relocation in &object.relocations {
+                let lookup = |leaf: &Expr| match *leaf {
+                    Expr::Reference(_, ref label) => symbols.get(&**label).map(|&(addr, _)| addr as i64),
+                    _ => None,
+                };
+                let value = relocation.expr.evaluate(&lookup)
+                                      .map_err(|err| format!("{}: {}", object.name, err))?;
+                if value < i32::MIN as i64 || value > u32::MAX as i64 {
+                    return Err(format!("{}: {} evaluates to {}, which does not fit in 32 bits.", object.name, relocation.expr, value))
+                }
+                code[relocation.offset..relocation.offset + 4].copy_from_slice(&to_bytes_32(value));
+            }
+            bytecode.append(&mut code);
+        }
+        let mut image = Image::new(data, bytecode);
+        image.debug = debug;
+        Ok(image)
+    }
+}
+
+
+#[cfg(test)]
+fn object(name: &str, code: Vec<u8>, symbols: Vec<(&str, usize)>, relocations: Vec<(usize, &str)>) -> ObjectFile {
+    use self::object::{Symbol, Relocation};
+    let mut object = ObjectFile::new(name);
+    object.code = code;
+    for (label, offset) in symbols {
+        object.symbols.push(Symbol { name: label.to_owned(), segment: Segment::Code, offset });
+    }
+    for (offset, expr) in relocations {
+        let expr = Expr::parse(expr).unwrap();
+        for (_, label) in expr.references() {
+            if object.symbol(label).is_none() { object.imports.push(label.to_owned()); }
+        }
+        object.relocations.push(Relocation { offset, expr });
+    }
+    object
+}
+
+#[test]
+fn test_link_resolves_across_objects() {
+    let mut linker = Linker::new();
+    linker.add(object("main.o", vec![0x18, 0, 0, 0, 0, 0xF0], vec![("._entry", 0)], vec![(1, ".double")]));
+    linker.add(object("lib.o", vec![0x30, 0x40, 0xA0], vec![(".double", 0)], vec![]));
+    let image = linker.link().unwrap();
+    assert!(image.code == vec![0x88, 0, 0, 0, 6, 0, 0x18, 0, 0, 0, 12, 0xF0, 0x30, 0x40, 0xA0]);
+}
+
+#[test]
+fn test_link_lays_out_memory() {
+    use self::object::Symbol;
+    let mut main = object("main.o", vec![0x12, 0, 0, 0, 0, 0x12, 0, 0, 0, 0], vec![("._entry", 0)],
+                          vec![(1, ".lib_buffer + 1"), (6, ".lib_value")]);
+    main.data = vec![1, 2];
+    main.space = 16;
+    main.symbols.push(Symbol { name: ".buffer".to_owned(), segment: Segment::Space, offset: 0 });
+    let mut lib = ObjectFile::new("lib.o");
+    lib.data = vec![3];
+    lib.space = 8;
+    lib.symbols.push(Symbol { name: ".lib_value".to_owned(), segment: Segment::Data, offset: 0 });
+    lib.symbols.push(Symbol { name: ".lib_buffer".to_owned(), segment: Segment::Space, offset: 0 });
+    let mut linker = Linker::new();
+    linker.add(main);
+    linker.add(lib);
+    let image = linker.link().unwrap();
+    assert!(image.data == vec![1, 2, 3]);
+    assert!(image.code[6..11] == [0x12, 0, 0, 0, 20]);
+    assert!(image.code[11..16] == [0x12, 0, 0, 0, 2]);
+
+    let mut huge = ObjectFile::new("huge.o");
+    huge.space = MEMORY_SIZE + 1;
+    linker.add(huge);
+    assert!(linker.link().is_err());
+}
+
+#[test]
+fn test_link_errors() {
+    let mut linker = Linker::new();
+    linker.add(object("main.o", vec![0x18, 0, 0, 0, 0], vec![("._entry", 0)], vec![(1, ".missing")]));
+    assert!(linker.link().unwrap_err() == ".missing is not a known label (imported by main.o).");
+
+    let mut linker = Linker::new();
+    linker.add(object("a.o", vec![0xF0], vec![("._entry", 0)], vec![]));
+    linker.add(object("b.o", vec![0xF0], vec![("._entry", 0)], vec![]));
+    assert!(linker.link().unwrap_err() == "._entry is defined in both a.o and b.o.");
+
+    let mut linker = Linker::new();
+    linker.add(object("lib.o", vec![0xA0], vec![(".lib", 0)], vec![]));
+    assert!(linker.link().is_err());
+}
@@ -0,0 +1,201 @@
+use assembler::expr::Expr;
+use image::SourceLine;
+
+
+const MAGIC: &[u8] = b"SLGO";
+const VERSION: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    Code,
+    Data,
+    Space,
+}
+
+/// A global label defined by an object, relative to the start of its segment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub segment: Segment,
+    pub offset: usize,
+}
+
+/// A 32 bit operand in the code segment whose value depends on where the
+/// linker places global labels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub offset: usize,
+    pub expr: Expr,
+}
+
+/// The relocatable output of assembling a single source file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectFile {
+    pub name: String,
+    pub code: Vec<u8>,
+    pub data: Vec<u32>,
+    pub space: usize,
+    pub symbols: Vec<Symbol>,
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+    /// Source lines of the instructions in `code`, by offset into it.
+    pub lines: Vec<SourceLine>,
+}
+
+impl ObjectFile {
+    pub fn new(name: &str) -> Self {
+        ObjectFile {
+            name: name.to_owned(),
+            code: Vec::new(),
+            data: Vec::new(),
+            space: 0,
+            symbols: Vec::new(),
+            imports: Vec::new(),
+            relocations: Vec::new(),
+            lines: Vec::new(),
+        }
+    }
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        write_blob(&mut bytes, &self.code);
+        write_u32(&mut bytes, self.data.len());
+        for word in &self.data {
+            write_u32(&mut bytes, *word as usize);
+        }
+        write_u32(&mut bytes, self.space);
+        write_u32(&mut bytes, self.symbols.len());
+        for symbol in &self.symbols {
+            write_blob(&mut bytes, symbol.name.as_bytes());
+            bytes.push(match symbol.segment { Segment::Code => 0, Segment::Data => 1, Segment::Space => 2 });
+            write_u32(&mut bytes, symbol.offset);
+        }
+        write_u32(&mut bytes, self.imports.len());
+        for import in &self.imports {
+            write_blob(&mut bytes, import.as_bytes());
+        }
+        write_u32(&mut bytes, self.relocations.len());
+        for relocation in &self.relocations {
+            write_u32(&mut bytes, relocation.offset);
+            write_blob(&mut bytes, format!("{}", relocation.expr).as_bytes());
+        }
+        write_u32(&mut bytes, self.lines.len());
+        for line in &self.lines {
+            write_u32(&mut bytes, line.address);
+            write_blob(&mut bytes, line.file.as_bytes());
+            write_u32(&mut bytes, line.line);
+            write_u32(&mut bytes, line.column);
+            write_blob(&mut bytes, line.label.as_bytes());
+        }
+        bytes
+    }
+    pub fn from_bytes(name: &str, bytes: &[u8]) -> Result<Self, String> {
+        if !bytes.starts_with(MAGIC) { return Err(format!("{} is not a SlangASM object file.", name)) }
+        let mut reader = Reader { name, bytes, pos: MAGIC.len() };
+        let version = reader.byte()?;
+        if version != VERSION { return Err(format!("{} has unsupported object version {}.", name, version)) }
+        let mut object = ObjectFile::new(name);
+        object.code = reader.blob()?.to_vec();
+        for _ in 0..reader.u32()? {
+            object.data.push(reader.u32()? as u32);
+        }
+        object.space = reader.u32()?;
+        for _ in 0..reader.u32()? {
+            let name = reader.string()?;
+            let segment = match reader.byte()? {
+                0 => Segment::Code,
+                1 => Segment::Data,
+                2 => Segment::Space,
+                other => return Err(format!("{} has an invalid segment {}.", object.name, other)),
+            };
+            object.symbols.push(Symbol { name, segment, offset: reader.u32()? });
+        }
+        for _ in 0..reader.u32()? {
+            object.imports.push(reader.string()?);
+        }
+        for _ in 0..reader.u32()? {
+            let offset = reader.u32()?;
+            let expr = Expr::parse(&reader.string()?)?;
+            object.relocations.push(Relocation { offset, expr });
+        }
+        for _ in 0..reader.u32()? {
+            object.lines.push(SourceLine {
+                address: reader.u32()?,
+                file: reader.string()?,
+                line: reader.u32()?,
+                column: reader.u32()?,
+                label: reader.string()?,
+            });
+        }
+        Ok(object)
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    for n in 0..4 {
+        bytes.push((value >> ((3 - n) * 8) & 0xFF) as u8);
+    }
+}
+
+fn write_blob(bytes: &mut Vec<u8>, blob: &[u8]) {
+    write_u32(bytes, blob.len());
+    bytes.extend_from_slice(blob);
+}
+
+struct Reader<'a> {
+    name: &'a str,
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        if self.pos + count > self.bytes.len() {
+            return Err(format!("{} ends unexpectedly.", self.name))
+        }
+        let slice = &self.bytes[self.pos..self.pos + count];
+        self.pos += count;
+        Ok(slice)
+    }
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+    fn u32(&mut self) -> Result<usize, String> {
+        Ok(self.take(4)?.iter().fold(0, |acc, byte| acc << 8 | *byte as usize))
+    }
+    fn blob(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()?;
+        self.take(len)
+    }
+    fn string(&mut self) -> Result<String, String> {
+        let name = self.name;
+        String::from_utf8(self.blob()?.to_vec()).map_err(|_| format!("{} contains an invalid string.", name))
+    }
+}
+
+
+#[test]
+fn test_object_round_trip() {
+    let mut object = ObjectFile::new("test.o");
+    object.code = vec![0x18, 0, 0, 0, 0, 0xA0];
+    object.data = vec![7, 0xFFFFFFFF];
+    object.space = 64;
+    object.symbols.push(Symbol { name: ".main".to_owned(), segment: Segment::Code, offset: 0 });
+    object.symbols.push(Symbol { name: ".seven".to_owned(), segment: Segment::Data, offset: 0 });
+    object.symbols.push(Symbol { name: ".buffer".to_owned(), segment: Segment::Space, offset: 0 });
+    object.imports.push(".lib".to_owned());
+    object.relocations.push(Relocation { offset: 1, expr: Expr::parse(".lib + -4").unwrap() });
+    object.lines.push(SourceLine { address: 5, file: "test.asm".to_owned(), line: 2, column: 3, label: ".main".to_owned() });
+    let restored = ObjectFile::from_bytes("test.o", &object.to_bytes()).unwrap();
+    assert!(restored == object);
+}
+
+#[test]
+fn test_object_rejects_garbage() {
+    assert!(ObjectFile::from_bytes("bad.o", b"nope").is_err());
+    assert!(ObjectFile::from_bytes("short.o", b"SLGO\x04\x00\x00").is_err());
+    assert!(ObjectFile::from_bytes("old.o", b"SLGO\x03").is_err());
+}
@@ -0,0 +1,289 @@
+use std::panic::{self, AssertUnwindSafe};
+
+use regex::Regex;
+
+use assembler::Assembler;
+use assembler::lexer::{Lexer, Token, Spanned, LabelType, Directive};
+use opcode::{OpcodeInfo, Operand, INSTRUCTION_SET};
+
+
+lazy_static! {
+    static ref REGEX_LOCATION: Regex = Regex::new(r"^(.*?):(\d+):(\d+): ").unwrap();
+}
+
+const DIRECTIVES: &[&str] = &["@code", "@data", "@space", "@define", "@equ", "@macro", "@endmacro", "@include"];
+
+/// A zero based line and character, as the protocol counts them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: usize,
+    pub character: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Range {
+    fn on_line(line: usize, start: usize, len: usize) -> Self {
+        Range {
+            start: Position { line, character: start },
+            end: Position { line, character: start + len },
+        }
+    }
+    fn contains(&self, position: Position) -> bool {
+        self.start <= position && position <= self.end
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub range: Range,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocumentSymbol {
+    pub name: String,
+    pub code: bool,
+    pub range: Range,
+    pub selection: Range,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+    pub label: String,
+    pub detail: String,
+    pub keyword: bool,
+}
+
+/// A label definition or use. Local labels are scoped by the global label
+/// whose section they appear in.
+#[derive(Debug, Clone)]
+struct LabelSite {
+    name: String,
+    scope: String,
+    range: Range,
+    definition: bool,
+    code: bool,
+}
+
+/// Run `f`, turning a panic into its message. The assembler reports errors by
+/// panicking; the panic hook is left alone, so the message also reaches
+/// stderr, which clients keep as the server's log.
+fn caught<T, F: FnOnce() -> T>(f: F) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|err| match err.downcast_ref::<String>() {
+        Some(message) => message.clone(),
+        None => err.downcast_ref::<&str>().map(|message| message.to_string()).unwrap_or_default(),
+    })
+}
+
+fn lex(text: &str) -> Vec<Spanned> {
+    let source = text.to_owned();
+    caught(|| Lexer::for_file(&source, "").lex_spanned()).unwrap_or_default()
+}
+
+fn word_len(line: &str, start: usize) -> usize {
+    line.get(start..).map_or(0, |rest| rest.chars().take_while(|c| is_word(*c)).count())
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.' || c == '\'' || c == '@'
+}
+
+/// Assemble `text` as the file at `path` and report the first error.
+pub fn diagnostics(text: &str, path: &str) -> Vec<Diagnostic> {
+    let source = text.to_owned();
+    let message = match caught(|| Assembler::with_path(source, path).assemble_object()) {
+        Ok(_) => return Vec::new(),
+        Err(message) => message,
+    };
+    let lines: Vec<&str> = text.lines().collect();
+    let range = match REGEX_LOCATION.captures(&message) {
+        Some(ref captures) if &captures[1] == path => {
+            let line = captures[2].parse::<usize>().unwrap_or(1).saturating_sub(1);
+            let start = captures[3].parse::<usize>().unwrap_or(1).saturating_sub(1);
+            let len = lines.get(line).map_or(0, |text| word_len(text, start));
+            return vec![Diagnostic { range: Range::on_line(line, start, len), message: message[captures[0].len()..].to_owned() }]
+        },
+        _ => Range::on_line(0, 0, lines.first().map_or(0, |line| line.len())),
+    };
+    vec![Diagnostic { range, message }]
+}
+
+fn label_sites(text: &str) -> Vec<LabelSite> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut sites: Vec<LabelSite> = Vec::new();
+    let mut scope = String::new();
+    let mut code = false;
+    for spanned in lex(text) {
+        let line = spanned.location.line - 1;
+        let column = spanned.location.column - 1;
+        let references = match spanned.token {
+            Token::Directive(ref directive) => {
+                match *directive {
+                    Directive::Code => code = true,
+                    Directive::Data | Directive::Space => code = false,
+                    _ => {},
+                }
+                continue
+            },
+            Token::Label(ref label_type, ref name) => {
+                if *label_type == LabelType::Global && code { scope = name.clone(); }
+                sites.push(LabelSite {
+                    name: name.clone(),
+                    scope: if *label_type == LabelType::Local { scope.clone() } else { String::new() },
+                    range: Range::on_line(line, column, name.len()),
+                    definition: true,
+                    code,
+                });
+                continue
+            },
+            ref token => match token.to_expr() {
+                Some(expr) => expr.references().into_iter().map(|(label_type, name)| (label_type.clone(), name.to_owned())).collect::<Vec<_>>(),
+                None => continue,
+            },
+        };
+        // Operand tokens only know where their line's operands start, so
+        // find each reference in the text from there.
+        let text = match lines.get(line) { Some(text) => *text, None => continue };
+        for (label_type, name) in references {
+            let mut from = column;
+            while let Some(found) = text.get(from..).and_then(|rest| rest.find(&*name)) {
+                let start = from + found;
+                from = start + name.len();
+                let before = text[..start].chars().last();
+                if before.is_some_and(is_word) || word_len(text, start) != name.len() { continue }
+                let range = Range::on_line(line, start, name.len());
+                if sites.iter().any(|site| site.range == range) { continue }
+                sites.push(LabelSite {
+                    name: name.clone(),
+                    scope: if label_type == LabelType::Local { scope.clone() } else { String::new() },
+                    range,
+                    definition: false,
+                    code,
+                });
+            }
+        }
+    }
+    sites
+}
+
+/// The label site under `position`, if any.
+fn site_at(sites: &[LabelSite], position: Position) -> Option<&LabelSite> {
+    sites.iter().find(|site| site.range.contains(position))
+}
+
+/// Where the label under `position` is defined.
+pub fn definition(text: &str, position: Position) -> Option<Range> {
+    let sites = label_sites(text);
+    let site = site_at(&sites, position)?;
+    sites.iter().find(|other| other.definition && other.name == site.name && other.scope == site.scope).map(|other| other.range)
+}
+
+/// Every use of the label under `position`, optionally with its definition.
+pub fn references(text: &str, position: Position, declaration: bool) -> Vec<Range> {
+    let sites = label_sites(text);
+    let site = match site_at(&sites, position) {
+        Some(site) => site,
+        None => return Vec::new(),
+    };
+    sites.iter().filter(|other| other.name == site.name && other.scope == site.scope && (declaration || !other.definition))
+                .map(|other| other.range).collect()
+}
+
+/// The word under `position`.
+fn word_at(text: &str, position: Position) -> Option<String> {
+    let line = text.lines().nth(position.line)?;
+    let chars: Vec<char> = line.chars().collect();
+    let mut start = position.character.min(chars.len());
+    while start > 0 && is_word(chars[start - 1]) { start -= 1; }
+    let word: String = chars[start..].iter().take_while(|c| is_word(**c)).collect();
+    if word.is_empty() { None } else { Some(word) }
+}
+
+/// Markdown describing the instruction under `position`.
+pub fn hover(text: &str, position: Position) -> Option<String> {
+    let info = OpcodeInfo::from_mnemonic(&word_at(text, position)?)?;
+    let operand = match info.operand {
+        Operand::None => "no operand".to_owned(),
+        operand => format!("{} operand of {} bytes", operand.name(), operand.width()),
+    };
+    Some(format!("`{}` (0x{:02X}, {})\n\nStack: pops {}, pushes {}\n\n{}",
+                 info.mnemonic, info.opcode as u8, operand, info.pops, info.pushes, info.description))
+}
+
+/// Mnemonics, directives and the document's global labels.
+pub fn completions(text: &str) -> Vec<Completion> {
+    let mut items: Vec<Completion> = INSTRUCTION_SET.iter().map(|info| Completion {
+        label: info.mnemonic.to_owned(),
+        detail: info.description.to_owned(),
+        keyword: true,
+    }).collect();
+    items.extend(DIRECTIVES.iter().map(|directive| Completion {
+        label: directive.to_string(),
+        detail: "directive".to_owned(),
+        keyword: true,
+    }));
+    for site in label_sites(text) {
+        if site.definition && site.scope.is_empty() {
+            items.push(Completion { label: site.name, detail: "label".to_owned(), keyword: false });
+        }
+    }
+    items
+}
+
+/// The global labels of the document, each spanning up to the next one.
+pub fn symbols(text: &str) -> Vec<DocumentSymbol> {
+    let globals: Vec<LabelSite> = label_sites(text).into_iter().filter(|site| site.definition && site.scope.is_empty()).collect();
+    let last_line = text.lines().count().saturating_sub(1);
+    globals.iter().enumerate().map(|(index, site)| {
+        let end_line = globals.get(index + 1).map_or(last_line, |next| next.range.start.line.saturating_sub(1).max(site.range.start.line));
+        let end = text.lines().nth(end_line).map_or(0, |line| line.len());
+        DocumentSymbol {
+            name: site.name.clone(),
+            code: site.code,
+            range: Range { start: Position { line: site.range.start.line, character: 0 }, end: Position { line: end_line, character: end } },
+            selection: site.range,
+        }
+    }).collect()
+}
+
+
+#[cfg(test)]
+const SOURCE: &str = "@data\n.count: 3\n@code\n._entry:\n  gload .count\n  'loop:\n  call .step + 0\n  jmp_rel 'loop\n.step:\n  'loop:\n  ret\n";
+
+#[cfg(test)]
+fn at(line: usize, character: usize) -> Position {
+    Position { line, character }
+}
+
+#[test]
+fn test_definition_and_references() {
+    assert!(definition(SOURCE, at(4, 10)) == Some(Range::on_line(1, 0, 6)));
+    assert!(definition(SOURCE, at(7, 12)) == Some(Range::on_line(5, 2, 5)));
+    assert!(definition(SOURCE, at(6, 8)) == Some(Range::on_line(8, 0, 5)));
+    assert!(references(SOURCE, at(5, 3), false) == vec![Range::on_line(7, 10, 5)]);
+    assert!(references(SOURCE, at(1, 2), true) == vec![Range::on_line(1, 0, 6), Range::on_line(4, 8, 6)]);
+    assert!(definition(SOURCE, at(10, 3)).is_none());
+}
+
+#[test]
+fn test_diagnostics() {
+    assert!(diagnostics(SOURCE, "test.asm").is_empty());
+    let found = diagnostics("@code\n._entry:\n  gload MISSING\n", "test.asm");
+    assert!(found == vec![Diagnostic { range: Range::on_line(2, 8, 7), message: "MISSING is not a defined symbol.".to_owned() }]);
+}
+
+#[test]
+fn test_hover_completion_and_symbols() {
+    assert!(hover(SOURCE, at(4, 4)).unwrap().starts_with("`gload` (0x12, global operand of 4 bytes)"));
+    assert!(hover(SOURCE, at(4, 10)).is_none());
+    let labels: Vec<String> = completions(SOURCE).into_iter().filter(|item| !item.keyword).map(|item| item.label).collect();
+    assert!(labels == vec![".count", "._entry", ".step"]);
+    let names: Vec<(String, bool, usize, usize)> = symbols(SOURCE).into_iter()
+        .map(|symbol| (symbol.name, symbol.code, symbol.range.start.line, symbol.range.end.line)).collect();
+    assert!(names == vec![(".count".to_owned(), false, 1, 2), ("._entry".to_owned(), true, 3, 7), (".step".to_owned(), true, 8, 10)]);
+}
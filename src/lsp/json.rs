@@ -0,0 +1,220 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+
+/// Just enough JSON for the language server protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(source: &str) -> Result<Json, String> {
+        let mut chars = source.chars().peekable();
+        let value = parse_value(&mut chars)?;
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            None => Ok(value),
+            Some(c) => Err(format!("Unexpected '{}' after JSON value.", c)),
+        }
+    }
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(fields.into_iter().map(|(key, value)| (key.to_owned(), value)).collect())
+    }
+    pub fn string(value: &str) -> Json {
+        Json::Str(value.to_owned())
+    }
+    /// The field `key` of an object, or `Null`.
+    pub fn get(&self, key: &str) -> &Json {
+        const NULL: &Json = &Json::Null;
+        match *self {
+            Json::Object(ref fields) => fields.iter().find(|&(name, _)| name == key).map(|(_, value)| value).unwrap_or(NULL),
+            _ => NULL,
+        }
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Json::Str(ref value) => Some(value),
+            _ => None,
+        }
+    }
+    pub fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Json::Number(value) if value >= 0.0 => Some(value as usize),
+            _ => None,
+        }
+    }
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Self {
+        Json::Number(value as f64)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Number(value) if value.fract() == 0.0 && value.abs() < 1e15 => write!(f, "{}", value as i64),
+            Json::Number(value) => write!(f, "{}", value),
+            Json::Str(ref value) => write_string(f, value),
+            Json::Array(ref values) => {
+                write!(f, "[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 { write!(f, ",")?; }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(ref fields) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in fields.iter().enumerate() {
+                    if index > 0 { write!(f, ",")?; }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, value: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) { chars.next(); }
+}
+
+fn expect(chars: &mut Peekable<Chars>, word: &str) -> Result<(), String> {
+    for expected in word.chars() {
+        if chars.next() != Some(expected) { return Err(format!("Expected '{}'.", word)) }
+    }
+    Ok(())
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Result<Json, String> {
+    skip_whitespace(chars);
+    match chars.peek().cloned() {
+        Some('n') => expect(chars, "null").map(|_| Json::Null),
+        Some('t') => expect(chars, "true").map(|_| Json::Bool(true)),
+        Some('f') => expect(chars, "false").map(|_| Json::Bool(false)),
+        Some('"') => parse_string(chars).map(Json::Str),
+        Some('[') => {
+            chars.next();
+            let mut values = Vec::new();
+            skip_whitespace(chars);
+            if chars.peek() == Some(&']') { chars.next(); return Ok(Json::Array(values)) }
+            loop {
+                values.push(parse_value(chars)?);
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {},
+                    Some(']') => return Ok(Json::Array(values)),
+                    _ => return Err("Expected ',' or ']' in array.".to_owned()),
+                }
+            }
+        },
+        Some('{') => {
+            chars.next();
+            let mut fields = Vec::new();
+            skip_whitespace(chars);
+            if chars.peek() == Some(&'}') { chars.next(); return Ok(Json::Object(fields)) }
+            loop {
+                skip_whitespace(chars);
+                let key = parse_string(chars)?;
+                skip_whitespace(chars);
+                if chars.next() != Some(':') { return Err("Expected ':' in object.".to_owned()) }
+                fields.push((key, parse_value(chars)?));
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {},
+                    Some('}') => return Ok(Json::Object(fields)),
+                    _ => return Err("Expected ',' or '}' in object.".to_owned()),
+                }
+            }
+        },
+        Some(c) if c == '-' || c.is_ascii_digit() => {
+            let mut number = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_digit() || c == '-' || c == '+' || c == '.' || c == 'e' || c == 'E') { break }
+                number.push(c);
+                chars.next();
+            }
+            number.parse().map(Json::Number).map_err(|_| format!("Invalid number {}.", number))
+        },
+        Some(c) => Err(format!("Unexpected '{}' in JSON.", c)),
+        None => Err("Unexpected end of JSON.".to_owned()),
+    }
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    if chars.next() != Some('"') { return Err("Expected a string.".to_owned()) }
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(value),
+            Some('\\') => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('r') => value.push('\r'),
+                Some('t') => value.push('\t'),
+                Some('b') => value.push('\u{8}'),
+                Some('f') => value.push('\u{c}'),
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    let code = u32::from_str_radix(&hex, 16).map_err(|_| format!("Invalid escape \\u{}.", hex))?;
+                    value.push(::std::char::from_u32(code).unwrap_or('\u{fffd}'));
+                },
+                Some(c) => value.push(c),
+                None => return Err("Unterminated string.".to_owned()),
+            },
+            Some(c) => value.push(c),
+            None => return Err("Unterminated string.".to_owned()),
+        }
+    }
+}
+
+
+#[test]
+fn test_json_round_trip() {
+    let source = r#"{"id":1,"params":{"text":"a\n\"b\"","list":[true,false,null,-2.5]}}"#;
+    let json = Json::parse(source).unwrap();
+    assert!(json.get("id").as_usize() == Some(1));
+    assert!(json.get("params").get("text").as_str() == Some("a\n\"b\""));
+    assert!(json.get("missing") == &Json::Null);
+    assert!(format!("{}", json) == source);
+}
+
+#[test]
+fn test_json_rejects_garbage() {
+    assert!(Json::parse("{\"a\" 1}").is_err());
+    assert!(Json::parse("[1, 2").is_err());
+    assert!(Json::parse("1 2").is_err());
+}
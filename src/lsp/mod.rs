@@ -0,0 +1,243 @@
+pub mod analysis;
+pub mod json;
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use self::analysis::{Position, Range};
+use self::json::Json;
+
+
+/// A language server for SlangASM documents. Requests go in, and the
+/// responses and notifications to send back come out.
+pub struct Server {
+    documents: HashMap<String, String>,
+    shutdown: bool,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Server {
+            documents: HashMap::new(),
+            shutdown: false,
+        }
+    }
+    pub fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let id = message.get("id").clone();
+        let params = message.get("params");
+        let method = message.get("method").as_str().unwrap_or("");
+        let result = match method {
+            "initialize" => capabilities(),
+            "shutdown" => {
+                self.shutdown = true;
+                Json::Null
+            },
+            "textDocument/didOpen" => {
+                let document = params.get("textDocument");
+                let text = document.get("text").as_str().unwrap_or("").to_owned();
+                return self.update(document.get("uri"), text)
+            },
+            "textDocument/didChange" => {
+                // Only full document sync is advertised, so the last change
+                // is the whole text.
+                let text = match *params.get("contentChanges") {
+                    Json::Array(ref changes) => changes.last().and_then(|change| change.get("text").as_str()).unwrap_or(""),
+                    _ => "",
+                };
+                return self.update(params.get("textDocument").get("uri"), text.to_owned())
+            },
+            "textDocument/didClose" => {
+                let uri = params.get("textDocument").get("uri");
+                self.documents.remove(uri.as_str().unwrap_or(""));
+                return vec![publish(uri, Vec::new())]
+            },
+            "textDocument/definition" => {
+                let (uri, text, position) = self.locate(params);
+                analysis::definition(&text, position).map_or(Json::Null, |range| location(uri, range))
+            },
+            "textDocument/references" => {
+                let (uri, text, position) = self.locate(params);
+                let declaration = params.get("context").get("includeDeclaration").as_bool().unwrap_or(false);
+                Json::Array(analysis::references(&text, position, declaration).into_iter().map(|range| location(uri.clone(), range)).collect())
+            },
+            "textDocument/hover" => {
+                let (_, text, position) = self.locate(params);
+                analysis::hover(&text, position).map_or(Json::Null, |value| Json::object(vec![
+                    ("contents", Json::object(vec![("kind", Json::string("markdown")), ("value", Json::Str(value))])),
+                ]))
+            },
+            "textDocument/completion" => {
+                let (_, text, _) = self.locate(params);
+                Json::Array(analysis::completions(&text).into_iter().map(|item| Json::object(vec![
+                    ("label", Json::Str(item.label)),
+                    // Keyword and Reference completion kinds.
+                    ("kind", Json::from(if item.keyword { 14 } else { 18 })),
+                    ("detail", Json::Str(item.detail)),
+                ])).collect())
+            },
+            "textDocument/documentSymbol" => {
+                let (_, text, _) = self.locate(params);
+                Json::Array(analysis::symbols(&text).into_iter().map(|symbol| Json::object(vec![
+                    ("name", Json::Str(symbol.name)),
+                    // Function and Variable symbol kinds.
+                    ("kind", Json::from(if symbol.code { 12 } else { 13 })),
+                    ("range", range(symbol.range)),
+                    ("selectionRange", range(symbol.selection)),
+                ])).collect())
+            },
+            _ if id == Json::Null => return Vec::new(),
+            _ => return vec![Json::object(vec![
+                ("jsonrpc", Json::string("2.0")),
+                ("id", id),
+                ("error", Json::object(vec![("code", Json::Number(-32601.0)), ("message", Json::Str(format!("Unknown method {}.", method)))])),
+            ])],
+        };
+        vec![Json::object(vec![("jsonrpc", Json::string("2.0")), ("id", id), ("result", result)])]
+    }
+    fn update(&mut self, uri: &Json, text: String) -> Vec<Json> {
+        let name = uri.as_str().unwrap_or("").to_owned();
+        let found = analysis::diagnostics(&text, &uri_to_path(&name));
+        self.documents.insert(name, text);
+        vec![publish(uri, found.into_iter().map(|diagnostic| Json::object(vec![
+            ("range", range(diagnostic.range)),
+            ("severity", Json::from(1)),
+            ("source", Json::string("slang")),
+            ("message", Json::Str(diagnostic.message)),
+        ])).collect())]
+    }
+    /// The document and position a request is about.
+    fn locate(&self, params: &Json) -> (Json, String, Position) {
+        let uri = params.get("textDocument").get("uri");
+        let text = self.documents.get(uri.as_str().unwrap_or("")).cloned().unwrap_or_default();
+        let position = Position {
+            line: params.get("position").get("line").as_usize().unwrap_or(0),
+            character: params.get("position").get("character").as_usize().unwrap_or(0),
+        };
+        (uri.clone(), text, position)
+    }
+}
+
+fn capabilities() -> Json {
+    Json::object(vec![
+        ("capabilities", Json::object(vec![
+            ("textDocumentSync", Json::from(1)),
+            ("definitionProvider", Json::Bool(true)),
+            ("referencesProvider", Json::Bool(true)),
+            ("hoverProvider", Json::Bool(true)),
+            ("completionProvider", Json::object(vec![("triggerCharacters", Json::Array(vec![Json::string("@")]))])),
+            ("documentSymbolProvider", Json::Bool(true)),
+        ])),
+        ("serverInfo", Json::object(vec![("name", Json::string("slang"))])),
+    ])
+}
+
+fn publish(uri: &Json, diagnostics: Vec<Json>) -> Json {
+    Json::object(vec![
+        ("jsonrpc", Json::string("2.0")),
+        ("method", Json::string("textDocument/publishDiagnostics")),
+        ("params", Json::object(vec![("uri", uri.clone()), ("diagnostics", Json::Array(diagnostics))])),
+    ])
+}
+
+fn position(position: Position) -> Json {
+    Json::object(vec![("line", Json::from(position.line)), ("character", Json::from(position.character))])
+}
+
+fn range(range: Range) -> Json {
+    Json::object(vec![("start", position(range.start)), ("end", position(range.end))])
+}
+
+fn location(uri: Json, span: Range) -> Json {
+    Json::object(vec![("uri", uri), ("range", range(span))])
+}
+
+/// The file path of a `file://` URI, as assembler errors name it.
+fn uri_to_path(uri: &str) -> String {
+    let path = uri.trim_start_matches("file://");
+    let mut decoded = Vec::new();
+    let bytes = path.as_bytes();
+    let mut index = 0;
+    while index < bytes.len() {
+        let escaped = if bytes[index] == b'%' { path.get(index + 1..index + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()) } else { None };
+        match escaped {
+            Some(byte) => { decoded.push(byte); index += 3; },
+            None => { decoded.push(bytes[index]); index += 1; },
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn read_message<R: BufRead>(input: &mut R) -> Option<Json> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 { return None }
+        let header = header.trim();
+        if header.is_empty() { break }
+        if header.to_lowercase().starts_with("content-length:") {
+            length = header["content-length:".len()..].trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    match Json::parse(&String::from_utf8_lossy(&body)) {
+        Ok(message) => Some(message),
+        Err(_) => Some(Json::Null),
+    }
+}
+
+fn write_message<W: Write>(output: &mut W, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Serve the protocol over stdin and stdout until the client says `exit`.
+pub fn run() {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut server = Server::new();
+    while let Some(message) = read_message(&mut input) {
+        if message.get("method").as_str() == Some("exit") {
+            ::std::process::exit(if server.shutdown { 0 } else { 1 })
+        }
+        for reply in server.handle(&message) {
+            write_message(&mut stdout.lock(), &reply).unwrap();
+        }
+    }
+}
+
+
+#[test]
+fn test_server_session() {
+    let mut input = String::new();
+    for body in &[
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+        r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///tmp/my%20prog.asm","text":"@code\n._entry:\n  gload MISSING\n"}}}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/definition","params":{"textDocument":{"uri":"file:///tmp/my%20prog.asm"},"position":{"line":2,"character":3}}}"#,
+        r#"{"jsonrpc":"2.0","id":3,"method":"textDocument/documentSymbol","params":{"textDocument":{"uri":"file:///tmp/my%20prog.asm"}}}"#,
+        r#"{"jsonrpc":"2.0","id":4,"method":"unknown"}"#,
+    ] {
+        input.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+    }
+    let mut input = input.as_bytes();
+    let mut server = Server::new();
+    let mut replies = Vec::new();
+    while let Some(message) = read_message(&mut input) {
+        replies.extend(server.handle(&message));
+    }
+    assert!(replies.len() == 5);
+    assert!(replies[0].get("result").get("capabilities").get("hoverProvider") == &Json::Bool(true));
+    let diagnostic = match *replies[1].get("params").get("diagnostics") {
+        Json::Array(ref diagnostics) => diagnostics[0].clone(),
+        _ => panic!("No diagnostics published."),
+    };
+    assert!(diagnostic.get("message").as_str() == Some("MISSING is not a defined symbol."));
+    assert!(diagnostic.get("range").get("start").get("line").as_usize() == Some(2));
+    assert!(replies[2].get("result") == &Json::Null);
+    assert!(format!("{}", replies[3].get("result")) ==
+            r#"[{"name":"._entry","kind":12,"range":{"start":{"line":1,"character":0},"end":{"line":2,"character":15}},"selectionRange":{"start":{"line":1,"character":0},"end":{"line":1,"character":7}}}]"#);
+    assert!(replies[4].get("error").get("code") == &Json::Number(-32601.0));
+    assert!(uri_to_path("file:///tmp/my%20prog.asm") == "/tmp/my prog.asm");
+}
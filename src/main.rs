@@ -11,35 +11,256 @@ extern crate regex;
 use std::env;
 
 mod vm;
+mod image;
 mod stack;
 mod opcode;
 mod program;
 mod compiler;
+mod linker;
 mod assembler;
+mod lsp;
+mod repl;
 mod instruction;
 
 
 use assembler::Assembler;
-use assembler::lexer::Lexer;
-use compiler::lex::{Tokenizer, shunting_yard};
+use image::Image;
+use linker::Linker;
+use linker::object::ObjectFile;
 use vm::VirtualMachine;
 
-
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::Path;
+use std::process;
 
 
 fn main() {
-    log4rs::init_file("config/log4rs.yaml", Default::default()).unwrap();
-
     let args: Vec<String> = env::args().collect();
-    let filename = &args[1];
-    let mut source = String::new();
-    let mut file = File::open(&filename).unwrap();
-    file.read_to_string(&mut source).unwrap();
-    let mut assembler = Assembler::new(source);
-    let bytes = assembler.assemble();
-    println!("{:?}", bytes);
-    let mut vm = VirtualMachine::new(bytes);
-    vm.run();
+    let command = args.get(1).map(|arg| arg.as_str());
+    // The REPL and the compiler write their output to stdout, where the trace
+    // would bury or corrupt it.
+    if command != Some("repl") && command != Some("compile") {
+        log4rs::init_file("config/log4rs.yaml", Default::default()).unwrap();
+    }
+
+    match command {
+        Some("asm") => assemble(&args[2..]),
+        Some("link") => link(&args[2..]),
+        Some("run") => run(&args[2..]),
+        Some("dis") => disassemble(&args[2..]),
+        Some("eval") => evaluate(&args[2..]),
+        Some("compile") => compile(&args[2..]),
+        Some("fmt") => format(&args[2..]),
+        Some("isa") => print!("{}", opcode::reference()),
+        Some("lsp") => lsp::run(),
+        Some("repl") => repl::run(!args[2..].iter().any(|arg| arg == "--no-optimize")),
+        Some(_) => run_source(&args[1..]),
+        None => usage(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: slang <file.asm | file.sl> [--no-optimize]");
+    eprintln!("       slang asm <file.asm> [-O] [--relative-jumps] [-l <file.lst>] [-o <file.o>]");
+    eprintln!("       slang link <file.o>... [-o <program>]");
+    eprintln!("       slang run <program>");
+    eprintln!("       slang dis <program>");
+    eprintln!("       slang eval <expression> [<name>=<value>]... [--asm]");
+    eprintln!("       slang compile <file.sl> [--no-optimize] [-o <file.asm>]");
+    eprintln!("       slang fmt [--check] <file.asm>...");
+    eprintln!("       slang isa");
+    eprintln!("       slang lsp");
+    eprintln!("       slang repl [--no-optimize]");
+    process::exit(2);
+}
+
+/// Split the `-o <path>` option out of a command's arguments.
+fn split_output(args: &[String]) -> (Vec<String>, Option<String>) {
+    let mut inputs = Vec::new();
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = args.next().cloned(),
+            _ => inputs.push(arg.clone()),
+        }
+    }
+    (inputs, output)
+}
+
+fn read_bytes(filename: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut file = File::open(filename).unwrap();
+    file.read_to_end(&mut bytes).unwrap();
+    bytes
+}
+
+fn write_bytes(filename: &str, bytes: &[u8]) {
+    let mut file = File::create(filename).unwrap();
+    file.write_all(bytes).unwrap();
+}
+
+fn assemble(args: &[String]) {
+    let (args, output) = split_output(args);
+    let mut inputs = Vec::new();
+    let mut listing = None;
+    let mut relative_jumps = false;
+    let mut optimize = false;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-l" => listing = args.next(),
+            "-O" => optimize = true,
+            "--relative-jumps" => relative_jumps = true,
+            _ => inputs.push(arg),
+        }
+    }
+    if inputs.len() != 1 { usage(); }
+    let output = output.unwrap_or_else(|| {
+        Path::new(&inputs[0]).with_extension("o").display().to_string()
+    });
+    let mut assembler = Assembler::from_file(&inputs[0]);
+    assembler.relative_jumps(relative_jumps);
+    assembler.peephole(optimize);
+    let object = assembler.assemble_object();
+    if let Some(listing) = listing {
+        write_bytes(&listing, assembler.listing(&object).as_bytes());
+    }
+    write_bytes(&output, &object.to_bytes());
+}
+
+/// Run an expression on the VM and print its value, or with `--asm` print the
+/// SlangASM it compiles to.
+fn evaluate(args: &[String]) {
+    let asm = args.iter().any(|arg| arg == "--asm");
+    let mut args = args.iter().filter(|arg| *arg != "--asm");
+    let source = match args.next() {
+        Some(source) => source,
+        None => usage(),
+    };
+    let mut bindings = Vec::new();
+    for arg in args {
+        let mut parts = arg.splitn(2, '=');
+        match (parts.next(), parts.next().and_then(|value| value.parse::<i64>().ok())) {
+            (Some(name), Some(value)) => bindings.push((name.to_owned(), value)),
+            _ => { eprintln!("{} is not a <name>=<value> binding.", arg); process::exit(2); }
+        }
+    }
+    let result = if asm {
+        compiler::compile_expression(source, &bindings).map(|(codegen, _)| print!("{}", codegen.asm()))
+    } else {
+        compiler::eval(source, &bindings).map(|value| println!("{}", value))
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+/// Compile a Slang program to SlangASM, with comments giving the source line
+/// each part was compiled from. `--no-optimize` keeps the code as written.
+fn compile(args: &[String]) {
+    let (mut inputs, output) = split_output(args);
+    let optimize = !inputs.iter().any(|arg| arg == "--no-optimize");
+    inputs.retain(|arg| arg != "--no-optimize");
+    if inputs.len() != 1 { usage(); }
+    let source = String::from_utf8_lossy(&read_bytes(&inputs[0])).into_owned();
+    let asm = match compiler::compile(&source, optimize) {
+        Ok(codegen) => codegen.annotated_asm(&inputs[0], &source),
+        Err(err) => { eprintln!("{}:{}", inputs[0], err); process::exit(1); }
+    };
+    match output {
+        Some(output) => write_bytes(&output, asm.as_bytes()),
+        None => print!("{}", asm),
+    }
+}
+
+/// Rewrite each file in the canonical layout, or with `--check` list the
+/// files that are not in it.
+fn format(args: &[String]) {
+    let check = args.iter().any(|arg| arg == "--check");
+    let inputs: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+    if inputs.is_empty() { usage(); }
+    let mut unformatted = false;
+    for input in inputs {
+        let source = String::from_utf8_lossy(&read_bytes(input)).into_owned();
+        let formatted = assembler::format::format(&source, input);
+        if formatted == source { continue }
+        if check {
+            println!("{}", input);
+            unformatted = true;
+        } else {
+            write_bytes(input, formatted.as_bytes());
+        }
+    }
+    if unformatted { process::exit(1); }
+}
+
+fn link(args: &[String]) {
+    let (inputs, output) = split_output(args);
+    if inputs.is_empty() { usage(); }
+    let mut linker = Linker::new();
+    for input in &inputs {
+        match ObjectFile::from_bytes(input, &read_bytes(input)) {
+            Ok(object) => linker.add(object),
+            Err(err) => { eprintln!("{}", err); process::exit(1); }
+        }
+    }
+    match linker.link() {
+        Ok(image) => write_bytes(&output.unwrap_or("a.out".to_owned()), &image.to_bytes()),
+        Err(err) => { eprintln!("{}", err); process::exit(1); }
+    }
+}
+
+fn load_image(filename: &str) -> Image {
+    match Image::from_bytes(&read_bytes(filename)).and_then(|image| instruction::verify(&image.code).map(|_| image)) {
+        Ok(image) => image,
+        Err(err) => { eprintln!("{}: {}", filename, err); process::exit(1); }
+    }
+}
+
+fn run(args: &[String]) {
+    if args.len() != 1 { usage(); }
+    execute(&args[0], load_image(&args[0]));
+}
+
+/// Run a linked program. A fault is reported with where it happened and
+/// exits with status 101, as a panic would.
+fn execute(filename: &str, image: Image) {
+    let mut vm = match VirtualMachine::from_image(image) {
+        Ok(vm) => vm,
+        Err(err) => { eprintln!("{}: {}", filename, err); process::exit(1); }
+    };
+    if let Err(err) = vm.run() {
+        eprintln!("{}", err);
+        process::exit(101);
+    }
+}
+
+fn disassemble(args: &[String]) {
+    if args.len() != 1 { usage(); }
+    match instruction::disassemble(&load_image(&args[0]).code) {
+        Ok(listing) => print!("{}", listing),
+        Err(err) => { eprintln!("{}: {}", args[0], err); process::exit(1); }
+    }
+}
+
+/// Run a SlangASM file, or a Slang program if it has the `.sl` extension.
+fn run_source(args: &[String]) {
+    let optimize = !args.iter().any(|arg| arg == "--no-optimize");
+    let inputs: Vec<&String> = args.iter().filter(|arg| *arg != "--no-optimize").collect();
+    if inputs.len() != 1 { usage(); }
+    let filename = inputs[0];
+    let image = if Path::new(filename).extension().map_or(false, |extension| extension == "sl") {
+        let source = String::from_utf8_lossy(&read_bytes(filename)).into_owned();
+        match compiler::compile(&source, optimize).and_then(|codegen| codegen.build()) {
+            Ok(image) => image,
+            Err(err) => { eprintln!("{}:{}", filename, err); process::exit(1); }
+        }
+    } else {
+        Assembler::from_file(filename).assemble()
+    };
+    println!("{:?}", image.code);
+    execute(filename, image);
 }
@@ -1,81 +1,215 @@
+/// How an instruction's operand is used, and how many bytes encode it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    None,
+    /// A value pushed as is.
+    Immediate(usize),
+    /// A slot in the current call frame.
+    Local(usize),
+    /// A word of global memory.
+    Global(usize),
+    /// An absolute code address.
+    Address(usize),
+    /// A signed offset from the end of the instruction.
+    Offset(usize),
+}
+
+impl Operand {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Operand::None => "none",
+            Operand::Immediate(_) => "immediate",
+            Operand::Local(_) => "local",
+            Operand::Global(_) => "global",
+            Operand::Address(_) => "address",
+            Operand::Offset(_) => "offset",
+        }
+    }
+    pub fn width(&self) -> usize {
+        match *self {
+            Operand::None => 0,
+            Operand::Immediate(width) | Operand::Local(width) | Operand::Global(width)
+                | Operand::Address(width) | Operand::Offset(width) => width,
+        }
+    }
+    /// Read an operand from its big endian bytes, sign extending offsets.
+    pub fn decode(&self, bytes: &[u8]) -> u32 {
+        let value = bytes.iter().fold(0, |acc, byte| acc << 8 | *byte as u32);
+        match *self {
+            Operand::Offset(width) if width < 4 => {
+                let shift = 32 - width as u32 * 8;
+                ((value << shift) as i32 >> shift) as u32
+            },
+            _ => value,
+        }
+    }
+    /// The big endian bytes of `value`, if it fits in the operand.
+    pub fn encode(&self, value: i64) -> Option<Vec<u8>> {
+        let width = self.width();
+        let bits = width as u32 * 8;
+        let fits = match *self {
+            Operand::None => false,
+            Operand::Offset(_) => value >= -(1 << (bits - 1)) && value < (1 << (bits - 1)),
+            _ => value >= -(1 << (bits - 1)) && value < (1 << bits),
+        };
+        if !fits { return None }
+        Some((0..width).map(|n| (value >> ((width - 1 - n) * 8)) as u8).collect())
+    }
+}
+
+/// The big endian bytes of the low 32 bits of `value`, as data words and
+/// linked addresses are stored.
+pub fn to_bytes_32(value: i64) -> [u8; 4] {
+    let value = value as u32;
+    [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+/// Everything the toolchain knows about one instruction.
 #[derive(Debug)]
-pub enum Opcode {
-    Noop     = 0x00,
-    Const    = 0x10,
-    Load     = 0x11,
-    GLoad    = 0x12,
-    Store    = 0x14,
-    GStore   = 0x15,
-    Call     = 0x18,
-    Dup      = 0x30,
-    Swap     = 0x31,
-    Add      = 0x40,
-    Sub      = 0x41,
-    Mul      = 0x42,
-    Div      = 0x43,
-    Pow      = 0x44,
-    Mod      = 0x45,
-    Shl      = 0x50,
-    Shr      = 0x51,
-    And      = 0x52,
-    Or       = 0x53,
-    Xor      = 0x54,
-    Not      = 0x55,
-    CmpEq    = 0x61,
-    CmpNe    = 0x62,
-    CmpGt    = 0x63,
-    CmpLt    = 0x64,
-    RelJmp   = 0x80,
-    RelJmpEq = 0x81,
-    RelJmpNe = 0x82,
-    RelJmpGt = 0x83,
-    RelJmpLt = 0x84,
-    Jmp      = 0x88,
-    JmpNZ    = 0x89,
-    Ret      = 0xA0,
-    Print    = 0xE0,
-    Halt     = 0xF0,
+pub struct OpcodeInfo {
+    pub opcode: Opcode,
+    pub mnemonic: &'static str,
+    pub operand: Operand,
+    pub pops: usize,
+    pub pushes: usize,
+    pub description: &'static str,
+}
+
+macro_rules! instruction_set {
+    ($($name:ident = $value:expr, $mnemonic:expr, $operand:expr, $pops:expr => $pushes:expr, $description:expr;)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum Opcode {
+            $($name = $value,)*
+        }
+
+        /// The instruction set, in opcode order. Decoding, assembling,
+        /// disassembling, verification and the reference all read this.
+        /// Executing an instruction is not table driven: the VM dispatches on
+        /// `Opcode` with an exhaustive match, so a new row fails to compile
+        /// until `VirtualMachine::handle_instruction` handles it.
+        pub const INSTRUCTION_SET: &'static [OpcodeInfo] = &[
+            $(OpcodeInfo {
+                opcode: Opcode::$name,
+                mnemonic: $mnemonic,
+                operand: $operand,
+                pops: $pops,
+                pushes: $pushes,
+                description: $description,
+            },)*
+        ];
+    }
+}
+
+instruction_set! {
+    Noop     = 0x00, "noop",       Operand::None,         0 => 0, "Do nothing.";
+    Const    = 0x10, "const",      Operand::Immediate(4), 0 => 1, "Push the operand.";
+    Load     = 0x11, "load",       Operand::Local(4),     0 => 1, "Push a local slot of the current frame.";
+    GLoad    = 0x12, "gload",      Operand::Global(4),    0 => 1, "Push a word of global memory.";
+    Store    = 0x14, "store",      Operand::Local(4),     1 => 0, "Pop into a local slot of the current frame.";
+    GStore   = 0x15, "gstore",     Operand::Global(4),    1 => 0, "Pop into a word of global memory.";
+    Call     = 0x18, "call",       Operand::Address(4),   0 => 0, "Call the subroutine at the operand address.";
+    Dup      = 0x30, "dup",        Operand::None,         1 => 2, "Duplicate the top of the stack.";
+    Swap     = 0x31, "swap",       Operand::None,         2 => 2, "Swap the top two values.";
+    Drop     = 0x32, "drop",       Operand::None,         1 => 0, "Discard the top of the stack.";
+    Add      = 0x40, "add",        Operand::None,         2 => 1, "Add the top two values.";
+    Sub      = 0x41, "sub",        Operand::None,         2 => 1, "Subtract the top value from the one below it.";
+    Mul      = 0x42, "mul",        Operand::None,         2 => 1, "Multiply the top two values.";
+    Div      = 0x43, "div",        Operand::None,         2 => 1, "Divide the value below the top by the top value.";
+    Pow      = 0x44, "pow",        Operand::None,         2 => 1, "Raise the top value to the power of the one below it.";
+    Mod      = 0x45, "mod",        Operand::None,         2 => 1, "Remainder of the top value divided by the one below it.";
+    Shl      = 0x50, "shl",        Operand::None,         1 => 1, "Shift the top value left by one bit.";
+    Shr      = 0x51, "shr",        Operand::None,         1 => 1, "Shift the top value right by one bit.";
+    And      = 0x52, "and",        Operand::None,         2 => 1, "Bitwise and of the top two values.";
+    Or       = 0x53, "or",         Operand::None,         2 => 1, "Bitwise or of the top two values.";
+    Xor      = 0x54, "xor",        Operand::None,         2 => 1, "Bitwise xor of the top two values.";
+    Not      = 0x55, "not",        Operand::None,         1 => 1, "Bitwise not of the top value.";
+    CmpEq    = 0x61, "cmp_eq",     Operand::None,         2 => 1, "Push 1 if the top two values are equal, else 0.";
+    CmpNe    = 0x62, "cmp_ne",     Operand::None,         2 => 1, "Push 1 if the top two values differ, else 0.";
+    CmpGt    = 0x63, "cmp_gt",     Operand::None,         2 => 1, "Push 1 if the top value is greater than the one below it.";
+    CmpLt    = 0x64, "cmp_lt",     Operand::None,         2 => 1, "Push 1 if the top value is less than the one below it.";
+    FAdd     = 0x70, "fadd",       Operand::None,         2 => 1, "Add the top two values as floats.";
+    FSub     = 0x71, "fsub",       Operand::None,         2 => 1, "Subtract the top float from the one below it.";
+    FMul     = 0x72, "fmul",       Operand::None,         2 => 1, "Multiply the top two values as floats.";
+    FDiv     = 0x73, "fdiv",       Operand::None,         2 => 1, "Divide the float below the top by the top float.";
+    FPow     = 0x74, "fpow",       Operand::None,         2 => 1, "Raise the top float to the power of the one below it.";
+    FMod     = 0x75, "fmod",       Operand::None,         2 => 1, "Remainder of the top float divided by the one below it.";
+    FCmpEq   = 0x76, "fcmp_eq",    Operand::None,         2 => 1, "Push 1 if the top two floats are equal, else 0.";
+    FCmpNe   = 0x77, "fcmp_ne",    Operand::None,         2 => 1, "Push 1 if the top two floats differ, else 0.";
+    FCmpGt   = 0x78, "fcmp_gt",    Operand::None,         2 => 1, "Push 1 if the top float is greater than the one below it.";
+    FCmpLt   = 0x79, "fcmp_lt",    Operand::None,         2 => 1, "Push 1 if the top float is less than the one below it.";
+    IToF     = 0x7A, "itof",       Operand::None,         1 => 1, "Convert the top value from an integer to a float.";
+    FToI     = 0x7B, "ftoi",       Operand::None,         1 => 1, "Convert the top value from a float to an integer, truncating.";
+    RelJmp   = 0x80, "jmp_rel",    Operand::Offset(4),    0 => 0, "Jump by the operand offset.";
+    RelJmpEq = 0x81, "jmp_rel_eq", Operand::Offset(4),    2 => 0, "Jump by the offset if the top two values are equal.";
+    RelJmpNe = 0x82, "jmp_rel_ne", Operand::Offset(4),    2 => 0, "Jump by the offset if the top two values differ.";
+    RelJmpGt = 0x83, "jmp_rel_gt", Operand::Offset(4),    2 => 0, "Jump by the offset if the top value is greater than the one below it.";
+    RelJmpLt = 0x84, "jmp_rel_lt", Operand::Offset(4),    2 => 0, "Jump by the offset if the top value is less than the one below it.";
+    Jmp      = 0x88, "jmp",        Operand::Address(4),   0 => 0, "Jump to the operand address.";
+    JmpNZ    = 0x89, "jmpnz",      Operand::Address(4),   1 => 0, "Jump to the operand address if the popped value is not zero.";
+    Ret      = 0xA0, "ret",        Operand::None,         0 => 0, "Return from the current subroutine.";
+    Print    = 0xE0, "print",      Operand::None,         0 => 0, "Print the top of the stack.";
+    FPrint   = 0xE1, "fprint",     Operand::None,         0 => 0, "Print the top of the stack as a float.";
+    Halt     = 0xF0, "halt",       Operand::None,         0 => 0, "Stop the program.";
 }
 
 impl Opcode {
-    pub fn from_value(value: u8) -> Self{
-        match value {
-            0x00 => Opcode::Noop,
-            0x10 => Opcode::Const,
-            0x11 => Opcode::Load,
-            0x12 => Opcode::GLoad,
-            0x14 => Opcode::Store,
-            0x15 => Opcode::GStore,
-            0x18 => Opcode::Call,
-            0x30 => Opcode::Dup,
-            0x31 => Opcode::Swap,
-            0x40 => Opcode::Add,
-            0x41 => Opcode::Sub,
-            0x42 => Opcode::Mul,
-            0x43 => Opcode::Div,
-            0x44 => Opcode::Pow,
-            0x45 => Opcode::Mod,
-            0x50 => Opcode::Shl,
-            0x51 => Opcode::Shr,
-            0x52 => Opcode::And,
-            0x53 => Opcode::Or,
-            0x54 => Opcode::Xor,
-            0x55 => Opcode::Not,
-            0x61 => Opcode::CmpEq,
-            0x62 => Opcode::CmpNe,
-            0x63 => Opcode::CmpGt,
-            0x64 => Opcode::CmpLt,
-            0x80 => Opcode::RelJmp,
-            0x81 => Opcode::RelJmpEq,
-            0x82 => Opcode::RelJmpNe,
-            0x83 => Opcode::RelJmpGt,
-            0x84 => Opcode::RelJmpLt,
-            0x88 => Opcode::Jmp,
-            0x89 => Opcode::JmpNZ,
-            0xA0 => Opcode::Ret,
-            0xE0 => Opcode::Print,
-            0xF0 => Opcode::Halt,
-            _ => panic!("{:04X} is not a valid opcode.", value)
+    pub fn from_value(value: u8) -> Self {
+        match OpcodeInfo::from_byte(value) {
+            Some(info) => info.opcode,
+            None => panic!("{:04X} is not a valid opcode.", value),
         }
     }
+    pub fn info(&self) -> &'static OpcodeInfo {
+        INSTRUCTION_SET.iter().find(|info| info.opcode == *self).unwrap()
+    }
+}
+
+impl OpcodeInfo {
+    pub fn from_byte(value: u8) -> Option<&'static OpcodeInfo> {
+        INSTRUCTION_SET.iter().find(|info| info.opcode as u8 == value)
+    }
+    pub fn from_mnemonic(mnemonic: &str) -> Option<&'static OpcodeInfo> {
+        let mnemonic = mnemonic.to_lowercase();
+        INSTRUCTION_SET.iter().find(|info| info.mnemonic == mnemonic)
+    }
+}
+
+/// A Markdown reference of the instruction set.
+pub fn reference() -> String {
+    let mut out = String::from("| Opcode | Mnemonic | Operand | Stack | Description |\n|---|---|---|---|---|\n");
+    for info in INSTRUCTION_SET {
+        let operand = match info.operand {
+            Operand::None => "".to_owned(),
+            operand => format!("{} ({} bytes)", operand.name(), operand.width()),
+        };
+        out.push_str(&format!("| 0x{:02X} | `{}` | {} | {} → {} | {} |\n", info.opcode as u8, info.mnemonic, operand,
+                              info.pops, info.pushes, info.description));
+    }
+    out
+}
+
+
+#[test]
+fn test_to_bytes_32() {
+    assert!(to_bytes_32(999999) == [0, 15, 66, 63]);
+    assert!(to_bytes_32(-1) == [0xFF; 4]);
+}
+
+#[test]
+fn test_instruction_set_is_consistent() {
+    for (index, info) in INSTRUCTION_SET.iter().enumerate() {
+        assert!(Opcode::from_value(info.opcode as u8) == info.opcode);
+        assert!(OpcodeInfo::from_mnemonic(info.mnemonic).unwrap().opcode == info.opcode);
+        assert!(INSTRUCTION_SET[..index].iter().all(|other| other.mnemonic != info.mnemonic));
+    }
+}
+
+#[test]
+fn test_operand_encoding() {
+    assert!(Operand::Immediate(4).encode(0xFFFFFFFF) == Some(vec![0xFF, 0xFF, 0xFF, 0xFF]));
+    assert!(Operand::Immediate(4).encode(1 << 32).is_none());
+    assert!(Operand::Offset(2).encode(-2) == Some(vec![0xFF, 0xFE]));
+    assert!(Operand::Offset(2).encode(0x8000).is_none());
+    assert!(Operand::Offset(2).decode(&[0xFF, 0xFE]) == (-2i32) as u32);
+    assert!(Operand::Local(1).decode(&[0xFE]) == 0xFE);
 }
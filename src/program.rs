@@ -1,3 +1,5 @@
+use opcode::Operand;
+
 
 pub struct Program {
     bytes: Vec<u8>,
@@ -17,16 +19,10 @@ impl Program {
         self.pc += 1;
         val
     }
-    pub fn next_halfword(&mut self) -> u16 {
-        let rv = (self.next_byte() as u16) << 8;
-        rv | self.next_byte() as u16
-    }
-    pub fn next_word(&mut self) -> u32 {
-        let rv = (self.next_halfword() as u32) << 16;
-        rv | self.next_halfword() as u32
-    }
-    pub fn next_code(&mut self) -> u16 {
-        self.next_halfword()
+    pub fn next_operand(&mut self, operand: Operand) -> u32 {
+        let start = self.pc;
+        self.pc += operand.width();
+        operand.decode(&self.bytes[start..self.pc])
     }
     pub fn jump_to(&mut self, addr: usize) {
         self.pc = addr;
@@ -35,6 +31,10 @@ impl Program {
         let na = self.pc as i32 + rel;
         self.jump_to(na as usize);
     }
+    /// Whether `count` more bytes can be read from the program counter.
+    pub fn has_bytes(&self, count: usize) -> bool {
+        self.pc.checked_add(count).map_or(false, |end| end <= self.bytes.len())
+    }
     pub fn load_bytes(&mut self, bytes: Vec<u8>) {
         self.bytes = bytes;
     }
@@ -65,24 +65,12 @@ fn test_next_byte() {
 }
 
 #[test]
-fn test_next_halfword() {
-    let mut prog = Program::new();
-    let bytes: Vec<u8> = vec![0xFA, 0xFF, 0xFF, 0xFF, 0x00, 0x00];
-    prog.load_bytes(bytes);
-    let val = prog.next_halfword();
-    assert!(val == 0xFAFF);
-    assert!(prog.pc == 2);
-}
-
-#[test]
-fn test_next_code() {
+fn test_next_operand() {
     let mut prog = Program::new();
-    let mut prog2 = Program::new();
-    let bytes: Vec<u8> = vec![0xFA, 0xFF, 0xFF, 0xFF, 0x00, 0x00];
-    let bytes2 = bytes.clone();
-    prog.load_bytes(bytes);
-    prog2.load_bytes(bytes2);
-    assert!(prog2.next_code() == prog.next_code());
+    prog.load_bytes(vec![0xFF, 0xFE, 0x00, 0x00, 0x01, 0x02]);
+    assert!(prog.next_operand(Operand::Offset(2)) == (-2i32) as u32);
+    assert!(prog.next_operand(Operand::Immediate(4)) == 0x0102);
+    assert!(prog.pc == 6);
 }
 
 #[test]
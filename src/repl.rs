@@ -0,0 +1,175 @@
+use std::io::{self, BufRead, Write};
+
+use compiler::codegen::CodeGen;
+use compiler::lex::{Token, Spanned, Tokenizer};
+use compiler::optimize;
+use compiler::parser::Parser;
+use compiler::types::{Checker, Type};
+use vm::VirtualMachine;
+
+
+/// A REPL session. Each input is checked, compiled and run as a program of
+/// its own, but the checker and code generator keep the globals and functions
+/// of earlier inputs, and every input runs on the same machine.
+pub struct Session {
+    checker: Checker,
+    codegen: CodeGen,
+    vm: VirtualMachine,
+    /// The SlangASM of the last input that compiled.
+    asm: String,
+    optimize: bool,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            checker: Checker::new(),
+            codegen: CodeGen::new(),
+            vm: VirtualMachine::new(Vec::new()),
+            asm: String::new(),
+            optimize: true,
+        }
+    }
+    /// Whether inputs are optimized before they are compiled. On by default.
+    pub fn optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+    /// Run one complete input: statements, or a single expression whose value
+    /// is left on the operand stack and returned.
+    pub fn eval(&mut self, input: &str) -> Result<Option<String>, String> {
+        let tokens = Tokenizer::new(input).tokenize()?;
+        let (checker, codegen) = (self.checker.clone(), self.codegen.clone());
+        let compiled = self.compile(tokens).and_then(|ty| self.codegen.build().map(|image| (ty, image)));
+        let (ty, image) = match compiled {
+            Ok(compiled) => compiled,
+            Err(err) => {
+                // Forget whatever the failed input declared.
+                self.checker = checker;
+                self.codegen = codegen;
+                return Err(err)
+            },
+        };
+        self.asm = self.codegen.asm();
+        self.vm.load(image);
+        self.vm.run()?;
+        Ok(ty.map(|ty| ty.show(self.vm.stack().top().expect("An expression leaves its value on the stack."))))
+    }
+    /// Compile an input, returning the type of its value if it is an
+    /// expression.
+    fn compile(&mut self, tokens: Vec<Spanned>) -> Result<Option<Type>, String> {
+        let mut parser = Parser::new(tokens.clone());
+        if let Ok(mut expr) = parser.expression(0) {
+            if parser.at_end() {
+                let ty = self.checker.expression(&mut expr)?;
+                let expr = if self.optimize { optimize::expression(expr) } else { expr };
+                self.codegen.begin_program();
+                self.codegen.expression(&expr);
+                self.codegen.end_program(&[]);
+                return Ok(Some(ty))
+            }
+        }
+        let mut parser = Parser::new(tokens);
+        let mut statements = Vec::new();
+        while !parser.at_end() {
+            statements.push(parser.statement()?);
+        }
+        self.checker.program(&mut statements)?;
+        if self.optimize {
+            statements = optimize::program(statements);
+        }
+        self.codegen.program(&statements);
+        Ok(None)
+    }
+    /// Run a `:` command.
+    pub fn command(&self, command: &str) -> Result<String, String> {
+        match command {
+            ":asm" => Ok(self.asm.clone()),
+            ":stack" => Ok(format!("{:?}\n", self.vm.stack())),
+            ":help" => Ok(HELP.to_owned()),
+            _ => Err(format!("{} is not a command; try :help.", command)),
+        }
+    }
+}
+
+const HELP: &'static str = "\
+Enter statements, or an expression to see its value. Blocks may span lines.
+  :asm     show the SlangASM of the last input
+  :stack   show the operand stack
+  :help    show this help
+  :quit    leave the REPL
+";
+
+/// How many more brackets `source` opens than it closes, or an error if it
+/// does not tokenize.
+fn depth(source: &str) -> Result<i32, String> {
+    let tokens = Tokenizer::new(source).tokenize()?;
+    Ok(tokens.iter().map(|spanned| match spanned.token {
+        Token::LeftBrace | Token::LeftParen => 1,
+        Token::RightBrace | Token::RightParen => -1,
+        _ => 0,
+    }).sum())
+}
+
+/// Read, evaluate and print lines from stdin until `:quit` or the end of the
+/// input. A line that leaves a block or call open is continued on the next.
+pub fn run(optimize: bool) {
+    let stdin = io::stdin();
+    let mut session = Session::new();
+    session.optimize(optimize);
+    let mut input = String::new();
+    loop {
+        print!("{}", if input.is_empty() { "> " } else { "... " });
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 { break }
+        if input.is_empty() && line.trim().starts_with(':') {
+            match line.trim() {
+                ":quit" => break,
+                command => match session.command(command) {
+                    Ok(output) => print!("{}", output),
+                    Err(err) => eprintln!("{}", err),
+                },
+            }
+            continue
+        }
+        input.push_str(&line);
+        match depth(&input) {
+            Ok(depth) if depth > 0 => continue,
+            Err(err) => eprintln!("{}", err),
+            Ok(_) if input.trim().is_empty() => {},
+            Ok(_) => match session.eval(&input) {
+                Ok(Some(value)) => println!("{}", value),
+                Ok(None) => {},
+                Err(err) => eprintln!("{}", err),
+            },
+        }
+        input.clear();
+    }
+}
+
+
+#[test]
+fn test_session_keeps_globals_and_functions() {
+    let mut session = Session::new();
+    assert!(session.eval("let x = 20;") == Ok(None));
+    assert!(session.eval("fn twice(n) {\n  return n * 2;\n}") == Ok(None));
+    assert!(session.eval("twice(x) + 2") == Ok(Some("42".to_owned())));
+    assert!(session.eval("x = x + 1;") == Ok(None));
+    assert!(session.eval("float(twice(x)) / 4.0") == Ok(Some("10.5".to_owned())));
+    assert!(session.command(":stack") == Ok(format!("[42, {}]\n", 10.5f32.to_bits())));
+    assert!(session.command(":asm").unwrap().contains("  call .fn_twice\n"));
+}
+
+#[test]
+fn test_session_recovers_from_errors() {
+    let mut session = Session::new();
+    assert!(session.eval("let y = 1;\nlet z = y + true;") == Err("2:9: Cannot apply + to int and bool.".to_owned()));
+    assert!(session.eval("let y = 2;") == Ok(None));
+    assert!(session.eval("y / (y - 2)").unwrap_err().ends_with("Division by zero."));
+    assert!(session.eval("y") == Ok(Some("2".to_owned())));
+    assert!(session.eval("let x = 1;") == Ok(None));
+    assert!(session.eval("fn var_x() { return 2; }") == Ok(None));
+    assert!(session.eval("var_x() + x") == Ok(Some("3".to_owned())));
+    assert!(session.eval("1 + 1") == Ok(Some("2".to_owned())));
+    assert!(depth("fn f() {\n  if x { (") == Ok(3));
+}
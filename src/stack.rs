@@ -15,11 +15,15 @@ impl Stack {
     pub fn push(&mut self, st: u32) {
         self.space.push(st);
     }
-    pub fn pop(&mut self) -> u32 {
-        self.space.pop().unwrap()
+    pub fn pop(&mut self) -> Result<u32, String> {
+        self.space.pop().ok_or_else(|| "Stack underflow.".to_owned())
     }
-    pub fn peek(&mut self) -> u32 {
-        *self.space.last().unwrap()
+    pub fn peek(&mut self) -> Result<u32, String> {
+        self.space.last().cloned().ok_or_else(|| "Stack underflow.".to_owned())
+    }
+    /// The top of the stack, if there is one.
+    pub fn top(&self) -> Option<u32> {
+        self.space.last().cloned()
     }
 }
 
@@ -45,8 +49,8 @@ impl CallFrame {
     pub fn set_local(&mut self, addr: usize, value: u32) {
         self.locals.insert(addr, value);
     }
-    pub fn get_local(&mut self, addr: usize) -> u32 {
-        *self.locals.get(&addr).unwrap()
+    pub fn get_local(&mut self, addr: usize) -> Result<u32, String> {
+        self.locals.get(&addr).cloned().ok_or_else(|| format!("Local {} is read before it is stored.", addr))
     }
 }
 
@@ -63,8 +67,12 @@ impl CallStack {
     pub fn push(&mut self, sf: CallFrame) {
         self.frames.push(sf);
     }
-    pub fn pop(&mut self) -> CallFrame {
-        self.frames.pop().unwrap()
+    pub fn pop(&mut self) -> Result<CallFrame, String> {
+        self.frames.pop().ok_or_else(|| "ret without a matching call.".to_owned())
+    }
+    /// Drop every frame, returning the outermost.
+    pub fn unwind(&mut self) -> Option<CallFrame> {
+        self.frames.drain(..).next()
     }
 }
 
@@ -88,7 +96,7 @@ fn test_stack_pull() {
     let mut stack = Stack::new();
     stack.space.push(111);
     stack.space.push(222);
-    assert!(stack.pop() + stack.pop() == 333);
+    assert!(stack.pop().unwrap() + stack.pop().unwrap() == 333);
     assert!(stack.space.len() == 0);
 }
 
@@ -97,7 +105,7 @@ fn test_stack_peek() {
     let mut stack = Stack::new();
     stack.space.push(111);
     stack.space.push(222);
-    assert!(stack.peek() == 222);
+    assert!(stack.peek() == Ok(222));
     assert!(stack.space.len() == 2);
     assert!(stack.space[1] == 222)
 }
@@ -1,17 +1,23 @@
 use std;
 
 use stack::{Stack, CallStack, CallFrame};
-use opcode::Opcode;
+use opcode::{Opcode, OpcodeInfo, Operand};
 use program::Program;
 use instruction::Instruction;
+use image::{Image, SourceLine, source_line};
 
 
+/// Number of 32 bit words of global memory addressed by `gload`/`gstore`.
+pub const MEMORY_SIZE: usize = 0xFFFF;
+
 pub struct VirtualMachine {
     stack: Stack,
     callstack: CallStack,
     program: Program,
-    mem: [u32; 0xFFFF],
-    current_frame: CallFrame
+    mem: [u32; MEMORY_SIZE],
+    current_frame: CallFrame,
+    debug: Vec<SourceLine>,
+    halted: bool,
 }
 
 
@@ -23,30 +29,80 @@ impl VirtualMachine {
             stack: Stack::new(),
             callstack: CallStack::new(),
             program: program,
-            mem: [0; 0xFFFF],
+            mem: [0; MEMORY_SIZE],
             current_frame: CallFrame::new(0),
+            debug: Vec::new(),
+            halted: false,
         }
     }
-    pub fn run(&mut self) {
+    /// Load a linked program, copying its data section into global memory.
+    pub fn from_image(image: Image) -> Result<Self, String> {
+        if image.data.len() > MEMORY_SIZE {
+            return Err(format!("{} data words exceed the {} words of VM memory.", image.data.len(), MEMORY_SIZE))
+        }
+        let mut vm = VirtualMachine::new(image.code);
+        vm.mem[..image.data.len()].copy_from_slice(&image.data);
+        vm.debug = image.debug;
+        Ok(vm)
+    }
+    /// Run another program on this machine. Global memory, the operand stack
+    /// and the locals of the outermost frame are kept, and the image's data
+    /// section is not loaded, so the new code sees the state the last left.
+    pub fn load(&mut self, image: Image) {
+        self.program.load_bytes(image.code);
+        self.program.reset();
+        self.debug = image.debug;
+        self.halted = false;
+        // Unwind any calls a fault left behind.
+        if let Some(outermost) = self.callstack.unwind() {
+            self.current_frame = outermost;
+        }
+    }
+    /// Run until `halt`, leaving the operand stack as the program left it.
+    /// A fault is returned with where it happened, against the source line
+    /// of the faulting instruction when the program carries debug information.
+    pub fn run(&mut self) -> Result<(), String> {
         loop {
-            let instr = self.fetch_instruction();
-            self.handle_instruction(instr);
+            if self.halted { break }
+            let pc = self.program.current();
+            let step = self.fetch_instruction().and_then(|instr| self.handle_instruction(instr));
+            if let Err(err) = step {
+                return Err(format!("{}: {}", self.location(pc), err))
+            }
+        }
+        Ok(())
+    }
+    pub fn stack(&self) -> &Stack {
+        &self.stack
+    }
+    /// Where `pc` is in the source, or the bare address without debug info.
+    pub fn location(&self, pc: usize) -> String {
+        match source_line(&self.debug, pc) {
+            Some(line) => format!("{} (pc {:04X})", line, pc),
+            None => format!("pc {:04X}", pc),
         }
     }
-    fn fetch_instruction(&mut self) -> Instruction {
+    fn fetch_instruction(&mut self) -> Result<Instruction, String> {
         let pc = self.program.current();
+        if !self.program.has_bytes(1) { return Err("The program ran past the end of its code.".to_owned()) }
         let base = self.program.next_byte();
-        let value = match base >> 4 {
-            1 | 8 => Some(self.program.next_word()),
-            _ => None
+        let info = OpcodeInfo::from_byte(base).ok_or_else(|| format!("{:02X} is not a valid opcode.", base))?;
+        let value = match info.operand {
+            Operand::None => None,
+            operand if self.program.has_bytes(operand.width()) => Some(self.program.next_operand(operand)),
+            _ => return Err(format!("The operand of {} runs past the end of the code.", info.mnemonic)),
         };
         let instruction = Instruction::new(base, value);
-        instruction.trace(pc, &self.stack);
-        instruction
-    }
-    fn handle_instruction(&mut self, instr: Instruction) {
+        instruction.trace(pc, source_line(&self.debug, pc), &self.stack);
+        Ok(instruction)
+    }
+    /// Execute one instruction. Integer arithmetic, comparisons and `print`
+    /// treat words as signed 32 bit integers, wrapping on overflow, and `div`
+    /// and `mod` fault on a zero divisor. `halt` stops `run` rather than
+    /// exiting the process.
+    fn handle_instruction(&mut self, instr: Instruction) -> Result<(), String> {
         match instr.opcode {
-            Opcode::Noop     => {},
+            Opcode::Noop     => Ok(()),
             Opcode::Const    => self.load_const(instr.value.unwrap()),
             Opcode::Load     => self.load_local(instr.value.unwrap()),
             Opcode::GLoad    => self.load_global(instr.value.unwrap()),
@@ -55,6 +111,7 @@ impl VirtualMachine {
             Opcode::Call     => self.call(instr.value.unwrap()),
             Opcode::Dup      => self.dup(),
             Opcode::Swap     => self.swap(),
+            Opcode::Drop     => self.stack.pop().map(|_| ()),
             Opcode::Add      => self.add(),
             Opcode::Sub      => self.sub(),
             Opcode::Mul      => self.mul(),
@@ -71,6 +128,18 @@ impl VirtualMachine {
             Opcode::CmpNe    => self.cmp_ne(),
             Opcode::CmpGt    => self.cmp_gt(),
             Opcode::CmpLt    => self.cmp_lt(),
+            Opcode::FAdd     => self.float_op(|s1, s2| s2 + s1),
+            Opcode::FSub     => self.float_op(|s1, s2| s2 - s1),
+            Opcode::FMul     => self.float_op(|s1, s2| s2 * s1),
+            Opcode::FDiv     => self.float_op(|s1, s2| s2 / s1),
+            Opcode::FPow     => self.float_op(|s1, s2| s1.powf(s2)),
+            Opcode::FMod     => self.float_op(|s1, s2| s1 % s2),
+            Opcode::FCmpEq   => self.float_cmp(|s1, s2| s1 == s2),
+            Opcode::FCmpNe   => self.float_cmp(|s1, s2| s1 != s2),
+            Opcode::FCmpGt   => self.float_cmp(|s1, s2| s1 > s2),
+            Opcode::FCmpLt   => self.float_cmp(|s1, s2| s1 < s2),
+            Opcode::IToF     => self.itof(),
+            Opcode::FToI     => self.ftoi(),
             Opcode::RelJmp   => self.rel_jmp(instr.value.unwrap()),
             Opcode::RelJmpEq => self.rel_jmp_eq(instr.value.unwrap()),
             Opcode::RelJmpNe => self.rel_jmp_ne(instr.value.unwrap()),
@@ -80,159 +149,235 @@ impl VirtualMachine {
             Opcode::JmpNZ    => self.jmp_nz(instr.value.unwrap()),
             Opcode::Ret      => self.ret(),
             Opcode::Print    => self.print(),
+            Opcode::FPrint   => self.fprint(),
             Opcode::Halt     => self.halt(),
         }
     }
-    fn jmp_nz(&mut self, value: u32) {
+    fn jmp_nz(&mut self, value: u32) -> Result<(), String> {
         let addr = value as usize;
-        if self.stack.pop() != 0 { self.program.jump_to(addr); }
+        if self.stack.pop()? != 0 { self.program.jump_to(addr); }
+        Ok(())
     }
-    fn load_const(&mut self, value: u32) {
+    fn load_const(&mut self, value: u32) -> Result<(), String> {
         self.stack.push(value);
+        Ok(())
     }
-    fn load_global(&mut self, addr: u32) {
-        let value = self.mem[addr as usize];
+    fn load_global(&mut self, addr: u32) -> Result<(), String> {
+        let value = *self.mem.get(addr as usize).ok_or_else(|| out_of_memory(addr))?;
         self.stack.push(value);
+        Ok(())
     }
-    fn load_local(&mut self, addr: u32) {
-        let value = self.current_frame.get_local(addr as usize);
+    fn load_local(&mut self, addr: u32) -> Result<(), String> {
+        let value = self.current_frame.get_local(addr as usize)?;
         self.stack.push(value);
+        Ok(())
     }
-    fn store_global(&mut self, addr: u32) {
-        let value = self.stack.pop();
-        self.mem[addr as usize] = value;
+    fn store_global(&mut self, addr: u32) -> Result<(), String> {
+        let value = self.stack.pop()?;
+        *self.mem.get_mut(addr as usize).ok_or_else(|| out_of_memory(addr))? = value;
+        Ok(())
     }
-    fn store_local(&mut self, addr: u32) {
-        let value = self.stack.pop();
+    fn store_local(&mut self, addr: u32) -> Result<(), String> {
+        let value = self.stack.pop()?;
         self.current_frame.set_local(addr as usize, value);
+        Ok(())
     }
-    fn call(&mut self, addr: u32) {
+    fn call(&mut self, addr: u32) -> Result<(), String> {
+        // The callee gets fresh locals; the caller's are restored by `ret`.
         let pc = self.program.current();
-        self.callstack.push(CallFrame::new(pc));
+        let caller = std::mem::replace(&mut self.current_frame, CallFrame::new(pc));
+        self.callstack.push(caller);
         self.program.jump_to(addr as usize);
+        Ok(())
     }
-    fn ret(&mut self) {
-        let ret = self.callstack.pop().ret;
+    fn ret(&mut self) -> Result<(), String> {
+        let ret = self.current_frame.ret;
+        self.current_frame = self.callstack.pop()?;
         self.program.jump_to(ret);
+        Ok(())
     }
-    fn add(&mut self) {
-        let s1 = self.stack.pop() as u32;
-        let s2 = self.stack.pop() as u32;
+    fn add(&mut self) -> Result<(), String> {
+        let s1 = self.stack.pop()? as u32;
+        let s2 = self.stack.pop()? as u32;
         self.stack.push((s1.wrapping_add(s2)) as u32);
-    }
-    fn sub(&mut self) {
-        let s2 = self.stack.pop() as f32;
-        let s1 = self.stack.pop() as f32;
-        self.stack.push((s1 - s2) as u32);
-    }
-    fn mul(&mut self) {
-        let s1 = self.stack.pop() as f32;
-        let s2 = self.stack.pop() as f32;
-        self.stack.push((s1 * s2) as u32);
-    }
-    fn div(&mut self) {
-        let s1 = self.stack.pop() as f32;
-        let s2 = self.stack.pop() as f32;
-        self.stack.push((s2 / s1) as u32);
-    }
-    fn pow(&mut self) {
-        let s1 = self.stack.pop() as f32;
-        let s2 = self.stack.pop() as f32;
-        self.stack.push((s1.powf(s2)) as u32);
-    }
-    fn modulo(&mut self) {
-        let s1 = self.stack.pop() as f32;
-        let s2 = self.stack.pop() as f32;
-        self.stack.push((s1 % s2) as u32);
-    }
-    fn bit_shl(&mut self) {
-        let s = self.stack.pop() << 1;
+        Ok(())
+    }
+    fn sub(&mut self) -> Result<(), String> {
+        let s2 = self.stack.pop()?;
+        let s1 = self.stack.pop()?;
+        self.stack.push(s1.wrapping_sub(s2));
+        Ok(())
+    }
+    fn mul(&mut self) -> Result<(), String> {
+        let s1 = self.stack.pop()?;
+        let s2 = self.stack.pop()?;
+        self.stack.push(s1.wrapping_mul(s2));
+        Ok(())
+    }
+    fn div(&mut self) -> Result<(), String> {
+        let s1 = self.stack.pop()? as i32;
+        let s2 = self.stack.pop()? as i32;
+        if s1 == 0 { return Err("Division by zero.".to_owned()) }
+        self.stack.push(s2.wrapping_div(s1) as u32);
+        Ok(())
+    }
+    fn pow(&mut self) -> Result<(), String> {
+        let s1 = self.stack.pop()? as i32;
+        let s2 = self.stack.pop()?;
+        self.stack.push(s1.wrapping_pow(s2) as u32);
+        Ok(())
+    }
+    fn modulo(&mut self) -> Result<(), String> {
+        let s1 = self.stack.pop()? as i32;
+        let s2 = self.stack.pop()? as i32;
+        if s2 == 0 { return Err("Division by zero.".to_owned()) }
+        self.stack.push(s1.wrapping_rem(s2) as u32);
+        Ok(())
+    }
+    fn bit_shl(&mut self) -> Result<(), String> {
+        let s = self.stack.pop()? << 1;
         self.stack.push(s);
+        Ok(())
     }
-    fn bit_shr(&mut self) {
-        let s = self.stack.pop() >> 1;
+    fn bit_shr(&mut self) -> Result<(), String> {
+        let s = self.stack.pop()? >> 1;
         self.stack.push(s);
+        Ok(())
     }
-    fn bit_and(&mut self) {
-        let s = self.stack.pop() & self.stack.pop();
+    fn bit_and(&mut self) -> Result<(), String> {
+        let s = self.stack.pop()? & self.stack.pop()?;
         self.stack.push(s);
+        Ok(())
     }
-    fn bit_or(&mut self) {
-        let s = self.stack.pop() | self.stack.pop();
+    fn bit_or(&mut self) -> Result<(), String> {
+        let s = self.stack.pop()? | self.stack.pop()?;
         self.stack.push(s);
+        Ok(())
     }
-    fn bit_xor(&mut self) {
-        let s = self.stack.pop() ^ self.stack.pop();
+    fn bit_xor(&mut self) -> Result<(), String> {
+        let s = self.stack.pop()? ^ self.stack.pop()?;
         self.stack.push(s);
+        Ok(())
     }
-    fn bit_not(&mut self) {
-        let s = self.stack.pop();
+    fn bit_not(&mut self) -> Result<(), String> {
+        let s = self.stack.pop()?;
         self.stack.push(!s);
+        Ok(())
     }
-    fn cmp_eq(&mut self) {
-        let eq = self.stack.pop() == self.stack.pop();
+    fn cmp_eq(&mut self) -> Result<(), String> {
+        let eq = self.stack.pop()? == self.stack.pop()?;
         self.stack.push(eq as u32);
+        Ok(())
     }
-    fn cmp_ne(&mut self) {
-        let ne = self.stack.pop() != self.stack.pop();
+    fn cmp_ne(&mut self) -> Result<(), String> {
+        let ne = self.stack.pop()? != self.stack.pop()?;
         self.stack.push(ne as u32);
+        Ok(())
     }
-    fn cmp_gt(&mut self) {
-        let s1 = self.stack.pop() as f32;
-        let s2 = self.stack.pop() as f32;
+    fn cmp_gt(&mut self) -> Result<(), String> {
+        let s1 = self.stack.pop()? as i32;
+        let s2 = self.stack.pop()? as i32;
         self.stack.push((s1 > s2) as u32);
+        Ok(())
     }
-    fn cmp_lt(&mut self) {
-        let s1 = self.stack.pop() as f32;
-        let s2 = self.stack.pop() as f32;
+    fn cmp_lt(&mut self) -> Result<(), String> {
+        let s1 = self.stack.pop()? as i32;
+        let s2 = self.stack.pop()? as i32;
         self.stack.push((s1 < s2) as u32);
-    }
-    fn rel_jmp(&mut self, addr: u32) {
+        Ok(())
+    }
+    /// Apply `op` to the top value and the one below it, as floats.
+    fn float_op<F: Fn(f32, f32) -> f32>(&mut self, op: F) -> Result<(), String> {
+        let s1 = f32::from_bits(self.stack.pop()?);
+        let s2 = f32::from_bits(self.stack.pop()?);
+        self.stack.push(op(s1, s2).to_bits());
+        Ok(())
+    }
+    fn float_cmp<F: Fn(f32, f32) -> bool>(&mut self, cmp: F) -> Result<(), String> {
+        let s1 = f32::from_bits(self.stack.pop()?);
+        let s2 = f32::from_bits(self.stack.pop()?);
+        self.stack.push(cmp(s1, s2) as u32);
+        Ok(())
+    }
+    fn itof(&mut self) -> Result<(), String> {
+        let s = self.stack.pop()? as i32;
+        self.stack.push((s as f32).to_bits());
+        Ok(())
+    }
+    fn ftoi(&mut self) -> Result<(), String> {
+        let s = f32::from_bits(self.stack.pop()?);
+        self.stack.push(s as i32 as u32);
+        Ok(())
+    }
+    fn rel_jmp(&mut self, addr: u32) -> Result<(), String> {
         let na = addr as i32;
         self.program.jump_relative(na);
+        Ok(())
     }
-    fn rel_jmp_eq(&mut self, addr: u32) {
-        if self.stack.pop() == self.stack.pop() {
+    fn rel_jmp_eq(&mut self, addr: u32) -> Result<(), String> {
+        if self.stack.pop()? == self.stack.pop()? {
             self.program.jump_relative(addr as i32);
         }
+        Ok(())
     }
-    fn rel_jmp_ne(&mut self, addr: u32) {
-        if self.stack.pop() != self.stack.pop() {
+    fn rel_jmp_ne(&mut self, addr: u32) -> Result<(), String> {
+        if self.stack.pop()? != self.stack.pop()? {
             self.program.jump_relative(addr as i32);
         }
+        Ok(())
     }
-    fn rel_jmp_gt(&mut self, addr: u32) {
-        if self.stack.pop() > self.stack.pop() {
+    fn rel_jmp_gt(&mut self, addr: u32) -> Result<(), String> {
+        let s1 = self.stack.pop()? as i32;
+        let s2 = self.stack.pop()? as i32;
+        if s1 > s2 {
             self.program.jump_relative(addr as i32);
         }
+        Ok(())
     }
-    fn rel_jmp_lt(&mut self, addr: u32) {
-        if self.stack.pop() < self.stack.pop() {
+    fn rel_jmp_lt(&mut self, addr: u32) -> Result<(), String> {
+        let s1 = self.stack.pop()? as i32;
+        let s2 = self.stack.pop()? as i32;
+        if s1 < s2 {
             self.program.jump_relative(addr as i32);
         }
+        Ok(())
     }
-    fn jmp(&mut self, addr: u32) {
-        self.program.jump_to(addr as usize)
+    fn jmp(&mut self, addr: u32) -> Result<(), String> {
+        self.program.jump_to(addr as usize);
+        Ok(())
+    }
+    fn halt(&mut self) -> Result<(), String> {
+        self.halted = true;
+        Ok(())
     }
-    fn halt(&mut self) {
-        std::process::exit(1);
+    fn print(&mut self) -> Result<(), String> {
+        let s = self.stack.peek()? as i32;
+        println!("{}", s);
+        Ok(())
     }
-    fn print(&mut self) {
-        let s = self.stack.peek() as f32;
+    fn fprint(&mut self) -> Result<(), String> {
+        let s = f32::from_bits(self.stack.peek()?);
         println!("{}", s);
+        Ok(())
     }
-    fn dup(&mut self) {
-        let todupe = self.stack.peek();
+    fn dup(&mut self) -> Result<(), String> {
+        let todupe = self.stack.peek()?;
         self.stack.push(todupe);
+        Ok(())
     }
-    fn swap(&mut self) {
-        let s1 = self.stack.pop();
-        let s2 = self.stack.pop();
+    fn swap(&mut self) -> Result<(), String> {
+        let s1 = self.stack.pop()?;
+        let s2 = self.stack.pop()?;
         self.stack.push(s1);
         self.stack.push(s2);
+        Ok(())
     }
 }
 
+fn out_of_memory(addr: u32) -> String {
+    format!("{:04X} is outside of global memory.", addr)
+}
+
 #[test]
 fn test_vm_new() {
     let mut vm = VirtualMachine::new(vec![0xFF, 0xFF]);
@@ -242,18 +387,114 @@ fn test_vm_new() {
 #[test]
 fn test_fetch_instruction() {
     let mut vm = VirtualMachine::new(vec![0x10, 0xFF, 0xFF, 0xFF, 0xFF]);
-    let inst = vm.fetch_instruction();
+    let inst = vm.fetch_instruction().unwrap();
     assert!(inst.code == 0x10);
     assert!(inst.value.unwrap() == 0xFFFFFFFF);
 }
 
+#[test]
+fn test_location_uses_debug_info() {
+    let mut image = Image::new(Vec::new(), vec![0x30, 0xF0]);
+    image.debug.push(SourceLine { address: 0, file: "main.asm".to_owned(), line: 3, column: 3, label: "._entry".to_owned() });
+    image.debug.push(SourceLine { address: 1, file: "main.asm".to_owned(), line: 4, column: 3, label: "._entry".to_owned() });
+    let vm = VirtualMachine::from_image(image).unwrap();
+    assert!(vm.location(1) == "main.asm:4 in ._entry (pc 0001)");
+    assert!(VirtualMachine::new(vec![0xF0]).location(0) == "pc 0000");
+}
+
+#[test]
+fn test_from_image_loads_data() {
+    let mut vm = VirtualMachine::from_image(Image::new(vec![7, 9], vec![0xF0])).unwrap();
+    vm.load_global(1).unwrap();
+    assert!(vm.stack.pop() == Ok(9));
+    assert!(vm.program.next_byte() == 0xF0);
+    let huge = Image::new(vec![0; MEMORY_SIZE + 1], vec![0xF0]);
+    assert!(VirtualMachine::from_image(huge).err() == Some("65536 data words exceed the 65535 words of VM memory.".to_owned()));
+}
+
 #[test]
 fn test_swap_instruction() {
     let mut vm = VirtualMachine::new(vec![]);
     vm.stack.push(0x01);
     vm.stack.push(0x02);
-    vm.swap();
+    vm.swap().unwrap();
+
+    assert!(vm.stack.pop() == Ok(1));
+    assert!(vm.stack.pop() == Ok(2));
+}
+
+#[test]
+fn test_faults_are_errors() {
+    let fault = |code: Vec<u8>| VirtualMachine::new(code).run().unwrap_err();
+    assert!(fault(vec![0x40]) == "pc 0000: Stack underflow.");
+    assert!(fault(vec![0xA0]) == "pc 0000: ret without a matching call.");
+    assert!(fault(vec![0x11, 0, 0, 0, 3]) == "pc 0000: Local 3 is read before it is stored.");
+    assert!(fault(vec![0x12, 0, 1, 0, 0]) == "pc 0000: 10000 is outside of global memory.");
+    assert!(fault(vec![0x00]) == "pc 0001: The program ran past the end of its code.");
+    assert!(fault(vec![0xEE]) == "pc 0000: EE is not a valid opcode.");
+    assert!(fault(vec![0x10, 0, 0]) == "pc 0000: The operand of const runs past the end of the code.");
+}
+
+#[test]
+fn test_integer_instructions() {
+    let apply = |opcode: Opcode, below: i32, top: i32| -> Result<i32, String> {
+        let mut vm = VirtualMachine::new(vec![]);
+        vm.stack.push(below as u32);
+        vm.stack.push(top as u32);
+        vm.handle_instruction(Instruction::new(opcode as u8, None))?;
+        Ok(vm.stack.pop()? as i32)
+    };
+    assert!(apply(Opcode::Sub, 3, 5) == Ok(-2));
+    assert!(apply(Opcode::Mul, -3, 5) == Ok(-15));
+    assert!(apply(Opcode::Mul, 0x10000, 0x10000) == Ok(0));
+    assert!(apply(Opcode::Div, -7, 2) == Ok(-3));
+    assert!(apply(Opcode::Div, 7, 0) == Err("Division by zero.".to_owned()));
+    assert!(apply(Opcode::Div, i32::min_value(), -1) == Ok(i32::min_value()));
+    assert!(apply(Opcode::Pow, 3, 2) == Ok(8));
+    assert!(apply(Opcode::Mod, 3, -7) == Ok(-1));
+    assert!(apply(Opcode::Mod, 0, 7) == Err("Division by zero.".to_owned()));
+    assert!(apply(Opcode::Mod, -1, i32::min_value()) == Ok(0));
+    assert!(apply(Opcode::CmpGt, -1, 1) == Ok(1));
+    assert!(apply(Opcode::CmpLt, -1, 1) == Ok(0));
 
-    assert!(vm.stack.pop() == 1);
-    assert!(vm.stack.pop() == 2);
+    let jumps = |opcode: Opcode, below: i32, top: i32| {
+        let mut vm = VirtualMachine::new(vec![]);
+        vm.stack.push(below as u32);
+        vm.stack.push(top as u32);
+        vm.handle_instruction(Instruction::new(opcode as u8, Some(5))).unwrap();
+        vm.program.current() == 5
+    };
+    assert!(!jumps(Opcode::RelJmpGt, 1, -1) && jumps(Opcode::RelJmpGt, -1, 1));
+    assert!(jumps(Opcode::RelJmpLt, 1, -1) && !jumps(Opcode::RelJmpLt, -1, 1));
+
+    let mut vm = VirtualMachine::new(vec![]);
+    vm.handle_instruction(Instruction::new(Opcode::Halt as u8, None)).unwrap();
+    assert!(vm.halted);
+}
+
+#[test]
+fn test_float_instructions() {
+    let mut vm = VirtualMachine::new(vec![]);
+    vm.stack.push(7.0f32.to_bits());
+    vm.stack.push(2.0f32.to_bits());
+    vm.handle_instruction(Instruction::new(Opcode::FDiv as u8, None)).unwrap();
+    assert!(f32::from_bits(vm.stack.peek().unwrap()) == 3.5);
+    vm.stack.push(1.5f32.to_bits());
+    vm.handle_instruction(Instruction::new(Opcode::FCmpLt as u8, None)).unwrap();
+    assert!(vm.stack.pop() == Ok(1));
+    vm.stack.push(2.5f32.to_bits());
+    vm.handle_instruction(Instruction::new(Opcode::FToI as u8, None)).unwrap();
+    vm.handle_instruction(Instruction::new(Opcode::IToF as u8, None)).unwrap();
+    assert!(f32::from_bits(vm.stack.pop().unwrap()) == 2.0);
+}
+
+#[test]
+fn test_load_keeps_state() {
+    let mut vm = VirtualMachine::from_image(Image::new(vec![0], vec![0x10, 0, 0, 0, 7, 0x15, 0, 0, 0, 0, 0xF0])).unwrap();
+    vm.run().unwrap();
+    vm.load(Image::new(vec![0], vec![0x12, 0, 0, 0, 0, 0x10, 0, 0, 0, 0, 0x43, 0xF0]));
+    assert!(vm.run() == Err("pc 000A: Division by zero.".to_owned()));
+    vm.load(Image::new(vec![0], vec![0x12, 0, 0, 0, 0, 0xF0]));
+    vm.run().unwrap();
+    assert!(vm.stack().top() == Some(7));
 }